@@ -94,7 +94,7 @@ async fn main() -> anyhow::Result<()> {
 
         tokio::spawn(
             async move {
-                let listener = TcpListener::bind(addr.as_tuple()).await?;
+                let listener = TcpListener::bind(addr.host_port()?).await?;
                 info!(address=%addr, "Started responder");
                 while let Ok((socket, client_addr)) = listener.accept().await {
                     let acceptor = tls_acceptor.clone();
@@ -257,7 +257,7 @@ mod simple_http {
                 let mut r = httparse::Request::new(&mut parsed_headers);
                 let status = r.parse(src).map_err(|e| {
                     let msg = format!("failed to parse http request: {:?}", e);
-                    io::Error::new(io::ErrorKind::Other, msg)
+                    io::Error::other(msg)
                 })?;
 
                 let amt = match status {
@@ -277,8 +277,7 @@ mod simple_http {
                     headers[i] = Some((k, v));
                 }
 
-                let method = http::Method::try_from(r.method.unwrap())
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                let method = http::Method::try_from(r.method.unwrap()).map_err(io::Error::other)?;
 
                 (
                     method,
@@ -288,10 +287,7 @@ mod simple_http {
                 )
             };
             if version != 1 {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "only HTTP/1.1 accepted",
-                ));
+                return Err(io::Error::other("only HTTP/1.1 accepted"));
             }
             let data = src.split_to(amt).freeze();
             let mut ret = Request::builder();
@@ -306,13 +302,11 @@ mod simple_http {
                     None => break,
                 };
                 let value = HeaderValue::from_bytes(data.slice(v.0..v.1).as_ref())
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "header decode error"))?;
+                    .map_err(|_| io::Error::other("header decode error"))?;
                 ret = ret.header(&data[k.0..k.1], value);
             }
 
-            let req = ret
-                .body(())
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let req = ret.body(()).map_err(io::Error::other)?;
             Ok(Some(req))
         }
     }
@@ -356,11 +350,11 @@ mod simple_http {
             unix_date: u64,
         }
 
-        thread_local!(static LAST: RefCell<LastRenderedNow> = RefCell::new(LastRenderedNow {
+        thread_local!(static LAST: RefCell<LastRenderedNow> = const { RefCell::new(LastRenderedNow {
             bytes: [0; 128],
             amt: 0,
             unix_date: 0,
-        }));
+        }) });
 
         impl fmt::Display for Now {
             fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
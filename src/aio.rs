@@ -1,18 +1,122 @@
 use futures::ready;
+use std::fmt::Display;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::sync::watch;
+use tokio::time::{sleep, Instant, Sleep};
 use tracing::{debug, error};
 
+use crate::state::rate::{BandwidthLimit, TokenBucket, TunnelBandwidth};
 use crate::tunnel::SocketSpec;
 use crate::State;
 
+/// Limits for stream, which close it even if peers did not finish it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamTimeouts {
+    /// Maximum time without any data transferred in either direction
+    pub idle: Option<Duration>,
+    /// Maximum time for whole stream
+    pub max_lifetime: Option<Duration>,
+}
+
+/// Reason why stream was closed by timeout, it's carried inside `io::Error` of kind `TimedOut`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamTimeout {
+    Idle,
+    Lifetime,
+}
+
+impl Display for StreamTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamTimeout::Idle => write!(f, "idle timeout"),
+            StreamTimeout::Lifetime => write!(f, "maximum lifetime reached"),
+        }
+    }
+}
+
+impl std::error::Error for StreamTimeout {}
+
+impl StreamTimeout {
+    /// Extracts timeout reason from error returned by `copy_bidirectional`
+    pub fn from_io_error(e: &io::Error) -> Option<StreamTimeout> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<StreamTimeout>())
+            .copied()
+    }
+}
+
+/// Bandwidth throttling of one direction of stream - after bytes are read,
+/// next read is delayed until both connection and tunnel limits allow it
+struct Throttle {
+    bandwidth: Arc<TunnelBandwidth>,
+    direction: Direction,
+    bucket: Option<TokenBucket>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+enum Direction {
+    Up,
+    Down,
+}
+
+impl Throttle {
+    fn new(bandwidth: Arc<TunnelBandwidth>, direction: Direction) -> Self {
+        Throttle {
+            bandwidth,
+            direction,
+            bucket: None,
+            delay: None,
+        }
+    }
+
+    fn limit(&self) -> &BandwidthLimit {
+        match self.direction {
+            Direction::Up => &self.bandwidth.up,
+            Direction::Down => &self.bandwidth.down,
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    fn consumed(&mut self, bytes: usize) {
+        let now = Instant::now();
+        // connection rate can be changed on live tunnel
+        let connection_wait = match self.limit().connection_rate() {
+            Some(rate) => {
+                let bucket = self
+                    .bucket
+                    .get_or_insert_with(|| TokenBucket::per_second(rate));
+                bucket.set_rate(rate, now);
+                bucket.take(bytes as f64, now)
+            }
+            None => {
+                self.bucket = None;
+                Duration::ZERO
+            }
+        };
+        let wait = connection_wait.max(self.limit().take_tunnel(bytes, now));
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+    }
+}
+
 pub(super) struct CopyBuffer<'a> {
     read_done: bool,
+    active: bool,
     need_flush: bool,
     pos: usize,
     cap: usize,
@@ -20,15 +124,22 @@ pub(super) struct CopyBuffer<'a> {
     buf: Box<[u8]>,
     update_progress: Box<dyn Fn(u64) + Send>,
     finish: FinishFuture<'a>,
+    throttle: Throttle,
 }
 
 impl<'a> CopyBuffer<'a> {
-    pub(super) fn new<F>(buf_size: usize, update_progress: F, finish: FinishFuture<'a>) -> Self
+    fn new<F>(
+        buf_size: usize,
+        update_progress: F,
+        finish: FinishFuture<'a>,
+        throttle: Throttle,
+    ) -> Self
     where
         F: Fn(u64) + Send + 'static,
     {
         Self {
             read_done: false,
+            active: false,
             need_flush: false,
             pos: 0,
             cap: 0,
@@ -36,6 +147,7 @@ impl<'a> CopyBuffer<'a> {
             buf: vec![0; buf_size].into_boxed_slice(),
             update_progress: Box::new(update_progress),
             finish,
+            throttle,
         }
     }
 
@@ -54,6 +166,9 @@ impl<'a> CopyBuffer<'a> {
         if let Poll::Ready(Ok(_)) = res {
             let filled_len = buf.filled().len();
             self.read_done = self.cap == filled_len;
+            if filled_len > self.cap {
+                self.throttle.consumed(filled_len - self.cap);
+            }
             self.cap = filled_len;
         }
         res
@@ -113,6 +228,13 @@ impl<'a> CopyBuffer<'a> {
             // // If our buffer is empty, then we need to read some data to
             // continue.
             if self.pos == self.cap && !self.read_done {
+                if self.throttle.poll_ready(cx).is_pending() {
+                    if self.need_flush {
+                        ready!(writer.as_mut().poll_flush(cx))?;
+                        self.need_flush = false;
+                    }
+                    return Poll::Pending;
+                }
                 self.pos = 0;
                 self.cap = 0;
 
@@ -142,6 +264,7 @@ impl<'a> CopyBuffer<'a> {
                     )));
                 } else {
                     self.pos += i;
+                    self.active = true;
                     (self.update_progress)(i as u64);
                     self.amt += i as u64;
                     self.need_flush = true;
@@ -180,6 +303,20 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     b: &'a mut B,
     a_to_b: TransferState<'a>,
     b_to_a: TransferState<'a>,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    lifetime: Option<Pin<Box<Sleep>>>,
+}
+
+/// Was there any data transferred since last check
+fn take_activity(state: &mut TransferState) -> bool {
+    match state {
+        TransferState::Running(buf) => std::mem::take(&mut buf.active),
+        _ => false,
+    }
+}
+
+fn timed_out(reason: StreamTimeout) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, reason)
 }
 
 fn transfer_one_direction<A, B>(
@@ -225,17 +362,37 @@ where
             b,
             a_to_b,
             b_to_a,
+            idle,
+            lifetime,
         } = &mut *self;
 
-        let a_to_b = transfer_one_direction(cx, a_to_b, a, b).map_err(|e| {
+        let a_to_b_state = a_to_b;
+        let a_to_b = transfer_one_direction(cx, a_to_b_state, a, b).map_err(|e| {
             debug!("Error on upstream copy {}", e);
             e
         })?;
-        let b_to_a = transfer_one_direction(cx, b_to_a, b, a).map_err(|e| {
+        let b_to_a_state = b_to_a;
+        let b_to_a = transfer_one_direction(cx, b_to_a_state, b, a).map_err(|e| {
             debug!("Error on downstream copy {}", e);
             e
         })?;
 
+        if a_to_b.is_pending() || b_to_a.is_pending() {
+            if let Some((idle_timeout, idle_sleep)) = idle {
+                if take_activity(a_to_b_state) | take_activity(b_to_a_state) {
+                    idle_sleep.as_mut().reset(Instant::now() + *idle_timeout);
+                }
+                if idle_sleep.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Err(timed_out(StreamTimeout::Idle)));
+                }
+            }
+            if let Some(lifetime_sleep) = lifetime {
+                if lifetime_sleep.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Err(timed_out(StreamTimeout::Lifetime)));
+                }
+            }
+        }
+
         // It is not a problem if ready! returns early because transfer_one_direction for the
         // other direction will keep returning TransferState::Done(count) in future calls to poll
         let a_to_b = ready!(a_to_b);
@@ -245,20 +402,32 @@ where
     }
 }
 
+/// Tunnel, remote and client of copied stream, used to update statistics
+pub struct StreamInfo {
+    pub tunnel: SocketSpec,
+    pub remote: SocketSpec,
+    pub client: SocketAddr,
+}
+
 pub async fn copy_bidirectional<A, B>(
     a: &mut A,
     b: &mut B,
-    tunnel_local: SocketSpec,
-    tunnel_remote: SocketSpec,
-    client_addr: SocketAddr,
+    info: StreamInfo,
     state: State,
     mut finish_receiver: watch::Receiver<bool>,
+    timeouts: StreamTimeouts,
 ) -> Result<(u64, u64), std::io::Error>
 where
     A: AsyncRead + AsyncWrite + Unpin + ?Sized,
     B: AsyncRead + AsyncWrite + Unpin + ?Sized,
 {
+    let StreamInfo {
+        tunnel: tunnel_local,
+        remote: tunnel_remote,
+        client: client_addr,
+    } = info;
     let buf_size = state.copy_buffer_size();
+    let bandwidth = state.bandwidth(&tunnel_local);
     let local = tunnel_local.clone();
     let remote = tunnel_remote.clone();
     let ctx = state.clone();
@@ -275,8 +444,138 @@ where
     CopyBidirectional {
         a,
         b,
-        a_to_b: TransferState::Running(CopyBuffer::new(buf_size, update_sent, finish1)),
-        b_to_a: TransferState::Running(CopyBuffer::new(buf_size, update_recieved, finish2)),
+        a_to_b: TransferState::Running(CopyBuffer::new(
+            buf_size,
+            update_sent,
+            finish1,
+            Throttle::new(bandwidth.clone(), Direction::Up),
+        )),
+        b_to_a: TransferState::Running(CopyBuffer::new(
+            buf_size,
+            update_recieved,
+            finish2,
+            Throttle::new(bandwidth, Direction::Down),
+        )),
+        idle: timeouts.idle.map(|d| (d, Box::pin(sleep(d)))),
+        lifetime: timeouts.max_lifetime.map(|d| Box::pin(sleep(d))),
     }
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Args;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    fn test_state() -> State {
+        let args = Args {
+            copy_buffer_size: 1024,
+            ..Default::default()
+        };
+        #[cfg(feature = "metrics")]
+        let state = State::new(args, crate::metrics::init_meter()).unwrap();
+        #[cfg(not(feature = "metrics"))]
+        let state = State::new(args).unwrap();
+        state
+    }
+
+    async fn copy_with_timeouts(timeouts: StreamTimeouts, keep_talking: bool) -> io::Result<()> {
+        let (mut client, mut a) = duplex(1024);
+        let (mut b, mut server) = duplex(1024);
+        let (_finish_sender, finish_receiver) = watch::channel(false);
+        let talk = async move {
+            for _ in 0..5 {
+                if keep_talking {
+                    client.write_all(b"ping").await.unwrap();
+                    let mut buf = [0u8; 4];
+                    server.read_exact(&mut buf).await.unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            (client, server)
+        };
+        let copy = copy_bidirectional(
+            &mut a,
+            &mut b,
+            StreamInfo {
+                tunnel: "3000".parse().unwrap(),
+                remote: "3001".parse().unwrap(),
+                client: "127.0.0.1:5555".parse().unwrap(),
+            },
+            test_state(),
+            finish_receiver,
+            timeouts,
+        );
+        tokio::select! {
+            res = copy => res.map(|_| ()),
+            _ = talk => Ok(()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_timeouts() {
+        let idle = StreamTimeouts {
+            idle: Some(Duration::from_millis(50)),
+            max_lifetime: None,
+        };
+        let e = copy_with_timeouts(idle, false).await.unwrap_err();
+        assert_eq!(Some(StreamTimeout::Idle), StreamTimeout::from_io_error(&e));
+        assert!(copy_with_timeouts(idle, true).await.is_ok());
+
+        let lifetime = StreamTimeouts {
+            idle: None,
+            max_lifetime: Some(Duration::from_millis(50)),
+        };
+        let e = copy_with_timeouts(lifetime, true).await.unwrap_err();
+        assert_eq!(
+            Some(StreamTimeout::Lifetime),
+            StreamTimeout::from_io_error(&e)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_throttling() {
+        let state = test_state();
+        let tunnel: crate::Tunnel = "3000=3001[rate-up=20000]".parse().unwrap();
+        let (sender, finish_receiver) = watch::channel(false);
+        state.add_tunnel(tunnel.clone(), sender).unwrap();
+        let (mut client, mut a) = duplex(65536);
+        let (mut b, mut server) = duplex(65536);
+        let copy_state = state.clone();
+        let start = Instant::now();
+        let talk = async move {
+            client.write_all(&[1u8; 30000]).await.unwrap();
+            let mut buf = vec![0u8; 30000];
+            server.read_exact(&mut buf).await.unwrap();
+            // allowed burst is 1s, so remaining 10000 bytes take 0.5s,
+            // minus last buffer, which is read before throttling delay
+            assert!(start.elapsed() >= Duration::from_millis(400));
+
+            // limit can be removed on live connection
+            let limits: crate::tunnel::RateLimits = "rate-up=0".parse().unwrap();
+            state.set_rate_limits(&tunnel.local, &limits).unwrap();
+            tokio::time::sleep(Duration::from_millis(600)).await;
+            let start = Instant::now();
+            client.write_all(&[1u8; 30000]).await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            assert!(start.elapsed() < Duration::from_millis(400));
+        };
+        let copy = copy_bidirectional(
+            &mut a,
+            &mut b,
+            StreamInfo {
+                tunnel: "3000".parse().unwrap(),
+                remote: "3001".parse().unwrap(),
+                client: "127.0.0.1:5555".parse().unwrap(),
+            },
+            copy_state,
+            finish_receiver,
+            StreamTimeouts::default(),
+        );
+        tokio::select! {
+            res = copy => panic!("copy finished early: {:?}", res),
+            _ = talk => (),
+        }
+    }
+}
@@ -58,6 +58,19 @@ pub struct Args {
     )]
     pub remote_dead_check_interval: f32,
 
+    #[arg(
+        long,
+        help = "maximum interval for checking dead remote, if set check interval doubles after each failed check"
+    )]
+    pub remote_dead_check_max_interval: Option<f32>,
+
+    #[arg(
+        long,
+        default_value = "0.1",
+        help = "random change of dead remote check interval, as fraction of interval (0-1)"
+    )]
+    pub remote_dead_check_jitter: f32,
+
     #[arg(long, help = "detailed help on tunnel specification syntax")]
     pub help_tunnel: bool,
 
@@ -82,6 +95,8 @@ impl Default for Args {
             remote_retries: 3,
             remote_errors: 1,
             remote_dead_check_interval: 10.0,
+            remote_dead_check_max_interval: None,
+            remote_dead_check_jitter: 0.1,
             help_tunnel: false,
             ca_bundle: None,
             prometheus_socket: None,
@@ -109,14 +124,28 @@ impl Args {
 
     socket is specified either by port number only, then address part is automatically IPv4 local loop - 127.0.0.1,
     or it's host IP address (IPv4 or IPv6) or host name (that resolves locally to IP address). 
+    Unix domain socket can be used both as local and remote socket - it's specified as unix:/path/to.sock
+    UDP tunnel is created by prefixing local socket with udp: (like udp:0.0.0.0:53), remotes are then
+    plain host:port UDP sockets. Each client address gets own session with selected remote, 
+    session ends after it's idle for session-timeout.
     You can have more then 1 remote socket addresses, in that case connections are load balanced between 
-    remote hosts.
+    remote hosts. Remote can have load balancing weight appended as @weight (like 10.0.0.1:3000@5), 
+    default weight is 1, weights are used by weighted strategies. Remote can be marked as backup by appending !backup
+    (or priority tier number like !2) - backup remotes get connections only when all remotes in lower tiers are dead.
     
     Options must be in [ ] at the end of tunnel specification and they are key value parts separated by comma,
-    like key1=value1,... Valid options are:
+    like key1=value1,... Options of running tunnel can be changed with SET command or updateTunnelOptions RPC method
+    (same format, only given options change, changes apply to new connections, local TLS cannot be changed).
+    Optional options can be unset with value none (like max-conns=none), health-check=none disables health checks
+    and outlier=none disables outlier detection.
+    Valid options are:
     
-    # Load balancing strategy
-    strategy=[random|round-robin|minimum-open-connections]
+    # Load balancing strategy, source-hash and consistent-hash keep clients (by IP address) on same remote,
+    # with consistent-hash adding or removing remote moves only about 1/N of clients,
+    # peak-ewma selects remote with lowest connect latency multiplied by open connections,
+    # p2c (power of two choices) compares same way two randomly chosen remotes,
+    # when plexy is used as library, custom strategies can be registered (State::register_strategy) and used by name
+    strategy=[random|round-robin|minimum-open-connections|weighted-round-robin|weighted-random|source-hash|consistent-hash|peak-ewma|p2c]
     # Timeout for remote connection - seconds, allows decimals
     timeout=<seconds>
     # Retries for remote connection before failing the connection
@@ -125,14 +154,113 @@ impl Args {
     errors=<n>
     # Internal to check if dead remote is alive again, allows decimals
     check-interval=<seconds>
+    # If set, check interval doubles after each failed check up to this maximum, must not be less than check-interval
+    check-interval-max=<seconds>
+    # Check interval is randomly changed by up to this fraction, so checks do not happen in lockstep, default 0.1
+    check-jitter=<0-1>
     # Connect to remote via TLS, default is false
     remote-tls=<true|false>
+    # Terminate TLS on local socket, default is false, requires cert and key options
+    local-tls=<true|false>
+    # Certificate (chain) for local TLS, PEM file
+    cert=<path>
+    # Private key for local TLS, PEM file without password
+    key=<path>
+    # Route connections by TLS SNI server name (TLS is not terminated, unless local-tls is used),
+    # routes are managed via control protocol (ROUTE/UNROUTE) or RPC (addRoute/removeRoute),
+    # connections without SNI or with unknown server name go to remotes not used in any route
+    sni-routing=<true|false>
+    # Send HAProxy PROXY protocol header (version 1 or 2) with real client address to remote,
+    # for TLS remotes header is sent inside TLS session
+    proxy-protocol=<v1|v2>
+    # Expect PROXY protocol header (v1 or v2) from clients (like load balancer in front of plexy),
+    # client address from header is then used as client address
+    accept-proxy-protocol=<true|false>
+    # Sources allowed to send PROXY protocol header, list of CIDRs separated by semicolon,
+    # header is not expected from other sources, required with accept-proxy-protocol=true
+    proxy-trusted=<cidr>[;<cidr>...]
+    # Close connection, if no data were transferred in either direction for this time
+    idle-timeout=<seconds>
+    # Close connection after this time, even if it's still active
+    max-lifetime=<seconds>
+    # Maximum of concurrent connections in tunnel
+    max-conns=<n>
+    # Maximum of concurrent connections to one remote, saturated remotes are skipped by load balancing
+    max-conns-per-remote=<n>
+    # Maximum of concurrent connections from one client IP address (all unix socket clients count as one)
+    max-conns-per-client=<n>
+    # When connection limit is reached, wait this long for free slot, otherwise connection is rejected immediately
+    queue-timeout=<seconds>
+    # Maximum of new connections per second accepted by tunnel, connections over limit are dropped
+    # (can be changed at runtime with LIMIT command or setRateLimits RPC method)
+    accept-rate=<n>
+    # Maximum of new connections per second from one client IP address, connections over limit are dropped
+    client-rate=<n>
+    # Bandwidth limit in bytes per second for each connection, up is from client to remote, down from remote to client
+    # (also can be changed at runtime on live connections)
+    rate-up=<bytes>
+    rate-down=<bytes>
+    # Bandwidth limit in bytes per second for all tunnel connections together
+    tunnel-rate-up=<bytes>
+    tunnel-rate-down=<bytes>
+    # Only clients from these networks are accepted, list of CIDRs separated by semicolon
+    # (lists can be changed at runtime with ALLOW, DENY and UNLIST commands or setAccessLists RPC method,
+    # not applied to unix socket tunnels)
+    allow=<cidr>[;<cidr>...]
+    # Clients from these networks are refused, even if they are in allow list
+    deny=<cidr>[;<cidr>...]
+    # Active health checks of all remotes (any health-* option enables them):
+    # tcp - connect (and TLS handshake with remote-tls=true), tls - TLS handshake, send - send health-send data and expect health-expect in response,
+    # http - GET request to health-path expecting health-status
+    # (UDP tunnels support only send check and it requires health-expect)
+    health-check=<tcp|tls|send|http>
+    # Interval between checks and timeout of one check, default 5 and 2
+    health-interval=<seconds>
+    health-timeout=<seconds>
+    # Consequent successful checks to revive dead remote, default 2
+    health-rise=<n>
+    # Consequent failed checks to consider remote dead, default 3
+    health-fall=<n>
+    # Data to send and data expected in response, escapes \\r \\n \\t \\\\ \\xNN can be used
+    health-send=<data>
+    health-expect=<data>
+    # HTTP path and expected status, default / and 200
+    health-path=</path>
+    health-status=<code>
+    # Outlier detection (any outlier-* option enables it) - remote is ejected from load balancing,
+    # if its connection error rate (percent) in sliding window is too high, default window 30s, error rate 50,
+    # remote is evaluated only after outlier-min-requests connections in window (default 10)
+    outlier-window=<seconds>
+    outlier-error-rate=<percent>
+    outlier-min-requests=<n>
+    # Remote is ejected also, if its average connect latency is more then this multiple of median of other remotes
+    outlier-latency=<factor>
+    # Ejection time, it grows by this value with each repeated ejection up to maximum, default 30 and 300
+    outlier-ejection-time=<seconds>
+    outlier-max-ejection-time=<seconds>
+    # Maximum percentage of remotes, which can be ejected at the same time, default 50
+    outlier-max-ejected=<percent>
+    # Share of connections of recovered or newly added remote ramps up from near zero to full during this time
+    slow-start=<seconds>
+    # Resolve host name remotes to all their addresses, each address is then load balanced (and marked dead) separately
+    # as name/address:port remote, addresses are re-resolved after this interval or when DNS records expire,
+    # new addresses are added and addresses no longer in DNS are drained
+    dns-refresh=<seconds>
+    # UDP tunnel only - session is closed after this time without traffic, default 30
+    session-timeout=<seconds>
 
     Examples of tunnel specifications:
         localhost:4444=some.remote.host.net:3333
         0.0.0.0:4444=192.168.33.5:3333,192.168.34.23:3333[strategy=random]
         3000=3001,3002,3003[strategy=min-open-connections]
+        0.0.0.0:8080=10.0.0.1:8080@4,10.0.0.2:8080@1[strategy=weighted-round-robin]
+        0.0.0.0:5432=10.0.0.1:5432,10.0.0.2:5432!backup
         [::1]:3000=[::1]:3001,[::1]:3002,[::1]:3003[strategy=round-robin,timeout=2]
+        0.0.0.0:443=3000,3001[local-tls=true,cert=data/localhost.crt,key=data/localhost.key]
+        0.0.0.0:5432=unix:/run/postgresql/.s.PGSQL.5432
+        0.0.0.0:80=10.0.0.1:8080,10.0.0.2:8080[health-check=http,health-path=/health,health-interval=2]
+        0.0.0.0:2222=10.0.0.5:22[allow=192.168.0.0/16;2001:db8::/32,deny=192.168.66.0/24]
+        udp:0.0.0.0:5353=10.0.0.1:53,10.0.0.2:53[strategy=round-robin,session-timeout=10]
 
         ")
     }
@@ -144,7 +272,7 @@ mod tests {
 
     #[test]
     fn test_basic_cli() {
-        let args = Args::try_parse_from(&[
+        let args = Args::try_parse_from([
             "plexy",
             "--control-socket",
             "0.0.0.0:9999",
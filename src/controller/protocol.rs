@@ -1,14 +1,20 @@
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
 use async_trait::async_trait;
 use tokio::net::TcpStream;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{
+    drain_tunnel,
     error::{Error, Result},
-    start_tunnel, stop_tunnel,
-    tunnel::SocketSpec,
-    State, Tunnel,
+    start_tunnel,
+    state::stats::RemoteAdminState,
+    stop_tunnel,
+    tunnel::{cidr::parse_cidr_list, Cidr, RateLimits, RemoteSpec, SocketSpec},
+    update_tunnel_options, State, Tunnel, DEFAULT_DRAIN_TIMEOUT,
 };
 
 use self::codec::CommandCodec;
@@ -35,15 +41,63 @@ pub trait Command: FromStr {
 }
 #[derive(Debug)]
 pub enum CommandRequest {
-    Open(Tunnel),
+    Open(Box<Tunnel>),
     Close(SocketSpec),
+    Drain(SocketSpec, Duration),
+    SetRemoteState(SocketSpec, SocketSpec, RemoteAdminState),
     Status(bool),
     Detail(SocketSpec),
     Help,
     Exit,
     Invalid(Error),
-    Add(SocketSpec, SocketSpec),
+    Add(SocketSpec, RemoteSpec),
+    Weight(SocketSpec, SocketSpec, u32),
     Remove(SocketSpec, SocketSpec),
+    Replace(SocketSpec, Vec<RemoteSpec>),
+    Route(SocketSpec, String, SocketSpec),
+    Unroute(SocketSpec, String, SocketSpec),
+    Limit(SocketSpec, RateLimits),
+    Set(SocketSpec, String),
+    Allow(SocketSpec, Vec<Cidr>),
+    Deny(SocketSpec, Vec<Cidr>),
+    Unlist(SocketSpec, Vec<Cidr>),
+    Access(SocketSpec),
+}
+
+fn cidr_args(s: &str) -> Result<(SocketSpec, Vec<Cidr>)> {
+    let (tunnel, cidrs) = s
+        .split_once(' ')
+        .ok_or_else(|| Error::ControlProtocolError("Missing networks".into()))?;
+    let cidrs = parse_cidr_list(cidrs)?;
+    if cidrs.is_empty() {
+        return Err(Error::ControlProtocolError("Missing networks".into()));
+    }
+    Ok((tunnel.parse()?, cidrs))
+}
+
+fn add_cidrs(list: &mut Vec<Cidr>, cidrs: Vec<Cidr>) {
+    for cidr in cidrs {
+        if !list.contains(&cidr) {
+            list.push(cidr)
+        }
+    }
+}
+
+fn route_args(s: &str) -> Result<(SocketSpec, String, SocketSpec)> {
+    let mut args = s.split_whitespace();
+    let tunnel: SocketSpec = args
+        .next()
+        .ok_or_else(|| Error::ControlProtocolError("Missing tunnel socket spec".into()))
+        .and_then(|s| s.parse())?;
+    let server_name = args
+        .next()
+        .ok_or_else(|| Error::ControlProtocolError("Missing server name".into()))?
+        .to_string();
+    let remote: SocketSpec = args
+        .next()
+        .ok_or_else(|| Error::ControlProtocolError("Missing remote socket spec".into()))
+        .and_then(|s| s.parse())?;
+    Ok((tunnel, server_name, remote))
 }
 
 impl FromStr for CommandRequest {
@@ -90,7 +144,7 @@ impl FromStr for CommandRequest {
             }
             "OPEN" => {
                 let tunnel: Tunnel = args()?.parse()?;
-                Ok(CommandRequest::Open(tunnel))
+                Ok(CommandRequest::Open(Box::new(tunnel)))
             }
             "HELP" => Ok(CommandRequest::Help),
             "EXIT" => Ok(CommandRequest::Exit),
@@ -98,18 +152,121 @@ impl FromStr for CommandRequest {
                 let addr: SocketSpec = args()?.parse()?;
                 Ok(CommandRequest::Close(addr))
             }
+            "DRAIN" => {
+                let mut args = args()?.split_whitespace();
+                let addr: SocketSpec = args
+                    .next()
+                    .ok_or_else(|| Error::ControlProtocolError("Missing tunnel socket spec".into()))
+                    .and_then(|s| s.parse())?;
+                let timeout = match args.next() {
+                    Some(t) => t.parse::<f32>().ok().filter(|t| *t >= 0.0).ok_or_else(|| {
+                        Error::ControlProtocolError(format!("Invalid drain timeout: {}", t))
+                    })?,
+                    None => DEFAULT_DRAIN_TIMEOUT,
+                };
+                Ok(CommandRequest::Drain(
+                    addr,
+                    Duration::from_secs_f32(timeout),
+                ))
+            }
+            "DRAINREMOTE" => {
+                let (tunnel, remote) = two_sockets()?;
+                Ok(CommandRequest::SetRemoteState(
+                    tunnel,
+                    remote,
+                    RemoteAdminState::Draining,
+                ))
+            }
+            "ENABLE" => {
+                let (tunnel, remote) = two_sockets()?;
+                Ok(CommandRequest::SetRemoteState(
+                    tunnel,
+                    remote,
+                    RemoteAdminState::Active,
+                ))
+            }
+            "DISABLE" => {
+                let (tunnel, remote) = two_sockets()?;
+                Ok(CommandRequest::SetRemoteState(
+                    tunnel,
+                    remote,
+                    RemoteAdminState::Disabled,
+                ))
+            }
             "DETAIL" => {
                 let addr: SocketSpec = args()?.parse()?;
                 Ok(CommandRequest::Detail(addr))
             }
             "ADD" => {
-                let (tunnel, remote) = two_sockets()?;
-                Ok(CommandRequest::Add(tunnel, remote))
+                let (tunnel, remote) = args()?.split_once(' ').ok_or_else(|| {
+                    Error::ControlProtocolError("Missing remote socket spec".into())
+                })?;
+                Ok(CommandRequest::Add(tunnel.parse()?, remote.trim().parse()?))
+            }
+            "WEIGHT" => {
+                let mut args = args()?.split_whitespace();
+                let mut next = |name: &str| {
+                    args.next()
+                        .ok_or_else(|| Error::ControlProtocolError(format!("Missing {}", name)))
+                };
+                let tunnel = next("tunnel socket spec")?.parse()?;
+                let remote = next("remote socket spec")?.parse()?;
+                let weight = next("weight")?
+                    .parse()
+                    .map_err(|_| Error::ControlProtocolError("Invalid weight".into()))?;
+                Ok(CommandRequest::Weight(tunnel, remote, weight))
             }
             "REMOVE" => {
                 let (tunnel, remote) = two_sockets()?;
                 Ok(CommandRequest::Remove(tunnel, remote))
             }
+            "REPLACE" => {
+                let (tunnel, remotes) = args()?
+                    .split_once(' ')
+                    .ok_or_else(|| Error::ControlProtocolError("Missing remotes".into()))?;
+                let remotes = remotes
+                    .trim()
+                    .split(',')
+                    .map(|r| r.trim().parse())
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(CommandRequest::Replace(tunnel.parse()?, remotes))
+            }
+            "ROUTE" => {
+                let (tunnel, server_name, remote) = route_args(args()?)?;
+                Ok(CommandRequest::Route(tunnel, server_name, remote))
+            }
+            "UNROUTE" => {
+                let (tunnel, server_name, remote) = route_args(args()?)?;
+                Ok(CommandRequest::Unroute(tunnel, server_name, remote))
+            }
+            "LIMIT" => {
+                let (tunnel, limits) = args()?
+                    .split_once(' ')
+                    .ok_or_else(|| Error::ControlProtocolError("Missing limits".into()))?;
+                Ok(CommandRequest::Limit(tunnel.parse()?, limits.parse()?))
+            }
+            "SET" => {
+                let (tunnel, options) = args()?
+                    .split_once(' ')
+                    .ok_or_else(|| Error::ControlProtocolError("Missing options".into()))?;
+                Ok(CommandRequest::Set(tunnel.parse()?, options.trim().into()))
+            }
+            "ALLOW" => {
+                let (tunnel, cidrs) = cidr_args(args()?)?;
+                Ok(CommandRequest::Allow(tunnel, cidrs))
+            }
+            "DENY" => {
+                let (tunnel, cidrs) = cidr_args(args()?)?;
+                Ok(CommandRequest::Deny(tunnel, cidrs))
+            }
+            "UNLIST" => {
+                let (tunnel, cidrs) = cidr_args(args()?)?;
+                Ok(CommandRequest::Unlist(tunnel, cidrs))
+            }
+            "ACCESS" => {
+                let addr: SocketSpec = args()?.parse()?;
+                Ok(CommandRequest::Access(addr))
+            }
             _ => Err(Error::ControlProtocolError(format!(
                 "Invalid command: {}",
                 cmd
@@ -172,8 +329,16 @@ impl<T> From<Result<T>> for CommandResponse {
 impl Command for CommandRequest {
     async fn exec(self, ctx: State) -> CommandResponse {
         match self {
-            CommandRequest::Open(tunnel) => start_tunnel(tunnel, ctx).await.into(),
+            CommandRequest::Open(tunnel) => start_tunnel(*tunnel, ctx).await.into(),
             CommandRequest::Close(local) => stop_tunnel(&local, ctx).into(),
+            CommandRequest::Drain(local, timeout) => match drain_tunnel(&local, timeout, ctx).await
+            {
+                Ok(closed) => CommandResponse::Info {
+                    short: format!("Drained, closed connections: {}", closed),
+                    details: None,
+                },
+                Err(e) => CommandResponse::Problem(Some(e)),
+            },
             CommandRequest::Invalid(e) => CommandResponse::Problem(Some(e)),
             CommandRequest::Exit => CommandResponse::Done,
             CommandRequest::Status(long) => {
@@ -190,13 +355,18 @@ impl Command for CommandRequest {
                             .into_iter()
                             .map(|(local, stats)| {
                                 format!(
-                                    "{} = open conns {}, total conns {}, bytes sent {}, received {}, total errors {}",
+                                    "{} = open conns {}, total conns {}, bytes sent {}, received {}, total errors {}, idle timeouts {}, lifetime timeouts {}, rejected {}, rate limited {}, denied {}",
                                     local,
                                     stats.streams_open,
                                     stats.total_connections,
                                     stats.bytes_sent,
                                     stats.bytes_received,
                                     stats.errors,
+                                    stats.idle_timeouts,
+                                    stats.lifetime_timeouts,
+                                    stats.rejected,
+                                    stats.rate_limited,
+                                    stats.denied,
                                 )
                             })
                             .collect();
@@ -214,23 +384,76 @@ impl Command for CommandRequest {
                         Err(e) => return CommandResponse::Problem(Some(e)),
                     };
 
+                    let draining = if ctx.is_draining(&local) {
+                        ", Draining"
+                    } else {
+                        ""
+                    };
+                    let failover = remotes
+                        .iter()
+                        .filter(|(_, info)| info.admin_state == RemoteAdminState::Active)
+                        .map(|(_, info)| info.tier)
+                        .min()
+                        .filter(|tier| *tier > 0)
+                        .map(|tier| format!(", Failed over to tier {}", tier))
+                        .unwrap_or_default();
                     let short = format!(
-                        "Remotes: {}, Dead Remotes: {}, Options: {}",
+                        "Remotes: {}, Dead Remotes: {}{}{}, Options: {}",
                         remotes.len(),
                         dead_remotes,
+                        draining,
+                        failover,
                         options
                     );
+                    let routes = ctx.routes(&local).unwrap_or_default();
+                    let resolved = ctx.resolved_names(&local).unwrap_or_default();
+                    let dead = ctx.dead_remotes(&local).unwrap_or_default();
+                    let now = SystemTime::now();
                     let details = remotes.into_iter()
                         .map(|(remote, info)| format!(
-                        "{} = open conns {}, total conns {}, bytes sent {}, received {}, recent errors {}, total errors {}",
+                        "{} = state {}{}, weight {}, tier {}, open conns {}, total conns {}, bytes sent {}, received {}, recent errors {}, total errors {}, ejections {}{}",
                             remote,
+                            info.admin_state,
+                            info.ejected_until
+                                .and_then(|t| t.duration_since(now).ok())
+                                .map(|d| format!(" (ejected for {:.1}s)", d.as_secs_f32()))
+                                .unwrap_or_default(),
+                            info.weight,
+                            info.tier,
                             info.streams_open,
                             info.total_connections,
                             info.bytes_sent,
                             info.bytes_received,
                             info.num_errors,
                             info.total_errors,
-                        )).collect();
+                            info.total_ejections,
+                            info.connect_latency_ewma
+                                .map(|l| format!(", connect latency {:.1}ms", l))
+                                .unwrap_or_default(),
+                        ))
+                        .chain(dead.into_iter().map(|(remote, dead)| {
+                            let next_check = dead
+                                .next_check
+                                .and_then(|t| t.duration_since(now).ok())
+                                .unwrap_or_default();
+                            format!(
+                                "{} = dead, state {}, next check in {:.1}s, check interval {}s, total errors {}",
+                                remote,
+                                dead.stats.admin_state,
+                                next_check.as_secs_f32(),
+                                dead.retry_interval,
+                                dead.stats.total_errors,
+                            )
+                        }))
+                        .chain(routes.into_iter().map(|(server_name, remotes)| {
+                            let remotes: Vec<_> = remotes.iter().map(|r| r.to_string()).collect();
+                            format!("route {} -> {}", server_name, remotes.join(","))
+                        }))
+                        .chain(resolved.into_iter().map(|(name, remotes)| {
+                            let remotes: Vec<_> = remotes.iter().map(|r| r.to_string()).collect();
+                            format!("dns {} -> {}", name, remotes.join(","))
+                        }))
+                        .collect();
                     CommandResponse::Info {
                         short,
                         details: Some(details),
@@ -242,8 +465,22 @@ impl Command for CommandRequest {
                 let help = &[
                     "OPEN tunnel",
                     "CLOSE tunnel",
-                    "ADD socket_address",
-                    "REMOVE socket_address",
+                    "DRAIN tunnel [timeout] (stop accepting, wait for open connections, then close)",
+                    "ADD tunnel remote[@weight][!backup|!tier]",
+                    "WEIGHT tunnel remote weight (set load balancing weight of remote)",
+                    "REMOVE socket_address (remote is removed after its open connections finish)",
+                    "REPLACE tunnel remote[,remote...] (replace all remotes at once, dropped remotes are drained)",
+                    "DRAINREMOTE tunnel remote (no new connections to remote, disabled when open ones finish)",
+                    "DISABLE tunnel remote",
+                    "ENABLE tunnel remote (also cancels pending removal)",
+                    "ROUTE tunnel server_name remote",
+                    "UNROUTE tunnel server_name remote",
+                    "LIMIT tunnel limit=value[,limit=value...] (accept-rate, client-rate, rate-up, rate-down, tunnel-rate-up, tunnel-rate-down, 0 for no limit)",
+                    "SET tunnel option=value[,option=value...] (change tunnel options, applies to new connections, option=none unsets option)",
+                    "ALLOW tunnel network[;network...] (add to client allow list)",
+                    "DENY tunnel network[;network...] (add to client deny list)",
+                    "UNLIST tunnel network[;network...] (remove from both client access lists)",
+                    "ACCESS tunnel (show client access lists)",
                     "STATUS [full|long]",
                     "DETAIL tunnel",
                     "EXIT",
@@ -256,9 +493,114 @@ impl Command for CommandRequest {
                 }
             }
             CommandRequest::Add(tunnel, remote) => ctx.add_remote_to_tunnel(&tunnel, remote).into(),
+            CommandRequest::Weight(tunnel, remote, weight) => {
+                ctx.set_remote_weight(&tunnel, &remote, weight).into()
+            }
+            CommandRequest::SetRemoteState(tunnel, remote, admin_state) => {
+                ctx.set_remote_state(&tunnel, &remote, admin_state).into()
+            }
             CommandRequest::Remove(tunnel, remote) => {
                 ctx.remove_remote_from_tunnel(&tunnel, &remote).into()
             }
+            CommandRequest::Replace(tunnel, remotes) => match ctx.replace_remotes(&tunnel, remotes)
+            {
+                Ok(change) => CommandResponse::Info {
+                    short: change.to_string(),
+                    details: None,
+                },
+                Err(e) => CommandResponse::Problem(Some(e)),
+            },
+            CommandRequest::Route(tunnel, server_name, remote) => {
+                ctx.add_route(&tunnel, &server_name, remote).into()
+            }
+            CommandRequest::Unroute(tunnel, server_name, remote) => {
+                ctx.remove_route(&tunnel, &server_name, &remote).into()
+            }
+            CommandRequest::Limit(tunnel, limits) => match ctx.set_rate_limits(&tunnel, &limits) {
+                Ok(limits) => CommandResponse::Info {
+                    short: limits.to_string(),
+                    details: None,
+                },
+                Err(e) => CommandResponse::Problem(Some(e)),
+            },
+            CommandRequest::Set(tunnel, options) => {
+                match update_tunnel_options(&tunnel, &options, ctx) {
+                    Ok(update) => CommandResponse::Info {
+                        short: "Options changed".into(),
+                        details: Some(vec![
+                            format!("previous: {}", update.previous),
+                            format!("current: {}", update.current),
+                        ]),
+                    },
+                    Err(e) => CommandResponse::Problem(Some(e)),
+                }
+            }
+            CommandRequest::Allow(tunnel, cidrs) => ctx
+                .update_access_lists(&tunnel, |allow, _| add_cidrs(allow, cidrs))
+                .into(),
+            CommandRequest::Deny(tunnel, cidrs) => ctx
+                .update_access_lists(&tunnel, |_, deny| add_cidrs(deny, cidrs))
+                .into(),
+            CommandRequest::Unlist(tunnel, cidrs) => ctx
+                .update_access_lists(&tunnel, |allow, deny| {
+                    allow.retain(|c| !cidrs.contains(c));
+                    deny.retain(|c| !cidrs.contains(c));
+                })
+                .into(),
+            CommandRequest::Access(tunnel) => match ctx.tunnel_options(&tunnel) {
+                Ok(options) => CommandResponse::Info {
+                    short: options.access_lists().to_string(),
+                    details: None,
+                },
+                Err(e) => CommandResponse::Problem(Some(e)),
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drain_commands() {
+        let cmd: CommandRequest = "DRAIN 3000 5".parse().unwrap();
+        assert!(
+            matches!(cmd, CommandRequest::Drain(ref t, d) if t.port() == Some(3000) && d == Duration::from_secs(5))
+        );
+        let cmd: CommandRequest = "drain 3000".parse().unwrap();
+        assert!(matches!(
+            cmd,
+            CommandRequest::Drain(_, d) if d == Duration::from_secs_f32(DEFAULT_DRAIN_TIMEOUT)
+        ));
+        assert!("DRAIN 3000 -1".parse::<CommandRequest>().is_err());
+        assert!("DRAIN 3000 127.0.0.1:3001"
+            .parse::<CommandRequest>()
+            .is_err());
+        assert!("DRAIN".parse::<CommandRequest>().is_err());
+
+        let cmd: CommandRequest = "DRAINREMOTE 3000 127.0.0.1:3001".parse().unwrap();
+        assert!(matches!(
+            cmd,
+            CommandRequest::SetRemoteState(_, ref r, RemoteAdminState::Draining) if r.port() == Some(3001)
+        ));
+        assert!("DRAINREMOTE 3000".parse::<CommandRequest>().is_err());
+    }
+
+    #[test]
+    fn test_remote_state_commands() {
+        let cmd: CommandRequest = "ENABLE 3000 127.0.0.1:3001".parse().unwrap();
+        assert!(matches!(
+            cmd,
+            CommandRequest::SetRemoteState(ref t, ref r, RemoteAdminState::Active)
+                if t.port() == Some(3000) && r.port() == Some(3001)
+        ));
+        let cmd: CommandRequest = "disable 3000 127.0.0.1:3001".parse().unwrap();
+        assert!(matches!(
+            cmd,
+            CommandRequest::SetRemoteState(_, _, RemoteAdminState::Disabled)
+        ));
+        assert!("ENABLE 3000".parse::<CommandRequest>().is_err());
+        assert!("DISABLE".parse::<CommandRequest>().is_err());
+    }
+}
@@ -28,6 +28,26 @@ pub enum Error {
     RPCError(#[from] jsonrpsee::core::Error),
     #[error("Certificate error: {0}")]
     CertificateError(webpki::Error),
+    #[error("TLS error: {0}")]
+    TLSError(#[from] rustls::Error),
+    #[error("TLS configuration error: {0}")]
+    TLSConfigError(String),
+    #[error("Route does not exist")]
+    RouteDoesNotExist,
+    #[error("PROXY protocol error: {0}")]
+    ProxyProtocolError(String),
+    #[error("Invalid CIDR: {0}")]
+    CidrParseError(String),
+    #[error("Invalid tunnel: {0}")]
+    InvalidTunnel(String),
+    #[error("Connection limit: {0}")]
+    ConnectionLimit(String),
+    #[error("Rate limit: {0}")]
+    RateLimited(String),
+    #[error("Access denied for client {0}")]
+    AccessDenied(String),
+    #[error("Tunnel is draining")]
+    TunnelDraining,
 }
 
 impl From<webpki::Error> for Error {
@@ -55,6 +75,16 @@ impl Error {
             Error::InvalidLBStrategy => ERROR_BASE + 12,
             Error::RPCError(_) => ERROR_BASE + 13,
             Error::CertificateError(_) => ERROR_BASE + 14,
+            Error::TLSError(_) => ERROR_BASE + 15,
+            Error::TLSConfigError(_) => ERROR_BASE + 16,
+            Error::RouteDoesNotExist => ERROR_BASE + 17,
+            Error::ProxyProtocolError(_) => ERROR_BASE + 18,
+            Error::CidrParseError(_) => ERROR_BASE + 19,
+            Error::InvalidTunnel(_) => ERROR_BASE + 20,
+            Error::ConnectionLimit(_) => ERROR_BASE + 21,
+            Error::RateLimited(_) => ERROR_BASE + 22,
+            Error::AccessDenied(_) => ERROR_BASE + 23,
+            Error::TunnelDraining => ERROR_BASE + 24,
         }
     }
 }
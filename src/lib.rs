@@ -1,23 +1,40 @@
-use std::{error::Error, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 use error::Result;
 
 use futures::TryFutureExt;
 use rustls::ClientConfig;
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
     sync::watch,
     task::JoinHandle,
-    time::timeout,
+    time::{sleep_until, timeout, timeout_at, Instant},
 };
-use tokio_rustls::TlsConnector;
-use tracing::{debug, error, instrument};
-use tunnel::SocketSpec;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, error, info, instrument, Span};
+use tunnel::{cidr::cidr_list_contains, SocketSpec, TunnelOptions, TunnelOptionsUpdate};
 
-pub use state::State;
+pub use state::{
+    info::TunnelInfo,
+    stats::RemoteStats,
+    strategy::{ConnectionContext, LBStrategy, RemoteView, StrategyFactory, TunnelLBStrategy},
+    State,
+};
 pub use tunnel::Tunnel;
 
-use crate::aio::copy_bidirectional;
+use crate::{
+    aio::{copy_bidirectional, StreamInfo, StreamTimeout, StreamTimeouts},
+    proxy_protocol::{encode_header, read_header},
+    sni::{normalize_server_name, read_client_hello},
+    state::{dns, tls::create_server_config},
+};
 
 mod aio;
 pub mod config;
@@ -25,13 +42,38 @@ pub mod controller;
 pub mod error;
 #[cfg(feature = "metrics")]
 pub mod metrics;
+pub mod proxy_protocol;
 pub mod rpc;
+mod sni;
 mod state;
 pub mod tunnel;
+mod udp;
+
+/// Unix socket peers do not have IP address, so they are represented by this one
+const UNIX_CLIENT_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// How long draining tunnel waits for open connections, if not specified
+pub const DEFAULT_DRAIN_TIMEOUT: f32 = 30.0;
 
 enum GenericStream {
     Open(TcpStream),
-    Encrypted(tokio_rustls::client::TlsStream<TcpStream>),
+    Unix(UnixStream),
+    /// TLS connection to remote
+    Encrypted(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+    /// TLS connection from client terminated in tunnel
+    Terminated(Box<tokio_rustls::server::TlsStream<GenericStream>>),
+}
+
+impl GenericStream {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            GenericStream::Open(s) => s.local_addr(),
+            GenericStream::Unix(_) => Ok(UNIX_CLIENT_ADDR),
+            GenericStream::Encrypted(s) => s.get_ref().0.local_addr(),
+            GenericStream::Terminated(s) => s.get_ref().0.local_addr(),
+        }
+    }
 }
 
 impl tokio::io::AsyncRead for GenericStream {
@@ -42,7 +84,9 @@ impl tokio::io::AsyncRead for GenericStream {
     ) -> std::task::Poll<std::io::Result<()>> {
         match self.get_mut() {
             GenericStream::Open(me) => Pin::new(me).poll_read(cx, buf),
+            GenericStream::Unix(me) => Pin::new(me).poll_read(cx, buf),
             GenericStream::Encrypted(me) => Pin::new(me).poll_read(cx, buf),
+            GenericStream::Terminated(me) => Pin::new(me).poll_read(cx, buf),
         }
     }
 }
@@ -55,7 +99,9 @@ impl tokio::io::AsyncWrite for GenericStream {
     ) -> std::task::Poll<std::result::Result<usize, std::io::Error>> {
         match self.get_mut() {
             GenericStream::Open(me) => Pin::new(me).poll_write(cx, buf),
+            GenericStream::Unix(me) => Pin::new(me).poll_write(cx, buf),
             GenericStream::Encrypted(me) => Pin::new(me).poll_write(cx, buf),
+            GenericStream::Terminated(me) => Pin::new(me).poll_write(cx, buf),
         }
     }
 
@@ -65,7 +111,9 @@ impl tokio::io::AsyncWrite for GenericStream {
     ) -> std::task::Poll<std::result::Result<(), std::io::Error>> {
         match self.get_mut() {
             GenericStream::Open(me) => Pin::new(me).poll_flush(cx),
+            GenericStream::Unix(me) => Pin::new(me).poll_flush(cx),
             GenericStream::Encrypted(me) => Pin::new(me).poll_flush(cx),
+            GenericStream::Terminated(me) => Pin::new(me).poll_flush(cx),
         }
     }
 
@@ -75,7 +123,9 @@ impl tokio::io::AsyncWrite for GenericStream {
     ) -> std::task::Poll<std::result::Result<(), std::io::Error>> {
         match self.get_mut() {
             GenericStream::Open(me) => Pin::new(me).poll_shutdown(cx),
+            GenericStream::Unix(me) => Pin::new(me).poll_shutdown(cx),
             GenericStream::Encrypted(me) => Pin::new(me).poll_shutdown(cx),
+            GenericStream::Terminated(me) => Pin::new(me).poll_shutdown(cx),
         }
     }
 }
@@ -84,35 +134,181 @@ pub(crate) async fn connect_remote(
     remote: &SocketSpec,
     tls_config: Option<Arc<ClientConfig>>,
 ) -> std::result::Result<GenericStream, std::io::Error> {
-    let stream = TcpStream::connect(remote.as_tuple()).await?;
+    if let Some(path) = remote.unix_path() {
+        if tls_config.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "TLS is not supported for unix socket remote",
+            ));
+        }
+        return Ok(GenericStream::Unix(UnixStream::connect(path).await?));
+    }
+    let (host, port) = remote.host_port()?;
+    let stream = TcpStream::connect((host, port)).await?;
     if let Some(tls_config) = tls_config {
         let connector = TlsConnector::from(tls_config);
-        let domain = rustls::ServerName::try_from(remote.host())
+        let domain = rustls::ServerName::try_from(remote.server_name().unwrap_or(host))
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
-        Ok(GenericStream::Encrypted(
+        Ok(GenericStream::Encrypted(Box::new(
             connector.connect(domain, stream).await?,
-        ))
+        )))
     } else {
         Ok(GenericStream::Open(stream))
     }
 }
 
-#[instrument(skip_all, fields(client=%local_client, tunnel=%tunnel_key))]
+async fn accept_client(
+    socket: GenericStream,
+    tls: Option<(TlsAcceptor, Duration)>,
+) -> std::result::Result<GenericStream, std::io::Error> {
+    match tls {
+        Some((acceptor, handshake_timeout)) => {
+            match timeout(handshake_timeout, acceptor.accept(socket)).await {
+                Ok(stream) => Ok(GenericStream::Terminated(Box::new(stream?))),
+                Err(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "TLS handshake timeout",
+                )),
+            }
+        }
+        None => Ok(socket),
+    }
+}
+
+/// Client address and address it connected to - either from socket
+/// or from PROXY protocol header, if tunnel accepts it from this peer
+async fn client_addresses(
+    socket: &mut GenericStream,
+    peer_addr: SocketAddr,
+    options: &TunnelOptions,
+) -> Result<(SocketAddr, SocketAddr)> {
+    let local_addr = socket.local_addr()?;
+    if options.accept_proxy_protocol && cidr_list_contains(&options.proxy_trusted, &peer_addr.ip())
+    {
+        let wait_for = Duration::from_secs_f32(options.options.connect_timeout);
+        match timeout(wait_for, read_header(socket)).await {
+            Ok(Ok(Some(addresses))) => Ok(addresses),
+            Ok(Ok(None)) => Ok((peer_addr, local_addr)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(crate::error::Error::ProxyProtocolError(
+                "timeout while waiting for header".into(),
+            )),
+        }
+    } else {
+        Ok((peer_addr, local_addr))
+    }
+}
+
+/// Repeats `f` while it fails on connection limit, waiting for released connection slot,
+/// until queue deadline - without deadline fails immediately
+async fn wait_for_slot<T, F>(
+    state: &State,
+    tunnel_key: &SocketSpec,
+    deadline: Option<Instant>,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let slot_released = state.slot_released(tunnel_key)?;
+    loop {
+        // must be created before check, so release cannot be missed
+        let released = slot_released.notified();
+        match f() {
+            Err(crate::error::Error::ConnectionLimit(reason)) => match deadline {
+                Some(deadline) if timeout_at(deadline, released).await.is_ok() => continue,
+                _ => return Err(crate::error::Error::ConnectionLimit(reason)),
+            },
+            res => return res,
+        }
+    }
+}
+
+#[instrument(skip_all, fields(client, tunnel=%tunnel_key, server_name))]
 async fn process_socket(
-    mut socket: TcpStream,
-    local_client: SocketAddr,
+    mut socket: GenericStream,
+    peer_addr: SocketAddr,
     tunnel_key: SocketSpec,
     state: State,
     finish_receiver: watch::Receiver<bool>,
+    tls: Option<(TlsAcceptor, Duration)>,
 ) -> Result<()> {
+    let tunnel_options = state.tunnel_options(&tunnel_key)?;
+    let (local_client, local_addr) =
+        match client_addresses(&mut socket, peer_addr, &tunnel_options).await {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                error!(error=%e, peer=%peer_addr, "Cannot get client address");
+                return Ok(());
+            }
+        };
+    Span::current().record("client", tracing::field::display(local_client));
+    if let Err(e) = state.check_client_rate(&tunnel_key, &local_client) {
+        debug!(error=%e, "Connection dropped");
+        return Ok(());
+    }
+    let mut socket = match accept_client(socket, tls).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!(error=%e, "Cannot accept client TLS connection");
+            return Ok(());
+        }
+    };
+    let (mut initial_data, server_name) = match socket {
+        GenericStream::Terminated(ref s) => (
+            vec![],
+            s.get_ref().1.server_name().map(normalize_server_name),
+        ),
+        _ if tunnel_options.sni_routing => {
+            let wait_for = Duration::from_secs_f32(tunnel_options.options.connect_timeout);
+            match read_client_hello(&mut socket, wait_for).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!(error=%e, "Cannot read TLS ClientHello");
+                    return Ok(());
+                }
+            }
+        }
+        _ => (vec![], None),
+    };
+    if let Some(ref name) = server_name {
+        Span::current().record("server_name", name.as_str());
+    }
+    if let Some(version) = tunnel_options.proxy_protocol {
+        let mut header = encode_header(version, local_client, local_addr);
+        header.append(&mut initial_data);
+        initial_data = header;
+    }
+    let queue_deadline = tunnel_options
+        .queue_timeout
+        .map(|t| Instant::now() + Duration::from_secs_f32(t));
+    if let Err(e) = wait_for_slot(&state, &tunnel_key, queue_deadline, || {
+        state.try_client_connected(&tunnel_key, &local_client)
+    })
+    .await
+    {
+        state.client_rejected(&tunnel_key);
+        error!(error=%e, "Connection rejected");
+        return Ok(());
+    }
     debug!("Client connected");
-    state.client_connected(&tunnel_key, &local_client);
     let mut last_remote = None;
     let mut retries = state.remote_retries(&tunnel_key)?;
     while retries > 0 {
-        match state.select_remote(&tunnel_key) {
+        match wait_for_slot(&state, &tunnel_key, queue_deadline, || {
+            state.select_remote(
+                &tunnel_key,
+                &ConnectionContext {
+                    client: Some(local_client),
+                    server_name: server_name.as_deref(),
+                },
+            )
+        })
+        .await
+        {
             Ok((remote, options)) => {
                 debug!(remote=%remote, "Selected remote");
+                let connect_start = Instant::now();
                 match timeout(
                     Duration::from_secs_f32(options.connect_timeout),
                     connect_remote(&remote, options.tls_config(&state)),
@@ -120,24 +316,48 @@ async fn process_socket(
                 .await
                 {
                     Ok(Ok(mut stream)) => {
-                        state.remote_connected(&tunnel_key, &remote, &local_client);
+                        state.remote_connected(
+                            &tunnel_key,
+                            &remote,
+                            &local_client,
+                            connect_start.elapsed(),
+                        );
                         last_remote = Some(remote.clone());
-                        match copy_bidirectional(
-                            &mut socket,
-                            &mut stream,
-                            tunnel_key.clone(),
-                            remote,
-                            local_client,
-                            state.clone(),
-                            finish_receiver,
-                        )
-                        .await
-                        {
+                        let res = match stream.write_all(&initial_data).await {
+                            Ok(()) => {
+                                copy_bidirectional(
+                                    &mut socket,
+                                    &mut stream,
+                                    StreamInfo {
+                                        tunnel: tunnel_key.clone(),
+                                        remote,
+                                        client: local_client,
+                                    },
+                                    state.clone(),
+                                    finish_receiver,
+                                    StreamTimeouts {
+                                        idle: tunnel_options
+                                            .idle_timeout
+                                            .map(Duration::from_secs_f32),
+                                        max_lifetime: tunnel_options
+                                            .max_lifetime
+                                            .map(Duration::from_secs_f32),
+                                    },
+                                )
+                                .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        match res {
                             Ok((_sent, _received)) => {
                                 // state.update_stats(&tunnel.local, received, sent, remote_client.as_ref());
                             }
-                            Err(e) => match e.kind() {
-                                std::io::ErrorKind::UnexpectedEof => {
+                            Err(e) => match (StreamTimeout::from_io_error(&e), e.kind()) {
+                                (Some(reason), _) => {
+                                    debug!(reason=%reason, "Connection closed on timeout");
+                                    state.stream_timed_out(&tunnel_key, reason);
+                                }
+                                (None, std::io::ErrorKind::UnexpectedEof) => {
                                     let s = e.source();
                                     debug!("Unexpected end of stream ({:?})", s)
                                 }
@@ -159,6 +379,9 @@ async fn process_socket(
                 }
             }
             Err(e) => {
+                if let crate::error::Error::ConnectionLimit(_) = e {
+                    state.client_rejected(&tunnel_key);
+                }
                 error!(error=%e, "Cannot get available remote");
                 last_remote = None;
                 break;
@@ -175,11 +398,64 @@ async fn process_socket(
     Ok(())
 }
 
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, std::path::PathBuf),
+}
+
+impl Listener {
+    async fn bind(local: &SocketSpec) -> std::io::Result<Self> {
+        match local.unix_path() {
+            Some(path) => {
+                let listener = match UnixListener::bind(path) {
+                    Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                        // socket file can be left from previous run, remove it if nobody listens on it
+                        if UnixStream::connect(path).await.is_ok() {
+                            return Err(e);
+                        }
+                        debug!(path = path, "Removing stale unix socket");
+                        std::fs::remove_file(path)?;
+                        UnixListener::bind(path)?
+                    }
+                    res => res?,
+                };
+                Ok(Listener::Unix(listener, path.into()))
+            }
+            None => Ok(Listener::Tcp(TcpListener::bind(local.host_port()?).await?)),
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<(GenericStream, SocketAddr)> {
+        match self {
+            Listener::Tcp(l) => l
+                .accept()
+                .await
+                .map(|(s, addr)| (GenericStream::Open(s), addr)),
+            Listener::Unix(l, _) => l
+                .accept()
+                .await
+                .map(|(s, _)| (GenericStream::Unix(s), UNIX_CLIENT_ADDR)),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            if let Err(e) = std::fs::remove_file(&path) {
+                error!(error=%e, path=?path, "Cannot remove unix socket")
+            }
+        }
+    }
+}
+
 pub(crate) struct TunnelHandler {
     state: State,
     tunnel_key: SocketSpec,
-    listener: TcpListener,
+    listener: Listener,
     close_channel: watch::Receiver<bool>,
+    drain_channel: watch::Receiver<bool>,
+    tls: Option<(TlsAcceptor, Duration)>,
 }
 
 pub fn stop_tunnel(local: &SocketSpec, state: State) -> Result<()> {
@@ -190,24 +466,109 @@ pub fn stop_tunnel(local: &SocketSpec, state: State) -> Result<()> {
     Ok(())
 }
 
+/// Stops accepting new connections on tunnel, waits until open connections finish,
+/// but at most `timeout`, then closes tunnel.
+/// Returns number of connections, which were still open and had to be closed.
+pub async fn drain_tunnel(local: &SocketSpec, timeout: Duration, state: State) -> Result<usize> {
+    let released = state.start_draining(local)?;
+    let deadline = Instant::now() + timeout;
+    debug!(tunnel=%local, "Draining tunnel");
+    loop {
+        let notified = released.notified();
+        if state.open_connections(local)? == 0 {
+            break;
+        }
+        tokio::select! {
+            _ = notified => (),
+            _ = sleep_until(deadline) => break,
+        }
+    }
+    let remaining = state.open_connections(local)?;
+    stop_tunnel(local, state)?;
+    info!(tunnel=%local, closed_connections=remaining, "Tunnel drained");
+    Ok(remaining)
+}
+
 pub async fn start_tunnel(tunnel: Tunnel, state: State) -> Result<JoinHandle<()>> {
-    let handler = create_tunnel(tunnel, state).await?;
-    Ok(tokio::spawn(run_tunnel(handler)))
+    let tunnel_key = tunnel.local.clone();
+    let handle = if tunnel.local.is_udp() {
+        let handler = udp::create_tunnel(tunnel, state.clone()).await?;
+        tokio::spawn(udp::run_tunnel(handler))
+    } else {
+        let handler = create_tunnel(tunnel, state.clone()).await?;
+        tokio::spawn(run_tunnel(handler))
+    };
+    let options = state.tunnel_options(&tunnel_key)?;
+    if options.health_check.is_some() {
+        state.spawn_health_checks(&tunnel_key)?;
+    }
+    if options.dns_refresh.is_some() {
+        spawn_dns_refresh(&tunnel_key, state)?;
+    }
+    Ok(handle)
+}
+
+fn spawn_dns_refresh(tunnel_key: &SocketSpec, state: State) -> Result<()> {
+    let close_channel = state.close_receiver(tunnel_key)?;
+    let task = tokio::spawn(dns::run_dns_refresh(
+        state.clone(),
+        tunnel_key.clone(),
+        close_channel,
+    ));
+    state.set_dns_task(tunnel_key, task);
+    Ok(())
+}
+
+/// Changes options of running tunnel, `changes` are in tunnel spec format (`key=value,...`)
+/// and are applied on top of current options. Changes affect only new connections.
+pub fn update_tunnel_options(
+    local: &SocketSpec,
+    changes: &str,
+    state: State,
+) -> Result<TunnelOptionsUpdate> {
+    let update = state.update_tunnel_options(local, changes)?;
+    if update.current.dns_refresh.is_some() && update.previous.dns_refresh.is_none() {
+        spawn_dns_refresh(local, state)?;
+    }
+    info!(tunnel=%local, previous=%update.previous, current=%update.current, "Tunnel options changed");
+    Ok(update)
 }
 
 async fn create_tunnel(tunnel: Tunnel, state: State) -> Result<TunnelHandler> {
     if state.tunnel_exists(&tunnel.local) {
         return Err(crate::error::Error::TunnelExists);
     }
-    let listener = TcpListener::bind(tunnel.local.as_tuple()).await?;
+    let tls = match tunnel.options {
+        Some(ref options) if options.local_tls => {
+            let (cert, key) = options
+                .local_cert
+                .as_ref()
+                .zip(options.local_key.as_ref())
+                .ok_or_else(|| {
+                    crate::error::Error::TLSConfigError(
+                        "local TLS requires both certificate and key".into(),
+                    )
+                })?;
+            let config = create_server_config(cert, key)?;
+            Some((
+                TlsAcceptor::from(Arc::new(config)),
+                Duration::from_secs_f32(options.options.connect_timeout),
+            ))
+        }
+        _ => None,
+    };
+    let listener = Listener::bind(&tunnel.local).await?;
     let (sender, receiver) = watch::channel(false);
     let tunnel_key = tunnel.local.clone();
     state.add_tunnel(tunnel, sender)?;
+    let drain_channel = state.drain_receiver(&tunnel_key)?;
     Ok(TunnelHandler {
         state,
         tunnel_key,
         listener,
         close_channel: receiver,
+        drain_channel,
+        tls,
     })
 }
 
@@ -221,12 +582,21 @@ async fn run_tunnel(mut handler: TunnelHandler) {
         socket = handler.listener.accept() => {
             match socket {
             Ok((socket, client_addr)) => {
+                if handler.state.check_access(&tunnel_key, &client_addr).is_err() {
+                    info!(client=%client_addr, "Connection denied by access list");
+                    continue;
+                }
+                if let Err(e) = handler.state.check_accept_rate(&tunnel_key) {
+                    debug!(client=%client_addr, error=%e, "Connection dropped");
+                    continue;
+                }
                 tokio::spawn(process_socket(
                     socket,
                     client_addr,
                     tunnel_key.clone(),
                     handler.state.clone(),
                     finish_receiver,
+                    handler.tls.clone(),
                 ).map_err(move |e| error!(error=%e, "Error in remote connection")));
             }
             Err(e) => error!(error=%e, "Cannot accept connection"),
@@ -238,6 +608,11 @@ async fn run_tunnel(mut handler: TunnelHandler) {
             debug!("Finished tunnel");
             break
          }
+
+         _ = handler.drain_channel.changed() => {
+            debug!("Tunnel stopped accepting connections");
+            break
+         }
         }
     }
 }
@@ -31,8 +31,11 @@ async fn main() -> plexy::error::Result<()> {
             connect_timeout: args.remote_timeout,
             errors_till_dead: args.remote_errors,
             dead_retry: args.remote_dead_check_interval,
+            dead_retry_max: args.remote_dead_check_max_interval,
+            dead_retry_jitter: args.remote_dead_check_jitter.clamp(0.0, 1.0),
             tls: false,
         },
+        ..Default::default()
     });
 
     let tunnels = match args.take_tunnels() {
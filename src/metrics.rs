@@ -2,8 +2,7 @@ use opentelemetry::metrics::{Meter, MeterProvider};
 
 pub fn init_meter() -> Meter {
     let meter_provider = opentelemetry::global::meter_provider();
-    let meter = meter_provider.meter("plexy");
-    meter
+    meter_provider.meter("plexy")
 }
 
 use hyper::{
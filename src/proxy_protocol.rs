@@ -0,0 +1,299 @@
+//! HAProxy PROXY protocol, see https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::{Error, Result};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION_PROXY: u8 = 0x21;
+const V2_VERSION_LOCAL: u8 = 0x20;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_UDP4: u8 = 0x12;
+const V2_UDP6: u8 = 0x22;
+const V1_MAX_LENGTH: usize = 107;
+/// Shortest v1 header is `PROXY UNKNOWN\r\n`, v2 header is longer,
+/// so we can always read this much without touching payload
+const MIN_HEADER_LENGTH: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "v1" | "1" => Ok(ProxyProtocolVersion::V1),
+            "v2" | "2" => Ok(ProxyProtocolVersion::V2),
+            _ => Err(Error::ProxyProtocolError(format!(
+                "Invalid protocol version {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Display for ProxyProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyProtocolVersion::V1 => write!(f, "v1"),
+            ProxyProtocolVersion::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), a.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Creates PROXY protocol header announcing connection from `source` to `destination`
+pub fn encode_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    let source = unmap(source);
+    let destination = unmap(destination);
+    let both_v4 = source.is_ipv4() && destination.is_ipv4();
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let header = if both_v4 {
+                format!(
+                    "PROXY TCP4 {} {} {} {}\r\n",
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+            } else {
+                format!(
+                    "PROXY TCP6 {} {} {} {}\r\n",
+                    to_v6(source.ip()),
+                    to_v6(destination.ip()),
+                    source.port(),
+                    destination.port()
+                )
+            };
+            header.into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = Vec::with_capacity(52);
+            header.extend_from_slice(V2_SIGNATURE);
+            header.push(V2_VERSION_PROXY);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    header.push(V2_TCP4);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    header.push(V2_TCP6);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&to_v6(src).octets());
+                    header.extend_from_slice(&to_v6(dst).octets());
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::ProxyProtocolError(msg.into())
+}
+
+/// Reads and decodes PROXY protocol header (v1 or v2) from the beginning of stream,
+/// reads exactly header bytes, so stream payload is not touched.
+/// Returns source and destination addresses of original connection,
+/// or None if header does not contain them (LOCAL command, UNKNOWN or unsupported address family)
+pub async fn read_header<R>(reader: &mut R) -> Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; MIN_HEADER_LENGTH];
+    reader.read_exact(&mut buf).await?;
+    if buf.starts_with(&V2_SIGNATURE[..]) {
+        let mut rest = [0u8; 1];
+        reader.read_exact(&mut rest).await?;
+        buf.extend_from_slice(&rest);
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        let mut addresses = vec![0u8; len];
+        reader.read_exact(&mut addresses).await?;
+        decode_v2(buf[12], buf[13], &addresses)
+    } else if buf.starts_with(b"PROXY ") {
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LENGTH {
+                return Err(invalid("v1 header too long"));
+            }
+            let mut next = [0u8; 1];
+            reader.read_exact(&mut next).await?;
+            buf.push(next[0]);
+        }
+        decode_v1(&buf[..buf.len() - 2])
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn decode_v1(line: &[u8]) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let mut next = || parts.next().ok_or_else(|| invalid("v1 header incomplete"));
+            let src_ip: IpAddr = next()?.parse()?;
+            let dst_ip: IpAddr = next()?.parse()?;
+            let src_port: u16 = next()?.parse().map_err(|_| invalid("invalid port"))?;
+            let dst_port: u16 = next()?.parse().map_err(|_| invalid("invalid port"))?;
+            Ok(Some((
+                SocketAddr::new(src_ip, src_port),
+                SocketAddr::new(dst_ip, dst_port),
+            )))
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(invalid("v1 header has invalid protocol")),
+    }
+}
+
+fn decode_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<(SocketAddr, SocketAddr)>> {
+    match version_command {
+        V2_VERSION_LOCAL => return Ok(None),
+        V2_VERSION_PROXY => (),
+        _ => return Err(invalid("v2 header has invalid version or command")),
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    match family {
+        V2_TCP4 | V2_UDP4 if addresses.len() >= 12 => {
+            let src: [u8; 4] = addresses[0..4].try_into().unwrap();
+            let dst: [u8; 4] = addresses[4..8].try_into().unwrap();
+            Ok(Some((
+                SocketAddr::new(Ipv4Addr::from(src).into(), port(8)),
+                SocketAddr::new(Ipv4Addr::from(dst).into(), port(10)),
+            )))
+        }
+        V2_TCP6 | V2_UDP6 if addresses.len() >= 36 => {
+            let src: [u8; 16] = addresses[0..16].try_into().unwrap();
+            let dst: [u8; 16] = addresses[16..32].try_into().unwrap();
+            Ok(Some((
+                SocketAddr::new(Ipv6Addr::from(src).into(), port(32)),
+                SocketAddr::new(Ipv6Addr::from(dst).into(), port(34)),
+            )))
+        }
+        V2_TCP4 | V2_UDP4 | V2_TCP6 | V2_UDP6 => Err(invalid("v2 header addresses too short")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let src: SocketAddr = "192.168.1.10:50000".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let mut data = encode_header(version, src, dst);
+            data.extend_from_slice(b"payload");
+            let mut reader = &data[..];
+            let (s, d) = read_header(&mut reader).await.unwrap().unwrap();
+            assert_eq!(unmap(s), src);
+            assert_eq!(d, dst);
+            assert_eq!(b"payload", reader);
+        }
+
+        let mut reader = &b"PROXY UNKNOWN\r\npayload"[..];
+        assert!(read_header(&mut reader).await.unwrap().is_none());
+        assert_eq!(b"payload", reader);
+
+        let mut reader = &b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"[..];
+        assert!(read_header(&mut reader).await.is_err());
+
+        let mut reader = &b"PROXY TCP4 192.168.1.10 10.0.0.1 50000\r\n"[..];
+        assert!(read_header(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn test_v1_header() {
+        let h = encode_header(
+            ProxyProtocolVersion::V1,
+            "192.168.1.10:50000".parse().unwrap(),
+            "10.0.0.1:443".parse().unwrap(),
+        );
+        assert_eq!(b"PROXY TCP4 192.168.1.10 10.0.0.1 50000 443\r\n", &h[..]);
+
+        let h = encode_header(
+            ProxyProtocolVersion::V1,
+            "[2001:db8::1]:50000".parse().unwrap(),
+            "10.0.0.1:443".parse().unwrap(),
+        );
+        assert_eq!(
+            b"PROXY TCP6 2001:db8::1 ::ffff:10.0.0.1 50000 443\r\n",
+            &h[..]
+        );
+
+        let h = encode_header(
+            ProxyProtocolVersion::V1,
+            "[::ffff:192.168.1.10]:50000".parse().unwrap(),
+            "[::ffff:10.0.0.1]:443".parse().unwrap(),
+        );
+        assert_eq!(b"PROXY TCP4 192.168.1.10 10.0.0.1 50000 443\r\n", &h[..]);
+    }
+
+    #[test]
+    fn test_v2_header() {
+        let h = encode_header(
+            ProxyProtocolVersion::V2,
+            "192.168.1.10:50000".parse().unwrap(),
+            "10.0.0.1:443".parse().unwrap(),
+        );
+        assert_eq!(28, h.len());
+        assert_eq!(V2_SIGNATURE, &h[..12]);
+        assert_eq!(
+            &[0x21, 0x11, 0, 12, 192, 168, 1, 10, 10, 0, 0, 1],
+            &h[12..24]
+        );
+        assert_eq!(50000u16.to_be_bytes(), h[24..26]);
+        assert_eq!(443u16.to_be_bytes(), h[26..28]);
+
+        let h = encode_header(
+            ProxyProtocolVersion::V2,
+            "[2001:db8::1]:50000".parse().unwrap(),
+            "[2001:db8::2]:443".parse().unwrap(),
+        );
+        assert_eq!(52, h.len());
+        assert_eq!(&[0x21, 0x21, 0, 36], &h[12..16]);
+    }
+}
@@ -1,19 +1,26 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use jsonrpsee::{proc_macros::rpc, server::ServerBuilder, types::ErrorObject};
 use serde::Serialize;
 
 use crate::{
+    drain_tunnel,
     error::Error,
     start_tunnel,
     state::{
         info::TunnelInfo,
-        stats::{RemoteStats, TunnelStats},
+        stats::{DeadRemoteStats, RemoteAdminState, RemoteStats, RemotesChange, TunnelStats},
     },
     stop_tunnel,
-    tunnel::{SocketSpec, TunnelOptions},
-    State, Tunnel,
+    tunnel::{
+        AccessLists, Cidr, RateLimits, RemoteSpec, SocketSpec, TunnelOptions, TunnelOptionsUpdate,
+    },
+    update_tunnel_options, State, Tunnel, DEFAULT_DRAIN_TIMEOUT,
 };
 
 type RPCResult<T> = Result<T, Error>;
@@ -29,6 +36,7 @@ pub struct RPCTunnelInfo {
     stats: TunnelStats,
     num_remotes: usize,
     num_dead_remotes: usize,
+    draining: bool,
     options: TunnelOptions,
 }
 
@@ -38,6 +46,7 @@ impl From<&TunnelInfo> for RPCTunnelInfo {
             stats: ti.stats.clone(),
             num_remotes: ti.remotes.len(),
             num_dead_remotes: ti.dead_remotes.len(),
+            draining: ti.draining,
             options: ti.options.clone(),
         }
     }
@@ -53,6 +62,8 @@ trait Interface {
     fn tunnel_info(&self, tunnel_socket: String) -> RPCResult<RPCTunnelInfo>;
     #[method(name = "remotes")]
     fn remotes(&self, tunnel_socket: String) -> RPCResult<HashMap<String, RemoteStats>>;
+    #[method(name = "deadRemotes")]
+    fn dead_remotes(&self, tunnel_socket: String) -> RPCResult<HashMap<String, DeadRemoteStats>>;
     #[method(name = "openTunnel")]
     async fn open_tunnel(
         &self,
@@ -62,20 +73,77 @@ trait Interface {
     ) -> RPCResult<()>;
     #[method(name = "closeTunnel")]
     fn close_tunnel(&self, tunnel_socket: String) -> RPCResult<()>;
+    #[method(name = "drainTunnel")]
+    async fn drain_tunnel(&self, tunnel_socket: String, timeout: Option<f32>) -> RPCResult<usize>;
     #[method(name = "addRemote")]
-    fn add_remote(&self, tunnel: String, remote: String) -> RPCResult<()>;
+    fn add_remote(&self, tunnel: String, remote: String, tier: Option<u8>) -> RPCResult<()>;
     #[method(name = "removeRemote")]
     fn remove_remote(&self, tunnel: String, remote: String) -> RPCResult<RemoteStats>;
+    #[method(name = "setRemotes")]
+    fn set_remotes(&self, tunnel: String, remotes: Vec<String>) -> RPCResult<RemotesChange>;
+    #[method(name = "enableRemote")]
+    fn enable_remote(&self, tunnel: String, remote: String) -> RPCResult<()>;
+    #[method(name = "disableRemote")]
+    fn disable_remote(&self, tunnel: String, remote: String) -> RPCResult<()>;
+    #[method(name = "drainRemote")]
+    fn drain_remote(&self, tunnel: String, remote: String) -> RPCResult<()>;
+    #[method(name = "setRemoteMetadata")]
+    fn set_remote_metadata(
+        &self,
+        tunnel: String,
+        remote: String,
+        metadata: BTreeMap<String, String>,
+    ) -> RPCResult<()>;
+    #[method(name = "customStrategies")]
+    fn custom_strategies(&self) -> Vec<String>;
+    #[method(name = "setRemoteWeight")]
+    fn set_remote_weight(&self, tunnel: String, remote: String, weight: u32) -> RPCResult<()>;
+    #[method(name = "routes")]
+    fn routes(&self, tunnel: String) -> RPCResult<HashMap<String, Vec<String>>>;
+    #[method(name = "addRoute")]
+    fn add_route(&self, tunnel: String, server_name: String, remote: String) -> RPCResult<()>;
+    #[method(name = "removeRoute")]
+    fn remove_route(&self, tunnel: String, server_name: String, remote: String) -> RPCResult<()>;
+    #[method(name = "setRateLimits")]
+    fn set_rate_limits(&self, tunnel: String, limits: RateLimits) -> RPCResult<RateLimits>;
+    #[method(name = "updateTunnelOptions")]
+    fn update_tunnel_options(
+        &self,
+        tunnel: String,
+        options: String,
+    ) -> RPCResult<TunnelOptionsUpdate>;
+    #[method(name = "accessLists")]
+    fn access_lists(&self, tunnel: String) -> RPCResult<AccessLists>;
+    #[method(name = "setAccessLists")]
+    fn set_access_lists(
+        &self,
+        tunnel: String,
+        allow: Option<Vec<Cidr>>,
+        deny: Option<Vec<Cidr>>,
+    ) -> RPCResult<AccessLists>;
 }
 
 pub struct ControlRpc {
     state: State,
 }
 
+impl ControlRpc {
+    fn set_remote_state(
+        &self,
+        tunnel: String,
+        remote: String,
+        admin_state: RemoteAdminState,
+    ) -> RPCResult<()> {
+        let local = tunnel.parse()?;
+        let remote = remote.parse()?;
+        self.state.set_remote_state(&local, &remote, admin_state)
+    }
+}
+
 #[async_trait]
 impl InterfaceServer for ControlRpc {
     fn number_of_tunnels(&self) -> usize {
-        return self.state.number_of_tunnels();
+        self.state.number_of_tunnels()
     }
 
     fn tunnel_info(&self, tunnel_socket: String) -> RPCResult<RPCTunnelInfo> {
@@ -93,6 +161,16 @@ impl InterfaceServer for ControlRpc {
             .collect()
     }
 
+    fn dead_remotes(&self, tunnel_socket: String) -> RPCResult<HashMap<String, DeadRemoteStats>> {
+        let addr: SocketSpec = tunnel_socket.parse()?;
+        Ok(self
+            .state
+            .dead_remotes(&addr)?
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect())
+    }
+
     async fn open_tunnel(
         &self,
         tunnel_socket: String,
@@ -118,6 +196,12 @@ impl InterfaceServer for ControlRpc {
         stop_tunnel(&local, self.state.clone())
     }
 
+    async fn drain_tunnel(&self, tunnel_socket: String, timeout: Option<f32>) -> RPCResult<usize> {
+        let local = tunnel_socket.parse()?;
+        let timeout = timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT).max(0.0);
+        drain_tunnel(&local, Duration::from_secs_f32(timeout), self.state.clone()).await
+    }
+
     fn list_tunnels(&self) -> Vec<String> {
         self.state
             .list_tunnels()
@@ -126,17 +210,119 @@ impl InterfaceServer for ControlRpc {
             .collect()
     }
 
-    fn add_remote(&self, tunnel: String, remote: String) -> RPCResult<()> {
+    fn add_remote(&self, tunnel: String, remote: String, tier: Option<u8>) -> RPCResult<()> {
         let local = tunnel.parse()?;
-        let remote = remote.parse()?;
+        let mut remote: RemoteSpec = remote.parse()?;
+        if let Some(tier) = tier {
+            remote.tier = tier;
+        }
         self.state.add_remote_to_tunnel(&local, remote)
     }
     fn remove_remote(&self, tunnel: String, remote: String) -> RPCResult<RemoteStats> {
         let local = tunnel.parse()?;
         let remote = remote.parse()?;
+        self.state.remove_remote_from_tunnel(&local, &remote)
+    }
+
+    fn set_remotes(&self, tunnel: String, remotes: Vec<String>) -> RPCResult<RemotesChange> {
+        let local = tunnel.parse()?;
+        let remotes = remotes
+            .into_iter()
+            .map(|s| s.parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        self.state.replace_remotes(&local, remotes)
+    }
+
+    fn enable_remote(&self, tunnel: String, remote: String) -> RPCResult<()> {
+        self.set_remote_state(tunnel, remote, RemoteAdminState::Active)
+    }
+
+    fn disable_remote(&self, tunnel: String, remote: String) -> RPCResult<()> {
+        self.set_remote_state(tunnel, remote, RemoteAdminState::Disabled)
+    }
+
+    fn drain_remote(&self, tunnel: String, remote: String) -> RPCResult<()> {
+        self.set_remote_state(tunnel, remote, RemoteAdminState::Draining)
+    }
+
+    fn set_remote_metadata(
+        &self,
+        tunnel: String,
+        remote: String,
+        metadata: BTreeMap<String, String>,
+    ) -> RPCResult<()> {
+        let local = tunnel.parse()?;
+        let remote = remote.parse()?;
+        self.state.set_remote_metadata(&local, &remote, metadata)
+    }
+
+    fn custom_strategies(&self) -> Vec<String> {
+        self.state.strategy_names()
+    }
+
+    fn set_remote_weight(&self, tunnel: String, remote: String, weight: u32) -> RPCResult<()> {
+        let local = tunnel.parse()?;
+        let remote = remote.parse()?;
+        self.state.set_remote_weight(&local, &remote, weight)
+    }
+
+    fn routes(&self, tunnel: String) -> RPCResult<HashMap<String, Vec<String>>> {
+        let local = tunnel.parse()?;
+        Ok(self
+            .state
+            .routes(&local)?
+            .into_iter()
+            .map(|(name, remotes)| (name, remotes.iter().map(|r| r.to_string()).collect()))
+            .collect())
+    }
+
+    fn add_route(&self, tunnel: String, server_name: String, remote: String) -> RPCResult<()> {
+        let local = tunnel.parse()?;
+        let remote = remote.parse()?;
+        self.state.add_route(&local, &server_name, remote)
+    }
+
+    fn remove_route(&self, tunnel: String, server_name: String, remote: String) -> RPCResult<()> {
+        let local = tunnel.parse()?;
+        let remote = remote.parse()?;
+        self.state.remove_route(&local, &server_name, &remote)
+    }
+
+    fn set_rate_limits(&self, tunnel: String, limits: RateLimits) -> RPCResult<RateLimits> {
+        let local = tunnel.parse()?;
+        self.state.set_rate_limits(&local, &limits)
+    }
+
+    fn update_tunnel_options(
+        &self,
+        tunnel: String,
+        options: String,
+    ) -> RPCResult<TunnelOptionsUpdate> {
+        let local = tunnel.parse()?;
+        update_tunnel_options(&local, &options, self.state.clone())
+    }
+
+    fn access_lists(&self, tunnel: String) -> RPCResult<AccessLists> {
+        let local = tunnel.parse()?;
+        self.state.tunnel_options(&local).map(|o| o.access_lists())
+    }
+
+    fn set_access_lists(
+        &self,
+        tunnel: String,
+        allow: Option<Vec<Cidr>>,
+        deny: Option<Vec<Cidr>>,
+    ) -> RPCResult<AccessLists> {
+        let local = tunnel.parse()?;
         self.state
-            .remove_remote_from_tunnel(&local, &remote)
-            .map(|ri| ri.stats)
+            .update_access_lists(&local, |allow_list, deny_list| {
+                if let Some(allow) = allow {
+                    *allow_list = allow;
+                }
+                if let Some(deny) = deny {
+                    *deny_list = deny;
+                }
+            })
     }
 }
 
@@ -144,5 +330,62 @@ pub async fn run_rpc_server(addr: SocketAddr, state: State) -> Result<(), Error>
     let server = ServerBuilder::default().build(addr).await?;
     let rpc = ControlRpc { state };
     let handle = server.start(rpc.into_rpc())?;
-    Ok(handle.stopped().await)
+    handle.stopped().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Args, state::health::HealthCheck, tunnel::TunnelRemoteOptions};
+
+    #[tokio::test]
+    async fn test_open_tunnel_invalid_options() {
+        #[cfg(feature = "metrics")]
+        let state = State::new(Args::default(), crate::metrics::init_meter()).unwrap();
+        #[cfg(not(feature = "metrics"))]
+        let state = State::new(Args::default()).unwrap();
+        let rpc = ControlRpc {
+            state: state.clone(),
+        };
+        let invalid = [
+            TunnelOptions {
+                idle_timeout: Some(-1.0),
+                ..Default::default()
+            },
+            TunnelOptions {
+                session_timeout: 0.0,
+                ..Default::default()
+            },
+            TunnelOptions {
+                options: TunnelRemoteOptions {
+                    connect_timeout: f32::NAN,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            TunnelOptions {
+                accept_proxy_protocol: true,
+                ..Default::default()
+            },
+            TunnelOptions {
+                health_check: Some(HealthCheck {
+                    interval: 0.0,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ];
+        for options in invalid {
+            let res = rpc
+                .open_tunnel(
+                    "127.0.0.1:0".into(),
+                    vec!["127.0.0.1:3001".into()],
+                    Some(options),
+                )
+                .await;
+            assert!(matches!(res, Err(Error::InvalidTunnel(_))));
+        }
+        assert_eq!(0, state.number_of_tunnels());
+    }
 }
@@ -0,0 +1,216 @@
+//! Minimal TLS ClientHello inspection - we only need server name (SNI)
+//! to choose remote, TLS session itself is not terminated, so all bytes read
+//! from client must be later forwarded to remote.
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::Instant,
+};
+
+const RECORD_HEADER_LEN: usize = 5;
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_TYPE_HOSTNAME: u8 = 0;
+
+/// Reads first TLS record from client and extracts server name from it.
+/// Returns all bytes read (to be forwarded to remote) and server name, if found.
+/// If client does not send TLS handshake or does not send it in given time,
+/// it returns what was read so far and no server name.
+pub(crate) async fn read_client_hello<R>(
+    reader: &mut R,
+    wait_for: Duration,
+) -> std::io::Result<(Vec<u8>, Option<String>)>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let deadline = Instant::now() + wait_for;
+    let mut buf = Vec::with_capacity(1024);
+    let mut needed = RECORD_HEADER_LEN;
+    while buf.len() < needed {
+        let read = tokio::time::timeout_at(deadline, read_more(reader, &mut buf, needed)).await;
+        match read {
+            Ok(Ok(0)) | Err(_) => return Ok((buf, None)),
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => return Err(e),
+        }
+        if buf.len() >= RECORD_HEADER_LEN && needed == RECORD_HEADER_LEN {
+            if buf[0] != CONTENT_TYPE_HANDSHAKE {
+                return Ok((buf, None));
+            }
+            let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            needed = RECORD_HEADER_LEN + record_len.min(MAX_RECORD_LEN);
+        }
+    }
+    let server_name = parse_server_name(&buf);
+    Ok((buf, server_name))
+}
+
+async fn read_more<R>(reader: &mut R, buf: &mut Vec<u8>, needed: usize) -> std::io::Result<usize>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut chunk = vec![0; needed - buf.len()];
+    let n = reader.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| ((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+/// Parses server name from TLS record containing ClientHello.
+/// Tolerates ClientHello truncated at record boundary, if server name extension is already there.
+pub(crate) fn parse_server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader { data: record };
+    if r.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    let _version = r.u16()?;
+    let record_len = r.u16()? as usize;
+    let mut r = Reader {
+        data: &r.data[..record_len.min(r.data.len())],
+    };
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let _hello_len = r.u24()?;
+    let _client_version = r.u16()?;
+    let _random = r.take(32)?;
+    let _session_id = r.vec_u8()?;
+    let _cipher_suites = r.vec_u16()?;
+    let _compression = r.vec_u8()?;
+    let extensions_len = r.u16()? as usize;
+    let mut ext = Reader {
+        data: &r.data[..extensions_len.min(r.data.len())],
+    };
+    loop {
+        let ext_type = ext.u16()?;
+        let ext_data = ext.vec_u16()?;
+        if ext_type == EXTENSION_SERVER_NAME {
+            let mut sn = Reader { data: ext_data };
+            let mut names = Reader {
+                data: sn.vec_u16()?,
+            };
+            while let Some(name_type) = names.u8() {
+                let name = names.vec_u16()?;
+                if name_type == SERVER_NAME_TYPE_HOSTNAME {
+                    return std::str::from_utf8(name).ok().map(normalize_server_name);
+                }
+            }
+            return None;
+        }
+    }
+}
+
+/// Server names are compared case insensitive and without trailing dot
+pub(crate) fn normalize_server_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        // some other extension before SNI - supported groups
+        extensions.extend_from_slice(&[0x00, 0x0a, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1d]);
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let list_len = name.len() + 3;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&((list_len + 2) as u16).to_be_bytes());
+            extensions.extend_from_slice(&(list_len as u16).to_be_bytes());
+            extensions.push(0);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        hello.push(0); // session id
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+        hello.extend_from_slice(&[0x01, 0x00]); // compression
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_server_name() {
+        let record = client_hello(Some("Example.COM."));
+        assert_eq!(Some("example.com".into()), parse_server_name(&record));
+
+        let record = client_hello(None);
+        assert_eq!(None, parse_server_name(&record));
+
+        assert_eq!(None, parse_server_name(b"GET / HTTP/1.1\r\n\r\n"));
+
+        let record = client_hello(Some("example.com"));
+        assert_eq!(None, parse_server_name(&record[..record.len() - 4]));
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let record = client_hello(Some("backend.example.com"));
+        let mut input = &record[..];
+        let (data, name) = read_client_hello(&mut input, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(record, data);
+        assert_eq!(Some("backend.example.com".into()), name);
+
+        let mut input = &b"HELLO"[..];
+        let (data, name) = read_client_hello(&mut input, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(b"HELLO", &data[..]);
+        assert_eq!(None, name);
+    }
+}
@@ -3,33 +3,66 @@ use opentelemetry::metrics::{Meter, UpDownCounter};
 
 use parking_lot::RwLock;
 use rustls::ClientConfig;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{sync::watch, task::JoinHandle, time};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+    time,
+};
 use tracing::{debug, instrument};
 
 use crate::{
+    aio::StreamTimeout,
     config::Args,
     connect_remote,
     error::{Error, Result},
+    sni::normalize_server_name,
     state::tls::create_client_config,
-    tunnel::{SocketSpec, TunnelOptions, TunnelRemoteOptions},
-    Tunnel,
+    tunnel::{
+        AccessLists, Cidr, RateLimits, RemoteSpec, SocketSpec, TunnelOptions, TunnelOptionsUpdate,
+        TunnelRemoteOptions,
+    },
+    udp, Tunnel,
 };
 
 use self::{
-    info::{DeadRemote, RemoteInfo, TunnelInfo},
-    stats::{RemoteStats, TunnelStats},
+    health::HealthCheck,
+    info::{DeadRemote, RemoteInfo, RoutesMap, TunnelInfo},
+    rate::TunnelBandwidth,
+    stats::{DeadRemoteStats, RemoteAdminState, RemoteStats, RemotesChange, TunnelStats},
+    strategy::{ConnectionContext, LBStrategy, StrategyRegistry, TunnelLBStrategy},
 };
 
+pub(crate) mod dns;
+pub mod health;
 pub mod info;
+pub mod outlier;
+pub(crate) mod rate;
 pub mod stats;
 pub mod strategy;
-mod tls;
+pub(crate) mod tls;
+
+fn check_remote_kind(local: &SocketSpec, remote: &SocketSpec) -> Result<()> {
+    if local.compatible_remote(remote) {
+        Ok(())
+    } else {
+        Err(Error::InvalidTunnel(format!(
+            "remote {} cannot be used with tunnel {}",
+            remote, local
+        )))
+    }
+}
 
 struct StateInner {
     tunnels: dashmap::DashMap<SocketSpec, TunnelInfo, fxhash::FxBuildHasher>,
     config: RwLock<Args>,
     client_ssl_config: RwLock<Arc<ClientConfig>>,
+    strategies: StrategyRegistry,
     #[cfg(feature = "metrics")]
     meter: Meter,
     #[cfg(feature = "metrics")]
@@ -50,6 +83,7 @@ impl State {
 
                 client_ssl_config: RwLock::new(Arc::new(create_client_config(&args)?)),
                 config: RwLock::new(args),
+                strategies: StrategyRegistry::default(),
                 tunnels_counter: meter
                     .i64_up_down_counter("number_of_tunnels")
                     .with_description("Number of tunnels open")
@@ -67,6 +101,7 @@ impl State {
 
                 client_ssl_config: RwLock::new(Arc::new(create_client_config(&args)?)),
                 config: RwLock::new(args),
+                strategies: StrategyRegistry::default(),
             }),
         })
     }
@@ -80,16 +115,37 @@ impl State {
         self.inner.client_ssl_config.read().clone()
     }
 
+    /// Registers user supplied load balancing strategy, tunnels can then use it as `strategy=<name>`
+    pub fn register_strategy<F>(&self, name: impl Into<String>, factory: F) -> Result<()>
+    where
+        F: Fn() -> Box<dyn LBStrategy + Send + Sync> + Send + Sync + 'static,
+    {
+        self.inner.strategies.register(name, Arc::new(factory))
+    }
+
+    /// Names of registered user supplied strategies
+    pub fn strategy_names(&self) -> Vec<String> {
+        self.inner.strategies.names()
+    }
+
+    pub(crate) fn create_strategy(
+        &self,
+        strategy: &TunnelLBStrategy,
+    ) -> Result<Box<dyn LBStrategy + Send + Sync>> {
+        strategy.create(&self.inner.strategies)
+    }
+
     pub fn select_remote(
         &self,
         tunnel_key: &SocketSpec,
+        ctx: &ConnectionContext,
     ) -> Result<(SocketSpec, TunnelRemoteOptions)> {
         let mut ti = self
             .inner
             .tunnels
             .get_mut(tunnel_key)
             .ok_or(Error::TunnelDoesNotExist)?;
-        let selected = ti.select_remote()?;
+        let selected = ti.select_remote(tunnel_key, ctx)?;
         let remote = ti
             .remotes
             .get_mut(&selected)
@@ -116,12 +172,12 @@ impl State {
         if self.inner.tunnels.contains_key(&tunnel.local) {
             return Err(Error::TunnelExists);
         }
-        let info = TunnelInfo::new(
-            close_channel,
-            tunnel.remote,
-            tunnel.options.unwrap_or_default(),
-            self,
-        );
+        for remote in &tunnel.remote {
+            check_remote_kind(&tunnel.local, &remote.socket)?;
+        }
+        let options = tunnel.options.unwrap_or_default();
+        options.validate()?;
+        let info = TunnelInfo::new(close_channel, tunnel.remote, options, self)?;
         self.inner.tunnels.insert(tunnel.local, info);
         #[cfg(feature = "metrics")]
         {
@@ -145,50 +201,226 @@ impl State {
                 t
             })
             .ok_or(Error::TunnelDoesNotExist)
-            .and_then(|ti| {
+            .inspect(|_| {
                 #[cfg(feature = "metrics")]
                 {
                     self.inner
                         .tunnels_counter
                         .add(&opentelemetry::Context::current(), -1, &[]);
                 }
-                Ok(ti)
             })
     }
 
     pub(crate) fn add_remote_to_tunnel(
         &self,
         tunnel: &SocketSpec,
-        remote: SocketSpec,
+        remote: RemoteSpec,
     ) -> Result<()> {
         let mut ti = self
             .inner
             .tunnels
             .get_mut(tunnel)
             .ok_or_else(|| Error::TunnelDoesNotExist)?;
-        if !ti.remotes.contains_key(&remote) && !ti.dead_remotes.contains_key(&remote) {
-            ti.remotes.insert(remote, RemoteInfo::new(self));
+        check_remote_kind(tunnel, &remote.socket)?;
+        if !ti.remotes.contains_key(&remote.socket)
+            && !ti.dead_remotes.contains_key(&remote.socket)
+            && !ti.dns_names.contains_key(&remote.socket)
+        {
+            let mut info = RemoteInfo::for_spec(self, &remote);
+            info.became_available();
+            ti.remotes.insert(remote.socket, info);
             Ok(())
         } else {
             Err(Error::RemoteExists)
         }
     }
 
+    /// Replaces all remotes of tunnel at once, see [`TunnelInfo::replace_remotes`]
+    pub(crate) fn replace_remotes(
+        &self,
+        tunnel: &SocketSpec,
+        remotes: Vec<RemoteSpec>,
+    ) -> Result<RemotesChange> {
+        if remotes.is_empty() {
+            return Err(Error::InvalidTunnel(
+                "at least one remote is required".into(),
+            ));
+        }
+        for (n, remote) in remotes.iter().enumerate() {
+            check_remote_kind(tunnel, &remote.socket)?;
+            if remotes[..n].iter().any(|r| r.socket == remote.socket) {
+                return Err(Error::InvalidTunnel(format!(
+                    "duplicate remote {}",
+                    remote.socket
+                )));
+            }
+        }
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(tunnel)
+            .ok_or_else(|| Error::TunnelDoesNotExist)?;
+        Ok(ti.replace_remotes(tunnel, remotes, self))
+    }
+
     pub(crate) fn remove_remote_from_tunnel(
         &self,
         tunnel: &SocketSpec,
         remote: &SocketSpec,
-    ) -> Result<RemoteInfo> {
+    ) -> Result<RemoteStats> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(tunnel)
+            .ok_or_else(|| Error::TunnelDoesNotExist)?;
+        // for host name all its addresses are removed, stats are not tracked for the name itself
+        match ti.remove_dns_name(remote) {
+            Some(stats) => Ok(stats),
+            None => ti.remove_remote_gracefully(remote),
+        }
+    }
+
+    /// Sets admin state of tunnel remote
+    pub(crate) fn set_remote_state(
+        &self,
+        tunnel: &SocketSpec,
+        remote: &SocketSpec,
+        admin_state: RemoteAdminState,
+    ) -> Result<()> {
+        self.inner
+            .tunnels
+            .get_mut(tunnel)
+            .ok_or_else(|| Error::TunnelDoesNotExist)?
+            .set_remote_state(remote, admin_state)
+    }
+
+    /// Replaces metadata of tunnel remote
+    pub fn set_remote_metadata(
+        &self,
+        tunnel: &SocketSpec,
+        remote: &SocketSpec,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<()> {
         let mut ti = self
             .inner
             .tunnels
             .get_mut(tunnel)
             .ok_or_else(|| Error::TunnelDoesNotExist)?;
+        ti.remote_info_mut(remote)
+            .ok_or(Error::RemoteDoesNotExist)?
+            .stats
+            .metadata = metadata;
+        Ok(())
+    }
 
-        ti.remotes
-            .remove(remote)
-            .or_else(|| ti.dead_remotes.remove(remote).map(|d| d.remote))
-            .ok_or_else(|| Error::RemoteDoesNotExist)
+    /// Sets load balancing weight of tunnel remote
+    pub(crate) fn set_remote_weight(
+        &self,
+        tunnel: &SocketSpec,
+        remote: &SocketSpec,
+        weight: u32,
+    ) -> Result<()> {
+        if weight == 0 {
+            return Err(Error::InvalidTunnel(
+                "remote weight must be positive".into(),
+            ));
+        }
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(tunnel)
+            .ok_or_else(|| Error::TunnelDoesNotExist)?;
+        ti.remote_info_mut(remote)
+            .ok_or(Error::RemoteDoesNotExist)?
+            .stats
+            .weight = weight;
+        Ok(())
+    }
+
+    /// Routes connections with given SNI server name to remote, remote is added to tunnel, if not already there
+    pub(crate) fn add_route(
+        &self,
+        tunnel: &SocketSpec,
+        server_name: &str,
+        remote: SocketSpec,
+    ) -> Result<()> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(tunnel)
+            .ok_or_else(|| Error::TunnelDoesNotExist)?;
+        check_remote_kind(tunnel, &remote)?;
+        ti.add_route(normalize_server_name(server_name), remote.clone())?;
+        if !ti.remotes.contains_key(&remote) && !ti.dead_remotes.contains_key(&remote) {
+            let mut info = RemoteInfo::new(self);
+            info.became_available();
+            ti.remotes.insert(remote, info);
+        }
+        Ok(())
+    }
+
+    /// Removes remote from route, remote itself stays in tunnel (in default pool if not used in other route)
+    pub(crate) fn remove_route(
+        &self,
+        tunnel: &SocketSpec,
+        server_name: &str,
+        remote: &SocketSpec,
+    ) -> Result<()> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(tunnel)
+            .ok_or_else(|| Error::TunnelDoesNotExist)?;
+        ti.remove_route(&normalize_server_name(server_name), remote)
+    }
+
+    pub fn routes(&self, tunnel: &SocketSpec) -> Result<RoutesMap> {
+        self.inner
+            .tunnels
+            .get(tunnel)
+            .map(|ti| ti.routes.clone())
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
+    /// Switches tunnel to draining, returns notification of finished connections
+    pub(crate) fn start_draining(&self, local: &SocketSpec) -> Result<Arc<Notify>> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        if ti.draining {
+            return Err(Error::TunnelDraining);
+        }
+        ti.draining = true;
+        ti.drain_channel.send_replace(true);
+        Ok(ti.slot_released.clone())
+    }
+
+    pub fn is_draining(&self, local: &SocketSpec) -> bool {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| ti.draining)
+            .unwrap_or(false)
+    }
+
+    /// Receiver signalled, when tunnel should stop accepting new connections
+    pub(crate) fn drain_receiver(&self, local: &SocketSpec) -> Result<watch::Receiver<bool>> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| ti.drain_channel.subscribe())
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
+    /// Number of client connections (or UDP sessions) in tunnel, including those still connecting to remote
+    pub fn open_connections(&self, local: &SocketSpec) -> Result<usize> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| ti.stats.streams_open)
+            .ok_or(Error::TunnelDoesNotExist)
     }
 
     pub fn tunnel_exists(&self, tunnel: &SocketSpec) -> bool {
@@ -207,6 +439,239 @@ impl State {
             .collect()
     }
 
+    /// Registers new client, if tunnel connection limits allow it
+    pub fn try_client_connected(&self, local: &SocketSpec, client_addr: &SocketAddr) -> Result<()> {
+        let mut rec = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        rec.check_client_limits(client_addr)?;
+        rec.client_connected(local, client_addr);
+        Ok(())
+    }
+
+    /// Checks client address against tunnel access lists, unix socket clients are not checked
+    pub fn check_access(&self, local: &SocketSpec, client_addr: &SocketAddr) -> Result<()> {
+        if local.unix_path().is_some() {
+            return Ok(());
+        }
+        self.inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?
+            .check_access(local, client_addr)
+    }
+
+    /// Changes access lists of running tunnel, returns lists now in effect
+    pub fn update_access_lists<F>(&self, local: &SocketSpec, f: F) -> Result<AccessLists>
+    where
+        F: FnOnce(&mut Vec<Cidr>, &mut Vec<Cidr>),
+    {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        let options = &mut ti.options;
+        f(&mut options.allow, &mut options.deny);
+        Ok(options.access_lists())
+    }
+
+    /// Checks accept rate and client connection rate for new connection
+    pub fn check_rate(&self, local: &SocketSpec, client_addr: &SocketAddr) -> Result<()> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        ti.check_client_rate(local, client_addr)?;
+        ti.check_accept_rate(local)
+    }
+
+    /// Checks only tunnel accept rate, client rate is checked later, when real client address is known
+    pub fn check_accept_rate(&self, local: &SocketSpec) -> Result<()> {
+        self.inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?
+            .check_accept_rate(local)
+    }
+
+    /// Checks only connection rate of client
+    pub fn check_client_rate(&self, local: &SocketSpec, client_addr: &SocketAddr) -> Result<()> {
+        self.inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?
+            .check_client_rate(local, client_addr)
+    }
+
+    /// Changes rate limits of running tunnel, returns limits now in effect
+    pub fn set_rate_limits(&self, local: &SocketSpec, limits: &RateLimits) -> Result<RateLimits> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        ti.set_rate_limits(limits);
+        Ok(ti.options.rate_limits())
+    }
+
+    /// Applies changes (in tunnel spec options format) to options of running tunnel,
+    /// returns previous and current options.
+    /// Listener level options (local TLS) cannot be changed on running tunnel.
+    pub fn update_tunnel_options(
+        &self,
+        local: &SocketSpec,
+        changes: &str,
+    ) -> Result<TunnelOptionsUpdate> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        let options = ti.options.updated(changes)?;
+        options.validate()?;
+        if local.is_udp() {
+            udp::check_options(&options)?;
+        }
+        if options.local_tls != ti.options.local_tls
+            || options.local_cert != ti.options.local_cert
+            || options.local_key != ti.options.local_key
+        {
+            return Err(Error::InvalidTunnel(
+                "local TLS cannot be changed on running tunnel".into(),
+            ));
+        }
+        let previous = ti.set_options(options, self)?;
+        if previous.health_check.is_some() && ti.options.health_check.is_none() {
+            // dead remotes were left for health checks to revive, now they have to be checked as usual
+            let options = ti.options.options.clone();
+            let retry_interval = Duration::from_secs_f32(options.dead_retry);
+            for (remote, dead) in ti.dead_remotes.iter_mut() {
+                if dead.join_handle.is_none() {
+                    let after = options.with_jitter(retry_interval);
+                    dead.retry_interval = retry_interval;
+                    dead.next_check = SystemTime::now() + after;
+                    dead.join_handle = Some(self.check_dead(
+                        local.clone(),
+                        remote.clone(),
+                        after,
+                        options.clone(),
+                    ));
+                }
+            }
+        }
+        let update = TunnelOptionsUpdate {
+            previous,
+            current: ti.options.clone(),
+        };
+        drop(ti);
+        if update.current.health_check.is_some() && update.previous.health_check.is_none() {
+            self.spawn_health_checks(local)?;
+        }
+        Ok(update)
+    }
+
+    /// Starts task running health checks of tunnel
+    pub(crate) fn spawn_health_checks(&self, local: &SocketSpec) -> Result<()> {
+        let close_channel = self.close_receiver(local)?;
+        let task = tokio::spawn(health::run_health_checks(
+            self.clone(),
+            local.clone(),
+            close_channel,
+        ));
+        self.set_health_task(local, task);
+        Ok(())
+    }
+
+    /// Registers task running health checks of tunnel, previous task is aborted
+    pub(crate) fn set_health_task(&self, local: &SocketSpec, task: JoinHandle<()>) {
+        match self.inner.tunnels.get_mut(local) {
+            Some(mut ti) => {
+                if let Some(previous) = ti.health_task.replace(task) {
+                    previous.abort()
+                }
+            }
+            None => task.abort(),
+        }
+    }
+
+    /// Registers task refreshing addresses of host name remotes, previous task is aborted
+    pub(crate) fn set_dns_task(&self, local: &SocketSpec, task: JoinHandle<()>) {
+        match self.inner.tunnels.get_mut(local) {
+            Some(mut ti) => {
+                if let Some(previous) = ti.dns_task.replace(task) {
+                    previous.abort()
+                }
+            }
+            None => task.abort(),
+        }
+    }
+
+    /// Host name remotes to be resolved, newly added host name remotes start to be tracked
+    pub(crate) fn track_dns_names(&self, local: &SocketSpec) -> Result<Vec<SocketSpec>> {
+        self.inner
+            .tunnels
+            .get_mut(local)
+            .map(|mut ti| ti.track_dns_names())
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
+    /// Sets resolved addresses of host name remote
+    pub(crate) fn update_resolved(
+        &self,
+        local: &SocketSpec,
+        name: &SocketSpec,
+        addresses: &[IpAddr],
+    ) -> Result<RemotesChange> {
+        let mut ti = self
+            .inner
+            .tunnels
+            .get_mut(local)
+            .ok_or(Error::TunnelDoesNotExist)?;
+        Ok(ti.update_resolved(local, name, addresses, self))
+    }
+
+    /// Host name remotes with their current remotes (resolved addresses)
+    pub fn resolved_names(&self, local: &SocketSpec) -> Result<Vec<(SocketSpec, Vec<SocketSpec>)>> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| {
+                ti.dns_names
+                    .keys()
+                    .map(|name| (name.clone(), ti.dns_members(name)))
+                    .collect()
+            })
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
+    /// Bandwidth limits shared by tunnel connections
+    pub(crate) fn bandwidth(&self, local: &SocketSpec) -> Arc<TunnelBandwidth> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| ti.bandwidth.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn client_rejected(&self, local: &SocketSpec) {
+        if let Some(mut rec) = self.inner.tunnels.get_mut(local) {
+            rec.client_rejected(local);
+        }
+    }
+
+    /// Notification, that some connection slot in tunnel was released
+    pub(crate) fn slot_released(&self, local: &SocketSpec) -> Result<Arc<Notify>> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| ti.slot_released.clone())
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
     pub fn client_connected(&self, local: &SocketSpec, client_addr: &SocketAddr) {
         if let Some(mut rec) = self.inner.tunnels.get_mut(local) {
             rec.client_connected(local, client_addr);
@@ -218,10 +683,12 @@ impl State {
         local: &SocketSpec,
         remote: &SocketSpec,
         client_addr: &SocketAddr,
+        connect_time: Duration,
     ) {
         if let Some(mut rec) = self.inner.tunnels.get_mut(local) {
-            if let Some(rec) = rec.remotes.get_mut(remote) {
-                rec.remote_connected(local, remote, client_addr);
+            if let Some(remote_info) = rec.remotes.get_mut(remote) {
+                remote_info.remote_connected(local, remote, client_addr, connect_time);
+                rec.record_outcome(local, remote, Some(connect_time));
             }
         };
     }
@@ -235,30 +702,48 @@ impl State {
     ) {
         if let Some(mut tunnel) = self.inner.tunnels.get_mut(local) {
             tunnel.remote_error(local, remote, Some(client_addr));
+            tunnel.slot_released.notify_waiters();
+            tunnel.record_outcome(local, remote, None);
 
             let mut is_dead = false;
             if let Some(remote_info) = tunnel.remotes.get_mut(remote) {
                 remote_info.error(local, remote, Some(client_addr));
                 is_dead = remote_info.stats.num_errors >= options.errors_till_dead;
             }
+            tunnel.check_remote_drained(remote);
 
             if is_dead {
-                if let Some(rec) = tunnel.remotes.remove(remote) {
-                    let join_handle = self.check_dead(
-                        local.clone(),
-                        remote.clone(),
-                        Duration::from_secs_f32(options.connect_timeout),
-                        Duration::from_secs_f32(10.0),
-                        options.tls_config(self),
-                    ); //TODO: from options
+                if let Some(mut rec) = tunnel.remotes.remove(remote) {
+                    // with active health checks, dead remote is revived by them
+                    let (join_handle, retry_interval, after) = match tunnel.options.health_check {
+                        Some(ref check) => {
+                            rec.health_successes = 0;
+                            let interval = Duration::from_secs_f32(check.interval);
+                            (None, interval, interval)
+                        }
+                        None => {
+                            let retry_interval = Duration::from_secs_f32(options.dead_retry);
+                            let after = options.with_jitter(retry_interval);
+                            let join_handle = self.check_dead(
+                                local.clone(),
+                                remote.clone(),
+                                after,
+                                options.clone(),
+                            );
+                            (Some(join_handle), retry_interval, after)
+                        }
+                    };
                     tunnel.dead_remotes.insert(
                         remote.clone(),
                         DeadRemote {
                             remote: rec,
-                            join_handle: Some(join_handle),
+                            join_handle,
+                            retry_interval,
+                            next_check: SystemTime::now() + after,
                         },
                     );
                     debug!("Tunnel remote {} moved to dead remotes", remote);
+                    tunnel.update_active_tier(local);
                 }
             }
         }
@@ -269,22 +754,33 @@ impl State {
         &self,
         local: SocketSpec,
         remote: SocketSpec,
-        timeout: Duration,
         after: Duration,
-        tls_config: Option<Arc<ClientConfig>>,
+        options: TunnelRemoteOptions,
     ) -> JoinHandle<()> {
         // spawn task after given duration
         // check that can connect to remote, which should be in dead remotes
         // if OK move Remote info to active remotes and reset error count
         // if not cannot connect just increase error count and timestamp and reschedule check_dead
+        // with interval increased by backoff
         let remote = remote.clone();
         let state = self.clone();
         let local = local.clone();
         let f = async move {
             time::sleep(after).await;
+            let timeout = Duration::from_secs_f32(options.connect_timeout);
+            let tls_config = options.tls_config(&state);
 
-            match time::timeout(timeout, connect_remote(&remote, tls_config.clone())).await {
-                Ok(Ok(_conn)) => {
+            let probe = async {
+                if local.is_udp() {
+                    udp::connect_remote(&remote).await.map(|_| ())
+                } else {
+                    connect_remote(&remote, tls_config.clone())
+                        .await
+                        .map(|_| ())
+                }
+            };
+            match time::timeout(timeout, probe).await {
+                Ok(Ok(())) => {
                     if let Some(mut tunnel) = state.inner.tunnels.get_mut(&local) {
                         if let Some(DeadRemote {
                             remote: mut rec, ..
@@ -297,6 +793,7 @@ impl State {
                                 remote
                             );
                             tunnel.remotes.insert(remote, rec);
+                            tunnel.update_active_tier(&local);
                         }
                     }
                 }
@@ -306,12 +803,16 @@ impl State {
                         if let Some(DeadRemote {
                             remote: ref mut remote_info,
                             ref mut join_handle,
+                            ref mut retry_interval,
+                            ref mut next_check,
                         }) = tunnel.dead_remotes.get_mut(&remote)
                         {
                             remote_info.error(&local, &remote, None);
 
-                            let new_handle =
-                                state.check_dead(local, remote, timeout, after, tls_config);
+                            *retry_interval = options.next_dead_retry(*retry_interval);
+                            let after = options.with_jitter(*retry_interval);
+                            *next_check = SystemTime::now() + after;
+                            let new_handle = state.check_dead(local, remote, after, options);
                             *join_handle = Some(new_handle);
                         }
                     }
@@ -321,6 +822,12 @@ impl State {
         return tokio::spawn(f);
     }
 
+    pub(crate) fn stream_timed_out(&self, local: &SocketSpec, reason: StreamTimeout) {
+        if let Some(mut rec) = self.inner.tunnels.get_mut(local) {
+            rec.stream_timed_out(local, reason);
+        }
+    }
+
     pub fn update_transferred(
         &self,
         local: &SocketSpec,
@@ -347,9 +854,10 @@ impl State {
             rec.client_disconnected(local, remote, _client_addr);
             //TODO: refactor when if let chain will become stable
             if let Some(remote) = remote {
-                if let Some(rec) = rec.remotes.get_mut(remote) {
-                    rec.client_disconnected(local, remote, _client_addr);
+                if let Some(info) = rec.remote_info_mut(remote) {
+                    info.client_disconnected(local, remote, _client_addr);
                 }
+                rec.check_remote_drained(remote);
             }
         }
     }
@@ -388,6 +896,55 @@ impl State {
             .ok_or(Error::TunnelDoesNotExist)
     }
 
+    /// All remotes of tunnel, live and dead
+    pub fn all_remotes(&self, local: &SocketSpec) -> Result<Vec<SocketSpec>> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|t| {
+                t.remotes
+                    .keys()
+                    .chain(t.dead_remotes.keys())
+                    .cloned()
+                    .collect()
+            })
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
+    pub(crate) fn health_check_result(
+        &self,
+        local: &SocketSpec,
+        remote: &SocketSpec,
+        ok: bool,
+        check: &HealthCheck,
+    ) {
+        if let Some(mut tunnel) = self.inner.tunnels.get_mut(local) {
+            tunnel.health_check_result(local, remote, ok, check);
+        }
+    }
+
+    /// Receiver signalled, when tunnel is closed
+    pub(crate) fn close_receiver(&self, local: &SocketSpec) -> Result<watch::Receiver<bool>> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|ti| ti.close_channel.subscribe())
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
+    pub fn dead_remotes(&self, local: &SocketSpec) -> Result<Vec<(SocketSpec, DeadRemoteStats)>> {
+        self.inner
+            .tunnels
+            .get(local)
+            .map(|t| {
+                t.dead_remotes
+                    .iter()
+                    .map(|(remote, dead)| (remote.clone(), dead.stats()))
+                    .collect()
+            })
+            .ok_or(Error::TunnelDoesNotExist)
+    }
+
     pub fn tunnel_options(&self, local: &SocketSpec) -> Result<TunnelOptions> {
         self.inner
             .tunnels
@@ -409,3 +966,792 @@ impl State {
         self.inner.config.read().remote_retries
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> State {
+        #[cfg(feature = "metrics")]
+        let state = State::new(Args::default(), crate::metrics::init_meter()).unwrap();
+        #[cfg(not(feature = "metrics"))]
+        let state = State::new(Args::default()).unwrap();
+        state
+    }
+
+    #[tokio::test]
+    async fn test_remote_admin_state() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001,3002".parse().unwrap();
+        let (local, r1, r2) = (
+            tunnel.local.clone(),
+            tunnel.remote[0].socket.clone(),
+            tunnel.remote[1].socket.clone(),
+        );
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let remote_state = |remote: &SocketSpec| {
+            state
+                .remotes(&local)
+                .unwrap()
+                .0
+                .into_iter()
+                .find(|(r, _)| r == remote)
+                .map(|(_, stats)| stats.admin_state)
+        };
+
+        state
+            .set_remote_state(&local, &r1, RemoteAdminState::Disabled)
+            .unwrap();
+        for _ in 0..5 {
+            state.try_client_connected(&local, &client).unwrap();
+            let (selected, _) = state
+                .select_remote(&local, &ConnectionContext::default())
+                .unwrap();
+            assert_eq!(r2, selected);
+            state.remote_connected(&local, &selected, &client, Duration::ZERO);
+        }
+
+        // draining remote is disabled after last connection finishes
+        state
+            .set_remote_state(&local, &r2, RemoteAdminState::Draining)
+            .unwrap();
+        assert!(matches!(
+            state.select_remote(&local, &ConnectionContext::default()),
+            Err(Error::NoRemote)
+        ));
+        for _ in 0..5 {
+            assert_eq!(Some(RemoteAdminState::Draining), remote_state(&r2));
+            state.client_disconnected(&local, Some(&r2), &client);
+        }
+        assert_eq!(Some(RemoteAdminState::Disabled), remote_state(&r2));
+
+        // removed remote stays in tunnel until its connection finishes
+        state
+            .set_remote_state(&local, &r1, RemoteAdminState::Active)
+            .unwrap();
+        state.try_client_connected(&local, &client).unwrap();
+        let (selected, _) = state
+            .select_remote(&local, &ConnectionContext::default())
+            .unwrap();
+        assert_eq!(r1, selected);
+        state.remote_connected(&local, &selected, &client, Duration::ZERO);
+        state.remove_remote_from_tunnel(&local, &r1).unwrap();
+        assert_eq!(Some(RemoteAdminState::Draining), remote_state(&r1));
+        state.client_disconnected(&local, Some(&r1), &client);
+        assert_eq!(None, remote_state(&r1));
+        state.remove_remote_from_tunnel(&local, &r2).unwrap();
+        assert_eq!(None, remote_state(&r2));
+    }
+
+    #[tokio::test]
+    async fn test_slow_start() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001[slow-start=100]".parse().unwrap();
+        let local = tunnel.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let new_remote: SocketSpec = "3002".parse().unwrap();
+        state
+            .add_remote_to_tunnel(&local, new_remote.clone().into())
+            .unwrap();
+
+        // newly added remote starts with small share of connections
+        let selected = (0..200)
+            .filter(|_| {
+                state
+                    .select_remote(&local, &ConnectionContext::default())
+                    .unwrap()
+                    .0
+                    == new_remote
+            })
+            .count();
+        assert!(
+            selected < 50,
+            "slow starting remote selected {} times",
+            selected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outlier_ejection() {
+        let state = test_state();
+        let tunnel: Tunnel =
+            "3000=3001,3002[strategy=round-robin,errors=100,outlier-min-requests=4]"
+                .parse()
+                .unwrap();
+        let (local, r1, r2) = (
+            tunnel.local.clone(),
+            tunnel.remote[0].socket.clone(),
+            tunnel.remote[1].socket.clone(),
+        );
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let connect = |failing: &[&SocketSpec]| {
+            state.try_client_connected(&local, &client).unwrap();
+            let (selected, options) = state
+                .select_remote(&local, &ConnectionContext::default())
+                .unwrap();
+            if failing.contains(&&selected) {
+                state.remote_error(&local, &selected, &client, &options);
+                state.client_disconnected(&local, None, &client);
+            } else {
+                state.remote_connected(&local, &selected, &client, Duration::ZERO);
+                state.client_disconnected(&local, Some(&selected), &client);
+            }
+            selected
+        };
+        let ejected = |remote: &SocketSpec| {
+            state
+                .remotes(&local)
+                .unwrap()
+                .0
+                .into_iter()
+                .find(|(r, _)| r == remote)
+                .map(|(_, stats)| stats.is_ejected())
+                .unwrap()
+        };
+
+        for _ in 0..8 {
+            connect(&[&r1]);
+        }
+        assert!(ejected(&r1));
+        assert!(!ejected(&r2));
+        for _ in 0..5 {
+            assert_eq!(r2, connect(&[&r1, &r2]));
+        }
+        // at most half of remotes can be ejected
+        assert!(!ejected(&r2));
+    }
+
+    #[tokio::test]
+    async fn test_weighted_strategies() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001@3,3002[strategy=weighted-round-robin]"
+            .parse()
+            .unwrap();
+        let (local, r1) = (tunnel.local.clone(), tunnel.remote[0].socket.clone());
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let selected: Vec<bool> = (0..8)
+            .map(|_| {
+                state
+                    .select_remote(&local, &ConnectionContext::default())
+                    .unwrap()
+                    .0
+                    == r1
+            })
+            .collect();
+        assert_eq!(6, selected.iter().filter(|s| **s).count());
+        // smooth - heavier remote is not selected in one burst
+        assert!(selected.windows(4).all(|w| w.contains(&false)));
+
+        state.set_remote_weight(&local, &r1, 1).unwrap();
+        let selected = (0..8)
+            .filter(|_| {
+                state
+                    .select_remote(&local, &ConnectionContext::default())
+                    .unwrap()
+                    .0
+                    == r1
+            })
+            .count();
+        assert_eq!(4, selected);
+        assert!(state.set_remote_weight(&local, &r1, 0).is_err());
+
+        let tunnel: Tunnel = "4000=4001@9,4002[strategy=weighted-random]"
+            .parse()
+            .unwrap();
+        let (local, r2) = (tunnel.local.clone(), tunnel.remote[1].socket.clone());
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let selected = (0..500)
+            .filter(|_| {
+                state
+                    .select_remote(&local, &ConnectionContext::default())
+                    .unwrap()
+                    .0
+                    == r2
+            })
+            .count();
+        assert!(selected < 150, "light remote selected {} times", selected);
+    }
+
+    #[tokio::test]
+    async fn test_hash_strategies() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001,3002,3003[strategy=source-hash]".parse().unwrap();
+        let local = tunnel.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let ctx = ConnectionContext {
+            client: Some("10.0.0.1:5000".parse().unwrap()),
+            server_name: None,
+        };
+        let (first, _) = state.select_remote(&local, &ctx).unwrap();
+        for port in 5001..5010 {
+            let ctx = ConnectionContext {
+                client: Some(SocketAddr::new("10.0.0.1".parse().unwrap(), port)),
+                server_name: None,
+            };
+            assert_eq!(first, state.select_remote(&local, &ctx).unwrap().0);
+        }
+
+        let tunnel: Tunnel = "4000=4001,4002,4003[strategy=consistent-hash]"
+            .parse()
+            .unwrap();
+        let (local, removed) = (tunnel.local.clone(), tunnel.remote[1].socket.clone());
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let assignment = || {
+            (0..300u32)
+                .map(|n| {
+                    let ctx = ConnectionContext {
+                        client: Some(SocketAddr::new(
+                            std::net::Ipv4Addr::from(0x0a00_0000 + n).into(),
+                            5000,
+                        )),
+                        server_name: None,
+                    };
+                    state.select_remote(&local, &ctx).unwrap().0
+                })
+                .collect::<Vec<_>>()
+        };
+        let before = assignment();
+        let added: SocketSpec = "4004".parse().unwrap();
+        state
+            .add_remote_to_tunnel(&local, added.clone().into())
+            .unwrap();
+        let after = assignment();
+        let moved: Vec<_> = before
+            .iter()
+            .zip(after.iter())
+            .filter(|(b, a)| b != a)
+            .collect();
+        // only clients moved to new remote, about 1/4 of them
+        assert!(moved.iter().all(|(_, a)| **a == added));
+        assert!(
+            moved.len() > 30 && moved.len() < 130,
+            "moved {}",
+            moved.len()
+        );
+
+        state.remove_remote_from_tunnel(&local, &removed).unwrap();
+        let after_remove = assignment();
+        for (a, r) in after.iter().zip(after_remove.iter()) {
+            if *a != removed {
+                assert_eq!(a, r);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latency_strategies() {
+        let state = test_state();
+        for (port, strategy) in [(3000, "peak-ewma"), (4000, "p2c")] {
+            let tunnel: Tunnel =
+                format!("{}={},{}[strategy={}]", port, port + 1, port + 2, strategy)
+                    .parse()
+                    .unwrap();
+            let (local, slow) = (tunnel.local.clone(), tunnel.remote[0].socket.clone());
+            let (sender, _receiver) = watch::channel(false);
+            state.add_tunnel(tunnel, sender).unwrap();
+            let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+            let ctx = ConnectionContext::default();
+            let latency =
+                |remote: &SocketSpec| Duration::from_millis(if *remote == slow { 50 } else { 5 });
+            for _ in 0..10 {
+                state.try_client_connected(&local, &client).unwrap();
+                let (selected, _) = state.select_remote(&local, &ctx).unwrap();
+                state.remote_connected(&local, &selected, &client, latency(&selected));
+                state.client_disconnected(&local, Some(&selected), &client);
+            }
+            let stats = state.remotes(&local).unwrap().0;
+            assert!(stats.iter().all(|(_, s)| s.connect_latency_ewma.is_some()));
+
+            // connections stay open, so load is taken into account too
+            let mut fast_selected = 0;
+            for _ in 0..10 {
+                state.try_client_connected(&local, &client).unwrap();
+                let (selected, _) = state.select_remote(&local, &ctx).unwrap();
+                state.remote_connected(&local, &selected, &client, latency(&selected));
+                if selected != slow {
+                    fast_selected += 1;
+                }
+            }
+            assert!(
+                fast_selected >= 8,
+                "{}: fast remote selected {} times",
+                strategy,
+                fast_selected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_tier() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001,3002,3003!backup".parse().unwrap();
+        let (local, r1, r2, backup) = (
+            tunnel.local.clone(),
+            tunnel.remote[0].socket.clone(),
+            tunnel.remote[1].socket.clone(),
+            tunnel.remote[2].socket.clone(),
+        );
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let ctx = ConnectionContext::default();
+        let tunnel_stats = || {
+            state
+                .stats()
+                .into_iter()
+                .find(|(t, _)| *t == local)
+                .map(|(_, stats)| stats)
+                .unwrap()
+        };
+        for _ in 0..20 {
+            assert_ne!(backup, state.select_remote(&local, &ctx).unwrap().0);
+        }
+        assert_eq!(Some(0), tunnel_stats().active_tier);
+
+        // backup is used only when all primaries are dead
+        let options = state.tunnel_options(&local).unwrap().options;
+        for remote in [&r1, &r2] {
+            state.try_client_connected(&local, &client).unwrap();
+            while state.select_remote(&local, &ctx).unwrap().0 != *remote {}
+            state.remote_error(&local, remote, &client, &options);
+            state.client_disconnected(&local, None, &client);
+        }
+        assert_eq!(backup, state.select_remote(&local, &ctx).unwrap().0);
+        let stats = tunnel_stats();
+        assert_eq!(Some(1), stats.active_tier);
+        assert_eq!(1, stats.tier_changes);
+    }
+
+    /// Prefers remotes with metadata `preferred=true`
+    #[derive(Debug)]
+    struct Preferred;
+
+    impl LBStrategy for Preferred {
+        fn select_remote(
+            &self,
+            tunnel: &TunnelInfo,
+            candidates: &[usize],
+            _ctx: &ConnectionContext,
+        ) -> Result<usize> {
+            Ok(tunnel
+                .remote_views(candidates)
+                .find(|r| r.metadata("preferred") == Some("true"))
+                .map(|r| r.index)
+                .unwrap_or(candidates[0]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_strategy() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001,3002[strategy=preferred]".parse().unwrap();
+        let (sender, _receiver) = watch::channel(false);
+        assert!(matches!(
+            state.add_tunnel(tunnel.clone(), sender),
+            Err(Error::InvalidLBStrategy)
+        ));
+        assert!(state
+            .register_strategy("round-robin", || Box::new(Preferred))
+            .is_err());
+        state
+            .register_strategy("preferred", || Box::new(Preferred))
+            .unwrap();
+        assert_eq!(vec!["preferred".to_string()], state.strategy_names());
+
+        let (local, r2) = (tunnel.local.clone(), tunnel.remote[1].socket.clone());
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let metadata = BTreeMap::from([("preferred".to_string(), "true".to_string())]);
+        state.set_remote_metadata(&local, &r2, metadata).unwrap();
+        for _ in 0..5 {
+            let (selected, _) = state
+                .select_remote(&local, &ConnectionContext::default())
+                .unwrap();
+            assert_eq!(r2, selected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=127.0.0.1:3001,127.0.0.1:3002".parse().unwrap();
+        let local = tunnel.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let candidates = |server_name: Option<&str>| {
+            let ti = state.inner.tunnels.get(&local).unwrap();
+            let mut remotes: Vec<String> = ti
+                .candidates(server_name)
+                .into_iter()
+                .map(|idx| ti.remotes.get_index(idx).unwrap().0.to_string())
+                .collect();
+            remotes.sort();
+            remotes
+        };
+        let default_pool = vec!["127.0.0.1:3001", "127.0.0.1:3002"];
+        assert_eq!(default_pool, candidates(Some("api.example.com")));
+
+        let routed: SocketSpec = "127.0.0.1:3003".parse().unwrap();
+        state
+            .add_route(&local, "API.example.com.", routed.clone())
+            .unwrap();
+        assert!(matches!(
+            state.add_route(&local, "api.example.com", routed.clone()),
+            Err(Error::RemoteExists)
+        ));
+        assert_eq!(vec!["127.0.0.1:3003"], candidates(Some("api.example.com")));
+        // unrouted names and connections without name use remotes not bound to any route
+        assert_eq!(default_pool, candidates(Some("www.example.com")));
+        assert_eq!(default_pool, candidates(None));
+        let ctx = ConnectionContext {
+            client: None,
+            server_name: Some("api.example.com"),
+        };
+        let (selected, _) = state.select_remote(&local, &ctx).unwrap();
+        assert_eq!(routed, selected);
+
+        state
+            .add_route(&local, "api.example.com", "127.0.0.1:3001".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            vec!["127.0.0.1:3001", "127.0.0.1:3003"],
+            candidates(Some("api.example.com"))
+        );
+        assert_eq!(vec!["127.0.0.1:3002"], candidates(None));
+
+        // removing last remote of route removes route, remotes return to default pool
+        state
+            .remove_route(
+                &local,
+                "api.example.com",
+                &"127.0.0.1:3001".parse().unwrap(),
+            )
+            .unwrap();
+        state
+            .remove_route(&local, "api.example.com", &routed)
+            .unwrap();
+        assert!(state.routes(&local).unwrap().is_empty());
+        assert!(matches!(
+            state.remove_route(&local, "api.example.com", &routed),
+            Err(Error::RouteDoesNotExist)
+        ));
+        let all = vec!["127.0.0.1:3001", "127.0.0.1:3002", "127.0.0.1:3003"];
+        assert_eq!(all, candidates(Some("api.example.com")));
+        assert_eq!(all, candidates(None));
+    }
+
+    #[tokio::test]
+    async fn test_update_tunnel_options() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001,3002[strategy=round-robin,max-conns=10]"
+            .parse()
+            .unwrap();
+        let (local, r2) = (tunnel.local.clone(), tunnel.remote[1].socket.clone());
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+
+        assert!(matches!(
+            state.update_tunnel_options(&local, "strategy=preferred"),
+            Err(Error::InvalidLBStrategy)
+        ));
+        assert!(matches!(
+            state.update_tunnel_options(&local, "local-tls=true"),
+            Err(Error::InvalidTunnel(_))
+        ));
+        assert!(state.update_tunnel_options(&local, "max-conns=x").is_err());
+        assert_eq!(Some(10), state.tunnel_options(&local).unwrap().max_conns);
+
+        state
+            .register_strategy("preferred", || Box::new(Preferred))
+            .unwrap();
+        let metadata = BTreeMap::from([("preferred".to_string(), "true".to_string())]);
+        state.set_remote_metadata(&local, &r2, metadata).unwrap();
+        let update = state
+            .update_tunnel_options(&local, "strategy=preferred,max-conns=20")
+            .unwrap();
+        assert_eq!(Some(10), update.previous.max_conns);
+        assert_eq!(Some(20), update.current.max_conns);
+        assert_eq!(Some(20), state.tunnel_options(&local).unwrap().max_conns);
+        for _ in 0..5 {
+            let (selected, _) = state
+                .select_remote(&local, &ConnectionContext::default())
+                .unwrap();
+            assert_eq!(r2, selected);
+        }
+
+        let update = state
+            .update_tunnel_options(&local, "max-conns=none,health-check=tcp")
+            .unwrap();
+        assert_eq!(None, update.current.max_conns);
+        assert!(update.current.health_check.is_some());
+        let update = state
+            .update_tunnel_options(&local, "health-check=none")
+            .unwrap();
+        assert!(update.current.health_check.is_none());
+        assert_eq!("preferred", update.current.lb_strategy.to_string());
+
+        let udp: Tunnel = "udp:4000=4001".parse().unwrap();
+        let udp_local = udp.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(udp, sender).unwrap();
+        assert!(state
+            .update_tunnel_options(&udp_local, "sni-routing=true")
+            .is_err());
+        assert!(state
+            .update_tunnel_options(&udp_local, "health-check=send,health-send=ping")
+            .is_err());
+        assert!(state
+            .update_tunnel_options(
+                &udp_local,
+                "health-check=send,health-send=ping,health-expect=pong"
+            )
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_enable_health_checks() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=127.0.0.1:3001".parse().unwrap();
+        let local = tunnel.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let health_running = || {
+            state
+                .inner
+                .tunnels
+                .get(&local)
+                .unwrap()
+                .health_task
+                .as_ref()
+                .map(|task| !task.is_finished())
+                .unwrap_or(false)
+        };
+        assert!(!health_running());
+        state
+            .update_tunnel_options(&local, "health-check=tcp,health-interval=0.1")
+            .unwrap();
+        assert!(health_running());
+        state
+            .update_tunnel_options(&local, "health-check=none")
+            .unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(!health_running());
+    }
+
+    #[tokio::test]
+    async fn test_disable_health_checks() {
+        let state = test_state();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote: SocketSpec = listener.local_addr().unwrap().to_string().parse().unwrap();
+        let tunnel: Tunnel = format!(
+            "3000={}[health-check=tcp,health-fall=1,check-interval=0.1,check-jitter=0]",
+            remote
+        )
+        .parse()
+        .unwrap();
+        let local = tunnel.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let check = state.tunnel_options(&local).unwrap().health_check.unwrap();
+        state.health_check_result(&local, &remote, false, &check);
+        assert_eq!(1, state.dead_remotes(&local).unwrap().len());
+
+        // without health checks dead remote is revived by usual dead remote check
+        state
+            .update_tunnel_options(&local, "health-check=none")
+            .unwrap();
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(state.dead_remotes(&local).unwrap().is_empty());
+        assert!(state
+            .inner
+            .tunnels
+            .get(&local)
+            .unwrap()
+            .remotes
+            .contains_key(&remote));
+    }
+
+    #[tokio::test]
+    async fn test_replace_remotes() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001,3002,3003".parse().unwrap();
+        let local = tunnel.local.clone();
+        let (r1, r2, r3) = (
+            tunnel.remote[0].socket.clone(),
+            tunnel.remote[1].socket.clone(),
+            tunnel.remote[2].socket.clone(),
+        );
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let ctx = ConnectionContext::default();
+        let options = state.tunnel_options(&local).unwrap().options;
+        let remote_stats = |remote: &SocketSpec| {
+            state
+                .remotes(&local)
+                .unwrap()
+                .0
+                .into_iter()
+                .find(|(r, _)| r == remote)
+                .map(|(_, stats)| stats)
+        };
+
+        for remote in [&r1, &r2, &r3] {
+            state.try_client_connected(&local, &client).unwrap();
+            while state.select_remote(&local, &ctx).unwrap().0 != *remote {}
+            if *remote == r3 {
+                state.remote_error(&local, remote, &client, &options);
+                state.client_disconnected(&local, None, &client);
+            } else {
+                state.remote_connected(&local, remote, &client, Duration::ZERO);
+            }
+        }
+        state.client_disconnected(&local, Some(&r2), &client);
+        assert_eq!(1, state.dead_remotes(&local).unwrap().len());
+
+        assert!(state.replace_remotes(&local, vec![]).is_err());
+        let duplicate = vec![r2.clone().into(), r2.clone().into()];
+        assert!(state.replace_remotes(&local, duplicate).is_err());
+
+        let r4: SocketSpec = "3004".parse().unwrap();
+        let remotes = vec!["3002@3".parse().unwrap(), r4.clone().into()];
+        let change = state.replace_remotes(&local, remotes).unwrap();
+        assert_eq!(
+            (1, 1, 1, 1),
+            (change.added, change.kept, change.draining, change.removed)
+        );
+        assert!(state.dead_remotes(&local).unwrap().is_empty());
+        let stats = remote_stats(&r2).unwrap();
+        assert_eq!((3, 1), (stats.weight, stats.total_connections));
+        assert_eq!(
+            Some(RemoteAdminState::Draining),
+            remote_stats(&r1).map(|s| s.admin_state)
+        );
+        assert!(remote_stats(&r4).is_some());
+
+        // drained remote is kept, when it returns to set
+        let remotes = vec![r1.clone().into(), r2.clone().into(), r4.clone().into()];
+        let change = state.replace_remotes(&local, remotes).unwrap();
+        assert_eq!((0, 3), (change.added, change.kept));
+        assert_eq!(
+            Some(RemoteAdminState::Active),
+            remote_stats(&r1).map(|s| s.admin_state)
+        );
+        state.client_disconnected(&local, Some(&r1), &client);
+        assert!(remote_stats(&r1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_dns_names() {
+        let state = test_state();
+        let tunnel: Tunnel = "3000=backend.internal:3001@2,3002[dns-refresh=30]"
+            .parse()
+            .unwrap();
+        let (local, name) = (tunnel.local.clone(), tunnel.remote[0].socket.clone());
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let client: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let address = |a: &str| name.with_address(a.parse().unwrap()).unwrap();
+        let remote_stats = |remote: &SocketSpec| {
+            state
+                .remotes(&local)
+                .unwrap()
+                .0
+                .into_iter()
+                .find(|(r, _)| r == remote)
+                .map(|(_, stats)| stats)
+        };
+        let ip = |a: &str| a.parse::<IpAddr>().unwrap();
+
+        assert_eq!(vec![name.clone()], state.track_dns_names(&local).unwrap());
+        let change = state
+            .update_resolved(&local, &name, &[ip("10.0.0.1"), ip("10.0.0.2")])
+            .unwrap();
+        assert_eq!((2, 1), (change.added, change.removed));
+        assert!(remote_stats(&name).is_none());
+        assert_eq!(2, remote_stats(&address("10.0.0.1")).unwrap().weight);
+
+        // other selected remotes are released, so they have no pending connections
+        let ctx = ConnectionContext::default();
+        loop {
+            state.try_client_connected(&local, &client).unwrap();
+            let (selected, _) = state.select_remote(&local, &ctx).unwrap();
+            state.remote_connected(&local, &selected, &client, Duration::ZERO);
+            if selected == address("10.0.0.1") {
+                break;
+            }
+            state.client_disconnected(&local, Some(&selected), &client);
+        }
+        let change = state
+            .update_resolved(&local, &name, &[ip("10.0.0.2"), ip("10.0.0.3")])
+            .unwrap();
+        assert_eq!(
+            (1, 1, 1, 0),
+            (change.added, change.kept, change.draining, change.removed)
+        );
+        assert_eq!(
+            RemoteAdminState::Draining,
+            remote_stats(&address("10.0.0.1")).unwrap().admin_state
+        );
+        assert_eq!(vec![name.clone()], state.track_dns_names(&local).unwrap());
+        assert_eq!(3, state.resolved_names(&local).unwrap()[0].1.len());
+
+        // name stays in replaced set, so do its addresses
+        let remotes = vec![
+            "backend.internal:3001@5".parse().unwrap(),
+            "3002".parse().unwrap(),
+        ];
+        let change = state.replace_remotes(&local, remotes).unwrap();
+        assert_eq!((0, 4), (change.added, change.kept));
+        assert!(remote_stats(&name).is_none());
+        assert_eq!(5, remote_stats(&address("10.0.0.3")).unwrap().weight);
+        assert!(matches!(
+            state.add_remote_to_tunnel(&local, name.clone().into()),
+            Err(Error::RemoteExists)
+        ));
+
+        state.remove_remote_from_tunnel(&local, &name).unwrap();
+        assert!(state.resolved_names(&local).unwrap().is_empty());
+        assert!(remote_stats(&address("10.0.0.2")).is_none());
+        assert!(remote_stats(&address("10.0.0.1")).is_some());
+        state.client_disconnected(&local, Some(&address("10.0.0.1")), &client);
+        assert!(remote_stats(&address("10.0.0.1")).is_none());
+        assert!(state.track_dns_names(&local).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_buckets_limit() {
+        use super::info::MAX_CLIENT_BUCKETS;
+        let state = test_state();
+        let tunnel: Tunnel = "3000=3001[client-rate=1]".parse().unwrap();
+        let local = tunnel.local.clone();
+        let (sender, _receiver) = watch::channel(false);
+        state.add_tunnel(tunnel, sender).unwrap();
+        let first: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        state.check_rate(&local, &first).unwrap();
+        assert!(state.check_rate(&local, &first).is_err());
+        for n in 0..MAX_CLIENT_BUCKETS as u32 * 2 {
+            let client = SocketAddr::new(IpAddr::from((n + (11 << 24)).to_be_bytes()), 5000);
+            state.check_rate(&local, &client).unwrap();
+            let buckets = state
+                .inner
+                .tunnels
+                .get(&local)
+                .unwrap()
+                .client_buckets
+                .len();
+            assert!(buckets <= MAX_CLIENT_BUCKETS);
+        }
+        // oldest bucket was evicted
+        state.check_rate(&local, &first).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! Periodic DNS resolution of host name remotes - each host name is resolved to all its addresses
+//! and every address becomes separate remote (`name/address:port`), addresses are refreshed
+//! after `dns-refresh` interval or sooner, when DNS records expire.
+
+use std::time::{Duration, Instant};
+
+use tokio::{sync::watch, time::sleep};
+use tracing::{debug, instrument, warn};
+use trust_dns_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    TokioAsyncResolver,
+};
+
+use crate::{tunnel::SocketSpec, State};
+
+/// Minimal time between resolutions, even if DNS records have shorter TTL
+const MIN_DNS_REFRESH: Duration = Duration::from_secs(1);
+
+fn create_resolver() -> TokioAsyncResolver {
+    TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|e| {
+        warn!(error=%e, "Cannot use system DNS configuration, using default");
+        TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+            .expect("default resolver")
+    })
+}
+
+/// Resolves host name remotes of tunnel, until tunnel is closed or `dns_refresh` option is unset
+#[instrument(skip_all, fields(tunnel=%tunnel_key))]
+pub(crate) async fn run_dns_refresh(
+    state: State,
+    tunnel_key: SocketSpec,
+    mut close_channel: watch::Receiver<bool>,
+) {
+    debug!("Started DNS refresh");
+    let resolver = create_resolver();
+    loop {
+        // options are read every round, so changes are applied
+        let refresh = match state.tunnel_options(&tunnel_key) {
+            Ok(options) => match options.dns_refresh {
+                Some(refresh) => Duration::from_secs_f32(refresh),
+                None => break,
+            },
+            Err(_) => break,
+        };
+        let names = match state.track_dns_names(&tunnel_key) {
+            Ok(names) => names,
+            Err(_) => break,
+        };
+        let mut next_refresh = refresh;
+        for name in names {
+            let Some(host) = name.host() else {
+                continue;
+            };
+            match resolver.lookup_ip(host).await {
+                Ok(lookup) => {
+                    let addresses: Vec<_> = lookup.iter().collect();
+                    if addresses.is_empty() {
+                        warn!(name=%name, "Host name has no addresses, keeping current ones");
+                        continue;
+                    }
+                    let ttl = lookup
+                        .valid_until()
+                        .saturating_duration_since(Instant::now());
+                    next_refresh = next_refresh.min(ttl);
+                    if state
+                        .update_resolved(&tunnel_key, &name, &addresses)
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!(name=%name, error=%e, "Cannot resolve host name, keeping current addresses")
+                }
+            }
+        }
+        tokio::select! {
+            _ = sleep(next_refresh.max(MIN_DNS_REFRESH)) => (),
+            _ = close_channel.changed() => break,
+        }
+    }
+    debug!("Finished DNS refresh");
+}
@@ -0,0 +1,369 @@
+//! Active health checks of tunnel remotes - all remotes (live and dead) are probed periodically,
+//! remote is moved to dead remotes after `fall` consequent failed checks
+//! and back to live remotes after `rise` consequent successful checks.
+
+use std::{fmt::Display, io, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tracing::{debug, instrument};
+
+use crate::{
+    connect_remote,
+    error::{Error, Result},
+    tunnel::SocketSpec,
+    udp, State,
+};
+
+/// Maximum of response data read by health check
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheckKind {
+    /// Connection can be established (TLS handshake too, if remote uses TLS)
+    #[default]
+    Tcp,
+    /// TLS handshake succeeds, even if tunnel does not use TLS to remote
+    Tls,
+    /// Sends `send` data and expects response containing `expect` (for UDP tunnel only this one is supported)
+    Send,
+    /// HTTP GET request to `path` returns `status` (and response contains `expect`, if set)
+    Http,
+}
+
+impl FromStr for HealthCheckKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(HealthCheckKind::Tcp),
+            "tls" => Ok(HealthCheckKind::Tls),
+            "send" => Ok(HealthCheckKind::Send),
+            "http" => Ok(HealthCheckKind::Http),
+            _ => Err(Error::InvalidTunnel(format!("invalid health check: {}", s))),
+        }
+    }
+}
+
+impl Display for HealthCheckKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthCheckKind::Tcp => write!(f, "tcp"),
+            HealthCheckKind::Tls => write!(f, "tls"),
+            HealthCheckKind::Send => write!(f, "send"),
+            HealthCheckKind::Http => write!(f, "http"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCheck {
+    pub kind: HealthCheckKind,
+    /// Seconds between checks
+    pub interval: f32,
+    /// Timeout of one check in seconds
+    pub timeout: f32,
+    /// Consequent successful checks to consider dead remote alive
+    pub rise: u32,
+    /// Consequent failed checks to consider remote dead
+    pub fall: u32,
+    /// Data to send, backslash escapes `\r`, `\n`, `\t`, `\\` and `\xNN` are supported
+    pub send: Option<String>,
+    /// Response must contain this data, same escapes as for `send`
+    pub expect: Option<String>,
+    /// HTTP request path
+    pub path: String,
+    /// Expected HTTP status
+    pub status: u16,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            kind: HealthCheckKind::Tcp,
+            interval: 5.0,
+            timeout: 2.0,
+            rise: 2,
+            fall: 3,
+            send: None,
+            expect: None,
+            path: "/".into(),
+            status: 200,
+        }
+    }
+}
+
+impl Display for HealthCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "health-check={}, health-interval={}, health-timeout={}, health-rise={}, health-fall={}",
+            self.kind, self.interval, self.timeout, self.rise, self.fall
+        )?;
+        if self.kind == HealthCheckKind::Http {
+            write!(
+                f,
+                ", health-path={}, health-status={}",
+                self.path, self.status
+            )?;
+        }
+        if let Some(ref send) = self.send {
+            write!(f, ", health-send={}", send)?;
+        }
+        if let Some(ref expect) = self.expect {
+            write!(f, ", health-expect={}", expect)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces backslash escapes with bytes they represent
+pub fn unescape(s: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidTunnel(format!("invalid escape in {}", s));
+    let mut res = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+        match bytes.next().ok_or_else(invalid)? {
+            b'r' => res.push(b'\r'),
+            b'n' => res.push(b'\n'),
+            b't' => res.push(b'\t'),
+            b'\\' => res.push(b'\\'),
+            b'x' => {
+                let hex = [
+                    bytes.next().ok_or_else(invalid)?,
+                    bytes.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                res.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(res)
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn check_failed(msg: impl Into<String>) -> io::Error {
+    io::Error::other(msg.into())
+}
+
+/// Reads response until `done` or until remote closes connection
+async fn read_response<S>(stream: &mut S, done: impl Fn(&[u8]) -> bool) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut response = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !done(&response) && response.len() < MAX_RESPONSE_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    Ok(response)
+}
+
+fn check_expected(response: &[u8], expect: &Option<Vec<u8>>) -> io::Result<()> {
+    match expect {
+        Some(expect) if !contains(response, expect) => {
+            Err(check_failed("response does not contain expected data"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn http_status(response: &[u8]) -> Option<u16> {
+    let line_end = response.windows(2).position(|w| w == b"\r\n")?;
+    let line = std::str::from_utf8(&response[..line_end]).ok()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Probes remote once, `remote_tls` is whether tunnel connects to remote via TLS
+pub(crate) async fn probe(
+    remote: &SocketSpec,
+    check: &HealthCheck,
+    state: &State,
+    remote_tls: bool,
+    udp: bool,
+) -> io::Result<()> {
+    let invalid = |e: Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+    let send = check
+        .send
+        .as_deref()
+        .map(unescape)
+        .transpose()
+        .map_err(invalid)?;
+    let expect = check
+        .expect
+        .as_deref()
+        .map(unescape)
+        .transpose()
+        .map_err(invalid)?;
+
+    if udp {
+        let socket = udp::connect_remote(remote).await?;
+        socket.send(send.as_deref().unwrap_or_default()).await?;
+        if expect.is_some() {
+            let mut buf = vec![0; MAX_RESPONSE_SIZE];
+            let n = socket.recv(&mut buf).await?;
+            check_expected(&buf[..n], &expect)?;
+        }
+        return Ok(());
+    }
+
+    let tls_config = match check.kind {
+        HealthCheckKind::Tls => Some(state.client_ssl_config()),
+        _ if remote_tls => Some(state.client_ssl_config()),
+        _ => None,
+    };
+    let mut stream = connect_remote(remote, tls_config).await?;
+    match check.kind {
+        HealthCheckKind::Tcp | HealthCheckKind::Tls => Ok(()),
+        HealthCheckKind::Send => {
+            if let Some(send) = send {
+                stream.write_all(&send).await?;
+                stream.flush().await?;
+            }
+            if let Some(ref needle) = expect {
+                let response = read_response(&mut stream, |r| contains(r, needle)).await?;
+                check_expected(&response, &expect)?;
+            }
+            Ok(())
+        }
+        HealthCheckKind::Http => {
+            let host = remote.server_name().unwrap_or("localhost");
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: plexy\r\nConnection: close\r\n\r\n",
+                check.path, host
+            );
+            stream.write_all(request.as_bytes()).await?;
+            stream.flush().await?;
+            let response = read_response(&mut stream, |r| match expect {
+                Some(ref needle) => contains(r, needle),
+                None => http_status(r).is_some(),
+            })
+            .await?;
+            match http_status(&response) {
+                Some(status) if status == check.status => check_expected(&response, &expect),
+                Some(status) => Err(check_failed(format!("unexpected HTTP status {}", status))),
+                None => Err(check_failed("invalid HTTP response")),
+            }
+        }
+    }
+}
+
+/// Runs health checks of tunnel remotes, until tunnel is closed
+#[instrument(skip_all, fields(tunnel=%tunnel_key))]
+pub(crate) async fn run_health_checks(
+    state: State,
+    tunnel_key: SocketSpec,
+    mut close_channel: watch::Receiver<bool>,
+) {
+    debug!("Started health checks");
+    loop {
+        // options are read every round, so changes are applied
+        let (check, remote_tls) = match state.tunnel_options(&tunnel_key) {
+            Ok(options) => match options.health_check {
+                Some(check) => (check, options.options.tls),
+                None => break,
+            },
+            Err(_) => break,
+        };
+        tokio::select! {
+            _ = sleep(Duration::from_secs_f32(check.interval)) => (),
+            _ = close_channel.changed() => break,
+        }
+        let remotes = match state.all_remotes(&tunnel_key) {
+            Ok(remotes) => remotes,
+            Err(_) => break,
+        };
+        let udp = tunnel_key.is_udp();
+        let probes = remotes.into_iter().map(|remote| {
+            let check = &check;
+            let state = &state;
+            async move {
+                let res = timeout(
+                    Duration::from_secs_f32(check.timeout),
+                    probe(&remote, check, state, remote_tls, udp),
+                )
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")));
+                (remote, res)
+            }
+        });
+        for (remote, res) in futures::future::join_all(probes).await {
+            if let Err(ref e) = res {
+                debug!(remote=%remote, error=%e, "Health check failed");
+            }
+            state.health_check_result(&tunnel_key, &remote, res.is_ok(), &check);
+        }
+    }
+    debug!("Finished health checks");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Args;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(b"PING\r\n".to_vec(), unescape("PING\\r\\n").unwrap());
+        assert_eq!(vec![0, 0xff, b'\\'], unescape("\\x00\\xFF\\\\").unwrap());
+        assert!(unescape("bad\\").is_err());
+        assert!(unescape("\\xZZ").is_err());
+        assert_eq!(Some(204), http_status(b"HTTP/1.1 204 No Content\r\n\r\n"));
+        assert_eq!(None, http_status(b"SSH-2.0-OpenSSH\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http_probe() {
+        #[cfg(feature = "metrics")]
+        let state = State::new(Args::default(), crate::metrics::init_meter()).unwrap();
+        #[cfg(not(feature = "metrics"))]
+        let state = State::new(Args::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote: SocketSpec = listener.local_addr().unwrap().to_string().parse().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let n = conn.read(&mut buf).await.unwrap();
+                let response: &[u8] = if buf[..n].starts_with(b"GET /health ") {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+                } else {
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                };
+                conn.write_all(response).await.unwrap();
+            }
+        });
+        let mut check = HealthCheck {
+            kind: HealthCheckKind::Http,
+            path: "/health".into(),
+            ..Default::default()
+        };
+        probe(&remote, &check, &state, false, false).await.unwrap();
+        check.expect = Some("ok".into());
+        probe(&remote, &check, &state, false, false).await.unwrap();
+        check.path = "/".into();
+        assert!(probe(&remote, &check, &state, false, false).await.is_err());
+    }
+}
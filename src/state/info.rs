@@ -1,27 +1,72 @@
-use std::{net::SocketAddr, time::SystemTime};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use indexmap::IndexMap;
 use opentelemetry::{Context, KeyValue};
-use tokio::{sync::watch, task::JoinHandle};
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, info, warn};
 
 use crate::{
+    aio::StreamTimeout,
     error::{Error, Result},
-    tunnel::{SocketSpec, TunnelOptions},
+    tunnel::{
+        cidr::access_permitted, RateLimits, RemoteSpec, SocketSpec, TunnelOptions,
+        DEFAULT_REMOTE_WEIGHT,
+    },
     State,
 };
 
 use super::{
-    stats::{RemoteMetrics, RemoteStats, TunnelMetrics, TunnelStats},
-    strategy::LBStrategy,
+    health::HealthCheck,
+    outlier::OutcomeWindow,
+    rate::{BandwidthLimit, TokenBucket, TunnelBandwidth},
+    stats::{
+        DeadRemoteStats, HealthMetrics, RemoteAdminState, RemoteMetrics, RemoteStats,
+        RemotesChange, TunnelMetrics, TunnelStats,
+    },
+    strategy::{ConnectionContext, LBStrategy, RemoteView},
 };
 
 type RemotesMap = IndexMap<SocketSpec, RemoteInfo, fxhash::FxBuildHasher>;
 type DeadRemotesMap = IndexMap<SocketSpec, DeadRemote, fxhash::FxBuildHasher>;
+/// SNI server name -> remotes serving it
+pub type RoutesMap = IndexMap<String, Vec<SocketSpec>, fxhash::FxBuildHasher>;
+/// Host name remote -> its spec (weight and tier are used for all resolved addresses)
+type DnsNamesMap = IndexMap<SocketSpec, RemoteSpec, fxhash::FxBuildHasher>;
+
+/// When there are more client rate buckets, idle and oldest ones are removed
+pub(super) const MAX_CLIENT_BUCKETS: usize = 4096;
+/// Weight factor of remote at beginning of slow start
+const MIN_SLOW_START_FACTOR: f64 = 0.05;
+/// Weight of new sample in connect latency moving average
+const LATENCY_EWMA_ALPHA: f64 = 0.2;
+/// Time constant of peak latency decay in seconds
+const PEAK_LATENCY_DECAY: f64 = 10.0;
 
 #[derive(Debug)]
 pub struct DeadRemote {
     pub remote: RemoteInfo,
     pub join_handle: Option<JoinHandle<()>>,
+    /// Current interval of checks (without jitter)
+    pub retry_interval: Duration,
+    pub next_check: SystemTime,
+}
+
+impl DeadRemote {
+    pub fn stats(&self) -> DeadRemoteStats {
+        DeadRemoteStats {
+            stats: self.remote.stats.clone(),
+            next_check: Some(self.next_check),
+            retry_interval: self.retry_interval.as_secs_f32(),
+        }
+    }
 }
 
 #[cfg(feature = "metrics")]
@@ -52,48 +97,692 @@ pub struct TunnelInfo {
     pub stats: TunnelStats,
     #[cfg(feature = "metrics")]
     pub metrics: TunnelMetrics,
+    #[cfg(feature = "metrics")]
+    pub health_metrics: HealthMetrics,
     pub close_channel: watch::Sender<bool>,
+    /// Signals tunnel listener to stop accepting new connections
+    pub drain_channel: watch::Sender<bool>,
+    /// Tunnel does not accept new connections and waits for open ones to finish
+    pub draining: bool,
     pub remotes: RemotesMap,
     pub dead_remotes: DeadRemotesMap,
+    pub routes: RoutesMap,
+    /// Host name remotes resolved to all their addresses
+    pub dns_names: DnsNamesMap,
     pub options: TunnelOptions,
     lb_strategy: Box<dyn LBStrategy + Send + Sync + 'static>,
     pub last_selected_index: Option<usize>,
+    /// Open connections per client IP address
+    pub client_conns: fxhash::FxHashMap<IpAddr, usize>,
+    /// Notified when connection or pending remote connection ends, so queued connections can try again
+    pub slot_released: Arc<Notify>,
+    accept_bucket: Option<TokenBucket>,
+    /// Connection rate buckets of clients, in order of first connection
+    pub(super) client_buckets: IndexMap<IpAddr, TokenBucket, fxhash::FxBuildHasher>,
+    pub bandwidth: Arc<TunnelBandwidth>,
+    /// Task running health checks of tunnel remotes
+    pub(crate) health_task: Option<JoinHandle<()>>,
+    /// Task refreshing addresses of host name remotes
+    pub(crate) dns_task: Option<JoinHandle<()>>,
 }
 
 impl TunnelInfo {
     pub fn new(
         close_channel: watch::Sender<bool>,
-        remotes: Vec<SocketSpec>,
+        remotes: Vec<RemoteSpec>,
         options: TunnelOptions,
         state: &State,
-    ) -> Self {
-        let lb_strategy = options.lb_strategy.create();
-        TunnelInfo {
-            stats: TunnelStats::default(),
+    ) -> Result<Self> {
+        let lb_strategy = state.create_strategy(&options.lb_strategy)?;
+        let accept_bucket = options.accept_rate.map(TokenBucket::per_second);
+        let bandwidth = Arc::new(TunnelBandwidth {
+            up: BandwidthLimit::new(options.rate_up, options.tunnel_rate_up),
+            down: BandwidthLimit::new(options.rate_down, options.tunnel_rate_down),
+        });
+        let (drain_channel, _) = watch::channel(false);
+        let active_tier = remotes.iter().map(|r| r.tier).min();
+        Ok(TunnelInfo {
+            stats: TunnelStats {
+                active_tier,
+                ..Default::default()
+            },
             close_channel,
+            drain_channel,
+            draining: false,
             remotes: remotes
                 .into_iter()
-                .map(|k| (k, RemoteInfo::new(state)))
+                .map(|r| {
+                    let info = RemoteInfo::for_spec(state, &r);
+                    (r.socket, info)
+                })
                 .collect(),
             dead_remotes: IndexMap::with_hasher(fxhash::FxBuildHasher::default()),
+            routes: IndexMap::with_hasher(fxhash::FxBuildHasher::default()),
+            dns_names: IndexMap::with_hasher(fxhash::FxBuildHasher::default()),
             lb_strategy,
             options,
             last_selected_index: None,
+            client_conns: fxhash::FxHashMap::default(),
+            slot_released: Arc::new(Notify::new()),
+            accept_bucket,
+            client_buckets: IndexMap::with_hasher(fxhash::FxBuildHasher::default()),
+            bandwidth,
+            health_task: None,
+            dns_task: None,
             #[cfg(feature = "metrics")]
             metrics: TunnelMetrics::new(state.meter()),
-        }
+            #[cfg(feature = "metrics")]
+            health_metrics: HealthMetrics::new(state.meter()),
+        })
     }
 }
 
 impl TunnelInfo {
-    pub fn select_remote(&mut self) -> Result<SocketSpec> {
-        let size = self.remotes.len();
-        let idx = if size == 0 {
-            return Err(Error::NoRemote);
-        } else if size == 1 {
-            0
+    pub(super) fn add_route(&mut self, server_name: String, remote: SocketSpec) -> Result<()> {
+        let route = self.routes.entry(server_name).or_default();
+        if route.contains(&remote) {
+            Err(Error::RemoteExists)
         } else {
-            self.lb_strategy.select_remote(self)?
+            route.push(remote);
+            Ok(())
+        }
+    }
+
+    pub(super) fn remove_route(&mut self, server_name: &str, remote: &SocketSpec) -> Result<()> {
+        let route = self
+            .routes
+            .get_mut(server_name)
+            .ok_or(Error::RouteDoesNotExist)?;
+        let pos = route
+            .iter()
+            .position(|r| r == remote)
+            .ok_or(Error::RemoteDoesNotExist)?;
+        route.remove(pos);
+        if route.is_empty() {
+            self.routes.shift_remove(server_name);
+        }
+        Ok(())
+    }
+
+    /// Indexes of remotes, which can serve connection with given server name.
+    /// If server name is not routed, remotes not bound to any route are used.
+    /// Only active remotes are candidates.
+    pub fn candidates(&self, server_name: Option<&str>) -> Vec<usize> {
+        let candidates: Vec<usize> = match server_name.and_then(|name| self.routes.get(name)) {
+            Some(route) => route
+                .iter()
+                .filter_map(|r| self.remotes.get_index_of(r))
+                .collect(),
+            None if self.routes.is_empty() => (0..self.remotes.len()).collect(),
+            None => self
+                .remotes
+                .keys()
+                .enumerate()
+                .filter(|(_, r)| !self.routes.values().any(|route| route.contains(r)))
+                .map(|(idx, _)| idx)
+                .collect(),
+        };
+        let active: Vec<usize> = candidates
+            .into_iter()
+            .filter(|idx| {
+                self.remotes
+                    .get_index(*idx)
+                    .map(|(_, r)| r.stats.admin_state == RemoteAdminState::Active)
+                    .unwrap_or(false)
+            })
+            .collect();
+        // only lowest tier is used, higher tiers are backups
+        let tier = |idx: &usize| self.remotes.get_index(*idx).map(|(_, r)| r.stats.tier);
+        let min_tier = active.iter().filter_map(tier).min();
+        let active: Vec<usize> = active
+            .into_iter()
+            .filter(|idx| tier(idx) == min_tier)
+            .collect();
+        // ejected remotes are used only if there is nothing else
+        let not_ejected: Vec<usize> = active
+            .iter()
+            .copied()
+            .filter(|idx| {
+                self.remotes
+                    .get_index(*idx)
+                    .map(|(_, r)| !r.stats.is_ejected())
+                    .unwrap_or(false)
+            })
+            .collect();
+        if not_ejected.is_empty() {
+            active
+        } else {
+            not_ejected
+        }
+    }
+
+    /// Updates lowest tier with live active remotes, logs failover to other tier
+    pub(super) fn update_active_tier(&mut self, tunnel: &SocketSpec) {
+        let tier = self
+            .remotes
+            .values()
+            .filter(|r| r.stats.admin_state == RemoteAdminState::Active)
+            .map(|r| r.stats.tier)
+            .min();
+        if tier == self.stats.active_tier {
+            return;
+        }
+        let previous = std::mem::replace(&mut self.stats.active_tier, tier);
+        self.stats.tier_changes += 1;
+        #[cfg(feature = "metrics")]
+        {
+            let delta = tier.map(i64::from).unwrap_or(-1) - previous.map(i64::from).unwrap_or(-1);
+            metric_add!(self.metrics.active_tier => delta; tunnel);
+            metric_add!(self.metrics.tier_changes => 1; tunnel);
+        }
+        match (previous, tier) {
+            (Some(previous), Some(tier)) if tier < previous => {
+                info!(
+                    tunnel=%tunnel,
+                    previous_tier=previous,
+                    tier=tier,
+                    "Tunnel returned to higher priority remotes"
+                )
+            }
+            (_, Some(tier)) => {
+                warn!(
+                    tunnel=%tunnel,
+                    previous_tier=?previous,
+                    tier=tier,
+                    "Tunnel failed over to lower priority remotes"
+                )
+            }
+            (_, None) => {
+                warn!(tunnel=%tunnel, previous_tier=?previous, "Tunnel has no live remotes")
+            }
+        }
+    }
+
+    /// Records outcome of connection to live remote (`None` latency for failed connection)
+    /// and ejects remote, if it's outlier
+    pub(super) fn record_outcome(
+        &mut self,
+        tunnel: &SocketSpec,
+        remote: &SocketSpec,
+        latency: Option<Duration>,
+    ) {
+        let Some(detection) = self.options.outlier.clone() else {
+            return;
+        };
+        let Some(info) = self.remotes.get_mut(remote) else {
+            return;
+        };
+        info.outcomes
+            .record(latency, Duration::from_secs_f32(detection.window));
+        if info.stats.is_ejected() {
+            return;
+        }
+        let others_latency: Vec<Duration> = self
+            .remotes
+            .iter()
+            .filter(|(r, i)| *r != remote && !i.stats.is_ejected())
+            .filter_map(|(_, i)| i.outcomes.mean_latency())
+            .collect();
+        let info = &self.remotes[remote];
+        let Some(reason) = info.outcomes.outlier(&detection, &others_latency) else {
+            return;
+        };
+        let ejected = self
+            .remotes
+            .values()
+            .filter(|r| r.stats.is_ejected())
+            .count();
+        if !detection.can_eject(ejected, self.remotes.len()) {
+            debug!(
+                tunnel=%tunnel,
+                remote=%remote,
+                reason=%reason,
+                "Outlier remote not ejected, too many remotes ejected already"
+            );
+            return;
+        }
+
+        let info = self.remotes.get_mut(remote).expect("remote exists");
+        let now = SystemTime::now();
+        // ejection time grows only for remotes ejected again shortly after previous ejection
+        let max_period = Duration::from_secs_f32(detection.max_ejection_time);
+        if info
+            .stats
+            .ejected_until
+            .map(|t| t + max_period < now)
+            .unwrap_or(true)
+        {
+            info.ejections = 0;
+        }
+        info.ejections += 1;
+        let period = detection.ejection_period(info.ejections);
+        info.stats.ejected_until = Some(now + period);
+        info.stats.total_ejections += 1;
+        info.outcomes.clear();
+        // slow start begins, when ejection ends
+        info.available_since = Some(Instant::now() + period);
+        #[cfg(feature = "metrics")]
+        {
+            metric_add!(info.metrics.ejections => 1; tunnel, remote);
+        }
+        info!(tunnel=%tunnel, remote=%remote, reason=%reason, period=?period, "Outlier remote ejected");
+    }
+
+    /// Slow start factor of remote - ramps from near zero to 1 during `slow-start` period
+    /// after remote became available
+    pub fn slow_start_factor(&self, remote: &RemoteInfo) -> f64 {
+        match (self.options.slow_start, remote.available_since) {
+            (Some(period), Some(since)) if period > 0.0 => {
+                (since.elapsed().as_secs_f64() / period as f64).clamp(MIN_SLOW_START_FACTOR, 1.0)
+            }
+            _ => 1.0,
+        }
+    }
+
+    pub fn lb_strategy(&self) -> &(dyn LBStrategy + Send + Sync) {
+        self.lb_strategy.as_ref()
+    }
+
+    /// Read-only view of remote at given index
+    pub fn remote_view(&self, idx: usize) -> Option<RemoteView<'_>> {
+        self.remotes
+            .get_index(idx)
+            .map(|(remote, info)| RemoteView {
+                index: idx,
+                remote,
+                stats: &info.stats,
+                slow_start_factor: self.slow_start_factor(info),
+                peak_latency: info.peak_latency,
+            })
+    }
+
+    /// Read-only views of candidates
+    pub fn remote_views<'a>(
+        &'a self,
+        candidates: &'a [usize],
+    ) -> impl Iterator<Item = RemoteView<'a>> + 'a {
+        candidates.iter().filter_map(|idx| self.remote_view(*idx))
+    }
+
+    /// Weight of remote at given index for load balancing strategies, which ignore configured weights,
+    /// is 1 or lower during slow start
+    pub fn effective_weight(&self, idx: usize) -> f64 {
+        self.remotes
+            .get_index(idx)
+            .map(|(_, r)| self.slow_start_factor(r))
+            .unwrap_or(0.0)
+    }
+
+    /// Configured weight of remote at given index, reduced during slow start
+    pub fn weighted_effective_weight(&self, idx: usize) -> f64 {
+        self.remotes
+            .get_index(idx)
+            .map(|(_, r)| r.stats.weight as f64 * self.slow_start_factor(r))
+            .unwrap_or(0.0)
+    }
+
+    /// Remote either alive or dead
+    pub(super) fn remote_info_mut(&mut self, remote: &SocketSpec) -> Option<&mut RemoteInfo> {
+        self.remotes.get_mut(remote).or_else(|| {
+            self.dead_remotes
+                .get_mut(remote)
+                .map(|dead| &mut dead.remote)
+        })
+    }
+
+    /// Removes remote (alive or dead) and its routes
+    pub(super) fn remove_remote(&mut self, remote: &SocketSpec) -> Option<RemoteInfo> {
+        let removed = self.remotes.remove(remote).or_else(|| {
+            self.dead_remotes.remove(remote).map(|mut dead| {
+                if let Some(handle) = dead.join_handle.take() {
+                    handle.abort()
+                }
+                dead.remote
+            })
+        })?;
+        self.routes.retain(|_, route| {
+            route.retain(|r| r != remote);
+            !route.is_empty()
+        });
+        Some(removed)
+    }
+
+    /// Changes admin state of remote, draining remote without connections is disabled at once
+    pub(super) fn set_remote_state(
+        &mut self,
+        remote: &SocketSpec,
+        admin_state: RemoteAdminState,
+    ) -> Result<()> {
+        let info = self
+            .remote_info_mut(remote)
+            .ok_or(Error::RemoteDoesNotExist)?;
+        info.stats.admin_state = admin_state;
+        info.remove_when_drained = false;
+        self.check_remote_drained(remote);
+        Ok(())
+    }
+
+    /// Replaces whole set of remotes, remotes which stay keep their stats,
+    /// dropped live remotes are drained, dropped dead remotes are removed at once.
+    /// Addresses of resolved host name stay, if the name stays.
+    pub(super) fn replace_remotes(
+        &mut self,
+        tunnel: &SocketSpec,
+        remotes: Vec<RemoteSpec>,
+        state: &State,
+    ) -> RemotesChange {
+        let mut change = RemotesChange::default();
+        let listed = |r: &SocketSpec| remotes.iter().any(|spec| spec.socket == *r);
+        self.dns_names.retain(|name, _| listed(name));
+        let dropped: Vec<SocketSpec> = self
+            .remotes
+            .keys()
+            .chain(self.dead_remotes.keys())
+            .filter(|r| {
+                !listed(r)
+                    && !r
+                        .resolved_name()
+                        .map(|name| self.dns_names.contains_key(&name))
+                        .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        for remote in dropped {
+            if self.drop_remote(&remote) {
+                change.draining += 1;
+            } else {
+                change.removed += 1;
+            }
+        }
+        for spec in remotes {
+            let members = if let Some(name) = self.dns_names.get_mut(&spec.socket) {
+                *name = spec.clone();
+                self.dns_members(&spec.socket)
+            } else if self.add_or_keep_remote(&spec, state) {
+                change.added += 1;
+                continue;
+            } else {
+                vec![spec.socket.clone()]
+            };
+            for member in members {
+                if let Some(info) = self.remote_info_mut(&member) {
+                    info.stats.weight = spec.weight;
+                    info.stats.tier = spec.tier;
+                    change.kept += 1;
+                }
+            }
+        }
+        self.update_active_tier(tunnel);
+        change
+    }
+
+    /// Live remote is drained, dead remote is removed at once, returns true if remote is still draining
+    fn drop_remote(&mut self, remote: &SocketSpec) -> bool {
+        if self.remotes.contains_key(remote) {
+            // remote can be removed at once, if it has no connections
+            let _ = self.remove_remote_gracefully(remote);
+            self.remotes.contains_key(remote)
+        } else {
+            self.remove_remote(remote);
+            false
+        }
+    }
+
+    /// Adds new remote, existing remote pending removal is activated again, returns true if remote was added
+    fn add_or_keep_remote(&mut self, spec: &RemoteSpec, state: &State) -> bool {
+        match self.remote_info_mut(&spec.socket) {
+            Some(info) => {
+                if info.remove_when_drained {
+                    info.remove_when_drained = false;
+                    info.stats.admin_state = RemoteAdminState::Active;
+                }
+                false
+            }
+            None => {
+                let mut info = RemoteInfo::for_spec(state, spec);
+                info.became_available();
+                self.remotes.insert(spec.socket.clone(), info);
+                true
+            }
+        }
+    }
+
+    /// Stops tracking of host name and drops all its resolved addresses,
+    /// returns `None` if the name is not tracked or the name itself is still remote
+    pub(super) fn remove_dns_name(&mut self, name: &SocketSpec) -> Option<RemoteStats> {
+        let spec = self.dns_names.shift_remove(name)?;
+        for member in self.dns_members(name) {
+            if member != *name {
+                self.drop_remote(&member);
+            }
+        }
+        if self.remote_info_mut(name).is_some() {
+            return None;
+        }
+        Some(RemoteStats {
+            weight: spec.weight,
+            tier: spec.tier,
+            ..Default::default()
+        })
+    }
+
+    /// Remotes belonging to host name - the name itself (until it's resolved) and its resolved addresses
+    pub(super) fn dns_members(&self, name: &SocketSpec) -> Vec<SocketSpec> {
+        self.remotes
+            .keys()
+            .chain(self.dead_remotes.keys())
+            .filter(|r| *r == name || r.resolved_name().as_ref() == Some(name))
+            .cloned()
+            .collect()
+    }
+
+    /// Starts tracking of new host name remotes, names without any remote left are forgotten,
+    /// returns names to resolve
+    pub(super) fn track_dns_names(&mut self) -> Vec<SocketSpec> {
+        let new_names: Vec<RemoteSpec> = self
+            .remotes
+            .iter()
+            .chain(self.dead_remotes.iter().map(|(r, dead)| (r, &dead.remote)))
+            .filter(|(r, _)| r.is_host_name() && !self.dns_names.contains_key(*r))
+            .map(|(r, info)| RemoteSpec {
+                socket: r.clone(),
+                weight: info.stats.weight,
+                tier: info.stats.tier,
+            })
+            .collect();
+        for spec in new_names {
+            self.dns_names.insert(spec.socket.clone(), spec);
+        }
+        let orphans: Vec<SocketSpec> = self
+            .dns_names
+            .keys()
+            .filter(|name| self.dns_members(name).is_empty())
+            .cloned()
+            .collect();
+        for name in orphans {
+            self.dns_names.shift_remove(&name);
+        }
+        self.dns_names.keys().cloned().collect()
+    }
+
+    /// Updates remotes of host name to resolved addresses - new addresses are added,
+    /// addresses no longer resolved (and not yet resolved name itself) are dropped
+    pub(super) fn update_resolved(
+        &mut self,
+        tunnel: &SocketSpec,
+        name: &SocketSpec,
+        addresses: &[IpAddr],
+        state: &State,
+    ) -> RemotesChange {
+        let mut change = RemotesChange::default();
+        let Some(template) = self.dns_names.get(name).cloned() else {
+            return change;
+        };
+        let mut resolved: Vec<SocketSpec> = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let Some(remote) = name.with_address(*address) else {
+                continue;
+            };
+            if !resolved.contains(&remote) {
+                resolved.push(remote)
+            }
+        }
+        for member in self.dns_members(name) {
+            if !resolved.contains(&member) {
+                if self.drop_remote(&member) {
+                    change.draining += 1;
+                } else {
+                    change.removed += 1;
+                }
+            }
+        }
+        for socket in resolved {
+            let spec = RemoteSpec {
+                socket,
+                ..template.clone()
+            };
+            if self.add_or_keep_remote(&spec, state) {
+                change.added += 1;
+            } else {
+                change.kept += 1;
+            }
+        }
+        if change.added + change.draining + change.removed > 0 {
+            info!(tunnel=%tunnel, name=%name, change=%change, "Addresses of remote host name changed");
+            self.update_active_tier(tunnel);
+        }
+        change
+    }
+
+    /// Remote is removed after its open connections finish, returns remote stats at time of request
+    pub(super) fn remove_remote_gracefully(&mut self, remote: &SocketSpec) -> Result<RemoteStats> {
+        let info = self
+            .remote_info_mut(remote)
+            .ok_or(Error::RemoteDoesNotExist)?;
+        info.stats.admin_state = RemoteAdminState::Draining;
+        info.remove_when_drained = true;
+        let stats = info.stats.clone();
+        self.check_remote_drained(remote);
+        Ok(stats)
+    }
+
+    /// Records health check of remote, moves remote between live and dead remotes,
+    /// when rise or fall threshold is reached
+    pub(super) fn health_check_result(
+        &mut self,
+        tunnel: &SocketSpec,
+        remote: &SocketSpec,
+        ok: bool,
+        check: &HealthCheck,
+    ) {
+        let Some(info) = self.remote_info_mut(remote) else {
+            return;
+        };
+        info.stats.health_checks += 1;
+        if ok {
+            info.health_successes += 1;
+            info.health_failures = 0;
+        } else {
+            info.stats.health_check_failures += 1;
+            info.health_failures += 1;
+            info.health_successes = 0;
+        }
+        let (successes, failures) = (info.health_successes, info.health_failures);
+        #[cfg(feature = "metrics")]
+        {
+            metric_add!(self.health_metrics.checks => 1; tunnel, remote);
+            if !ok {
+                metric_add!(self.health_metrics.failures => 1; tunnel, remote);
+            }
+        }
+
+        let interval = Duration::from_secs_f32(check.interval);
+        if failures >= check.fall && self.remotes.contains_key(remote) {
+            let rec = self.remotes.remove(remote).expect("remote exists");
+            self.dead_remotes.insert(
+                remote.clone(),
+                DeadRemote {
+                    remote: rec,
+                    join_handle: None,
+                    retry_interval: interval,
+                    next_check: SystemTime::now() + interval,
+                },
+            );
+            #[cfg(feature = "metrics")]
+            {
+                metric_add!(self.health_metrics.remotes_down => 1; tunnel, remote);
+            }
+            info!(tunnel=%tunnel, remote=%remote, "Remote failed health checks, moved to dead remotes");
+            self.update_active_tier(tunnel);
+        } else if let Some(dead) = self.dead_remotes.get_mut(remote) {
+            dead.next_check = SystemTime::now() + interval;
+            if successes >= check.rise {
+                let mut dead = self.dead_remotes.remove(remote).expect("remote exists");
+                if let Some(handle) = dead.join_handle.take() {
+                    handle.abort()
+                }
+                dead.remote.remote_recovered(tunnel, remote);
+                self.remotes.insert(remote.clone(), dead.remote);
+                #[cfg(feature = "metrics")]
+                {
+                    metric_add!(self.health_metrics.remotes_up => 1; tunnel, remote);
+                }
+                info!(tunnel=%tunnel, remote=%remote, "Remote passed health checks, moved to live remotes");
+                self.update_active_tier(tunnel);
+            }
+        }
+    }
+
+    /// Draining remote without open connections is either removed or disabled
+    pub(super) fn check_remote_drained(&mut self, remote: &SocketSpec) {
+        let remove = match self.remote_info_mut(remote) {
+            Some(info)
+                if info.stats.admin_state == RemoteAdminState::Draining
+                    && info.stats.streams_open + info.stats.streams_pending == 0 =>
+            {
+                info.stats.admin_state = RemoteAdminState::Disabled;
+                info.remove_when_drained
+            }
+            _ => false,
+        };
+        if remove {
+            self.remove_remote(remote);
+            debug!(remote=%remote, "Drained remote removed from tunnel");
+        }
+    }
+
+    /// Remote has reached `max_conns_per_remote` limit
+    fn is_saturated(&self, remote: &RemoteInfo) -> bool {
+        self.options
+            .max_conns_per_remote
+            .map(|max| remote.stats.streams_open + remote.stats.streams_pending >= max)
+            .unwrap_or(false)
+    }
+
+    pub fn select_remote(
+        &mut self,
+        tunnel: &SocketSpec,
+        ctx: &ConnectionContext,
+    ) -> Result<SocketSpec> {
+        self.update_active_tier(tunnel);
+        let all_candidates = self.candidates(ctx.server_name);
+        let candidates: Vec<usize> = all_candidates
+            .iter()
+            .copied()
+            .filter(|idx| {
+                self.remotes
+                    .get_index(*idx)
+                    .map(|(_, r)| !self.is_saturated(r))
+                    .unwrap_or(false)
+            })
+            .collect();
+        let idx = match candidates.len() {
+            0 if !all_candidates.is_empty() => {
+                return Err(Error::ConnectionLimit("all remotes are saturated".into()))
+            }
+            0 => return Err(Error::NoRemote),
+            1 => candidates[0],
+            _ => self.lb_strategy.select_remote(self, &candidates, ctx)?,
         };
         self.last_selected_index = Some(idx);
         self.remotes
@@ -103,8 +792,141 @@ impl TunnelInfo {
             .cloned()
     }
 
-    pub(super) fn client_connected(&mut self, tunnel: &SocketSpec, _client: &SocketAddr) {
+    /// Checks tunnel wide and per client IP connection limits for new client
+    pub(super) fn check_client_limits(&self, client: &SocketAddr) -> Result<()> {
+        if self.draining {
+            return Err(Error::TunnelDraining);
+        }
+        if let Some(max) = self.options.max_conns {
+            if self.stats.streams_open >= max {
+                return Err(Error::ConnectionLimit(
+                    "tunnel connections limit reached".into(),
+                ));
+            }
+        }
+        if let Some(max) = self.options.max_conns_per_client {
+            if self.client_conns.get(&client.ip()).copied().unwrap_or(0) >= max {
+                return Err(Error::ConnectionLimit(format!(
+                    "connections limit reached for client {}",
+                    client.ip()
+                )));
+            }
+        }
+        Ok(())
+    }
 
+    /// Checks client address against tunnel access lists, refused client is counted
+    pub(super) fn check_access(&mut self, tunnel: &SocketSpec, client: &SocketAddr) -> Result<()> {
+        if access_permitted(&self.options.allow, &self.options.deny, &client.ip()) {
+            Ok(())
+        } else {
+            self.stats.denied += 1;
+            #[cfg(feature = "metrics")]
+            {
+                metric_add!(self.metrics.denied => 1; tunnel);
+            }
+            Err(Error::AccessDenied(client.ip().to_string()))
+        }
+    }
+
+    /// Checks client connection rate and tunnel accept rate, dropped connection is counted
+    pub(super) fn check_client_rate(
+        &mut self,
+        tunnel: &SocketSpec,
+        client: &SocketAddr,
+    ) -> Result<()> {
+        let now = Instant::now();
+        if let Some(rate) = self.options.client_rate {
+            if self.client_buckets.len() >= MAX_CLIENT_BUCKETS
+                && !self.client_buckets.contains_key(&client.ip())
+            {
+                self.evict_client_buckets(now);
+            }
+            let bucket = self
+                .client_buckets
+                .entry(client.ip())
+                .or_insert_with(|| TokenBucket::per_second(rate));
+            if !bucket.try_take(now) {
+                self.rate_limited(tunnel);
+                return Err(Error::RateLimited(format!(
+                    "connection rate exceeded for client {}",
+                    client.ip()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn check_accept_rate(&mut self, tunnel: &SocketSpec) -> Result<()> {
+        if let Some(bucket) = self.accept_bucket.as_mut() {
+            if !bucket.try_take(Instant::now()) {
+                self.rate_limited(tunnel);
+                return Err(Error::RateLimited("tunnel accept rate exceeded".into()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Full buckets are same as new ones, so they can be forgotten, if there are still too many buckets,
+    /// oldest ones are removed - map is reduced to half, so eviction does not run on every new client
+    fn evict_client_buckets(&mut self, now: Instant) {
+        self.client_buckets.retain(|_, bucket| !bucket.is_full(now));
+        let keep = MAX_CLIENT_BUCKETS / 2;
+        if self.client_buckets.len() > keep {
+            let excess = self.client_buckets.len() - keep;
+            self.client_buckets.drain(..excess);
+        }
+    }
+
+    fn rate_limited(&mut self, tunnel: &SocketSpec) {
+        self.stats.rate_limited += 1;
+        #[cfg(feature = "metrics")]
+        {
+            metric_add!(self.metrics.rate_limited => 1; tunnel);
+        }
+    }
+
+    pub(super) fn set_rate_limits(&mut self, limits: &RateLimits) {
+        self.options.update_rate_limits(limits);
+        self.apply_rate_limits();
+    }
+
+    /// Replaces tunnel options, new options apply only to new connections, returns previous options
+    pub(super) fn set_options(
+        &mut self,
+        options: TunnelOptions,
+        state: &State,
+    ) -> Result<TunnelOptions> {
+        if options.lb_strategy != self.options.lb_strategy {
+            self.lb_strategy = state.create_strategy(&options.lb_strategy)?;
+            self.last_selected_index = None;
+        }
+        let previous = std::mem::replace(&mut self.options, options);
+        self.apply_rate_limits();
+        Ok(previous)
+    }
+
+    fn apply_rate_limits(&mut self) {
+        self.accept_bucket = self.options.accept_rate.map(TokenBucket::per_second);
+        self.client_buckets.clear();
+        self.bandwidth
+            .up
+            .set(self.options.rate_up, self.options.tunnel_rate_up);
+        self.bandwidth
+            .down
+            .set(self.options.rate_down, self.options.tunnel_rate_down);
+    }
+
+    pub(super) fn client_rejected(&mut self, tunnel: &SocketSpec) {
+        self.stats.rejected += 1;
+        #[cfg(feature = "metrics")]
+        {
+            metric_add!(self.metrics.rejected => 1; tunnel);
+        }
+    }
+
+    pub(super) fn client_connected(&mut self, tunnel: &SocketSpec, client: &SocketAddr) {
+        *self.client_conns.entry(client.ip()).or_insert(0) += 1;
         self.stats.total_connections += 1;
         self.stats.streams_open += 1;
 
@@ -116,7 +938,19 @@ impl TunnelInfo {
         }
     }
 
-    pub (super) fn client_disconnected(&mut self, tunnel: &SocketSpec, _remote: Option<&SocketSpec>, _client: &SocketAddr) {
+    pub(super) fn client_disconnected(
+        &mut self,
+        tunnel: &SocketSpec,
+        _remote: Option<&SocketSpec>,
+        client: &SocketAddr,
+    ) {
+        if let Some(conns) = self.client_conns.get_mut(&client.ip()) {
+            *conns -= 1;
+            if *conns == 0 {
+                self.client_conns.remove(&client.ip());
+            }
+        }
+        self.slot_released.notify_waiters();
         self.stats.streams_open -= 1;
         #[cfg(feature="metrics")]
         {
@@ -132,6 +966,25 @@ impl TunnelInfo {
         }
     }
 
+    pub(super) fn stream_timed_out(&mut self, tunnel: &SocketSpec, reason: StreamTimeout) {
+        match reason {
+            StreamTimeout::Idle => {
+                self.stats.idle_timeouts += 1;
+                #[cfg(feature = "metrics")]
+                {
+                    metric_add!(self.metrics.idle_timeouts => 1; tunnel);
+                }
+            }
+            StreamTimeout::Lifetime => {
+                self.stats.lifetime_timeouts += 1;
+                #[cfg(feature = "metrics")]
+                {
+                    metric_add!(self.metrics.lifetime_timeouts => 1; tunnel);
+                }
+            }
+        }
+    }
+
     pub(super) fn update_moved_bytes(&mut self, sent: bool, bytes: u64, tunnel: &SocketSpec) {
         if sent {
             self.stats.bytes_sent += bytes;
@@ -152,6 +1005,21 @@ impl TunnelInfo {
 #[derive(Debug)]
 pub struct RemoteInfo {
     pub stats: RemoteStats,
+    /// Remote is removed from tunnel, when it's drained
+    pub remove_when_drained: bool,
+    /// Consequent successful health checks
+    pub health_successes: u32,
+    /// Consequent failed health checks
+    pub health_failures: u32,
+    /// When remote was added to running tunnel or recovered, for slow start
+    pub available_since: Option<Instant>,
+    /// Recent connection outcomes for outlier detection
+    pub outcomes: OutcomeWindow,
+    /// Consequent ejections by outlier detection
+    pub ejections: u32,
+    /// Connect latency in milliseconds, which jumps up to peaks and decays slowly
+    pub peak_latency: f64,
+    peak_latency_updated: Option<Instant>,
     #[cfg(feature = "metrics")]
     pub metrics: RemoteMetrics,
 }
@@ -159,7 +1027,18 @@ pub struct RemoteInfo {
 impl RemoteInfo {
     pub fn new(_state: &State) -> Self {
         RemoteInfo {
-            stats: RemoteStats::default(),
+            stats: RemoteStats {
+                weight: DEFAULT_REMOTE_WEIGHT,
+                ..Default::default()
+            },
+            remove_when_drained: false,
+            health_successes: 0,
+            health_failures: 0,
+            available_since: None,
+            outcomes: OutcomeWindow::default(),
+            ejections: 0,
+            peak_latency: 0.0,
+            peak_latency_updated: None,
             #[cfg(feature = "metrics")]
             metrics: RemoteMetrics::new(_state.meter()),
         }
@@ -173,7 +1052,14 @@ impl RemoteInfo {
         }
     }
 
-    pub(crate) fn remote_connected(&mut self, tunnel: &SocketSpec, remote: &SocketSpec, _client_addr: &SocketAddr) {
+    pub(crate) fn remote_connected(
+        &mut self,
+        tunnel: &SocketSpec,
+        remote: &SocketSpec,
+        _client_addr: &SocketAddr,
+        connect_time: Duration,
+    ) {
+        self.update_latency(connect_time);
         #[cfg(feature = "metrics")]
                 {
                     metric_add!(
@@ -213,7 +1099,38 @@ impl RemoteInfo {
         }
     }
 
+    /// Remote info with weight and tier from remote spec
+    pub fn for_spec(state: &State, spec: &RemoteSpec) -> Self {
+        let mut info = RemoteInfo::new(state);
+        info.stats.weight = spec.weight;
+        info.stats.tier = spec.tier;
+        info
+    }
+
+    fn update_latency(&mut self, connect_time: Duration) {
+        let sample = connect_time.as_secs_f64() * 1000.0;
+        self.stats.connect_latency_ewma = Some(match self.stats.connect_latency_ewma {
+            Some(ewma) => ewma + LATENCY_EWMA_ALPHA * (sample - ewma),
+            None => sample,
+        });
+        let now = Instant::now();
+        self.peak_latency = match self.peak_latency_updated {
+            Some(updated) if sample < self.peak_latency => {
+                let w = (-now.duration_since(updated).as_secs_f64() / PEAK_LATENCY_DECAY).exp();
+                self.peak_latency * w + sample * (1.0 - w)
+            }
+            _ => sample,
+        };
+        self.peak_latency_updated = Some(now);
+    }
+
+    /// Remote starts to receive connections, slow start begins
+    pub(crate) fn became_available(&mut self) {
+        self.available_since = Some(Instant::now());
+    }
+
     pub(crate) fn remote_recovered(&mut self, tunnel: &SocketSpec, remote: &SocketSpec) {
+        self.became_available();
 
         #[cfg(feature = "metrics")]
         {
@@ -0,0 +1,223 @@
+//! Outlier detection - connection outcomes of remotes are tracked in sliding time window,
+//! remote with too high error rate (or connect latency much higher then other remotes)
+//! is ejected from load balancing for some time.
+
+use std::{collections::VecDeque, fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Maximum of outcomes remembered for one remote
+const MAX_OUTCOMES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutlierDetection {
+    /// Seconds of sliding window, in which connection outcomes are evaluated
+    pub window: f32,
+    /// Percentage of failed connections in window, which ejects remote
+    pub error_rate: f32,
+    /// Minimum of connections in window, before remote is evaluated
+    pub min_requests: usize,
+    /// Remote is ejected, if its average connect latency is more then this multiple of median of other remotes
+    pub latency_factor: Option<f32>,
+    /// Seconds of first ejection, each consequent ejection is longer by this time
+    pub ejection_time: f32,
+    /// Maximum of ejection time in seconds
+    pub max_ejection_time: f32,
+    /// Maximum percentage of tunnel remotes, which can be ejected at the same time
+    pub max_ejected: f32,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            window: 30.0,
+            error_rate: 50.0,
+            min_requests: 10,
+            latency_factor: None,
+            ejection_time: 30.0,
+            max_ejection_time: 300.0,
+            max_ejected: 50.0,
+        }
+    }
+}
+
+impl Display for OutlierDetection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "outlier-window={}, outlier-error-rate={}, outlier-min-requests={}, outlier-ejection-time={}, outlier-max-ejection-time={}, outlier-max-ejected={}",
+            self.window,
+            self.error_rate,
+            self.min_requests,
+            self.ejection_time,
+            self.max_ejection_time,
+            self.max_ejected
+        )?;
+        if let Some(factor) = self.latency_factor {
+            write!(f, ", outlier-latency={}", factor)?;
+        }
+        Ok(())
+    }
+}
+
+impl OutlierDetection {
+    /// Ejection time for n-th consequent ejection
+    pub fn ejection_period(&self, ejections: u32) -> Duration {
+        Duration::from_secs_f32(
+            (self.ejection_time * ejections.max(1) as f32).min(self.max_ejection_time),
+        )
+    }
+
+    /// Can one more remote be ejected, when `ejected` of `total` remotes are already ejected
+    pub fn can_eject(&self, ejected: usize, total: usize) -> bool {
+        (ejected + 1) as f32 * 100.0 <= total as f32 * self.max_ejected
+    }
+}
+
+/// Why remote was ejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutlierReason {
+    ErrorRate,
+    Latency,
+}
+
+impl Display for OutlierReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutlierReason::ErrorRate => write!(f, "error rate"),
+            OutlierReason::Latency => write!(f, "latency"),
+        }
+    }
+}
+
+/// Connection outcomes of remote in sliding window, `None` latency is failed connection
+#[derive(Debug, Default)]
+pub struct OutcomeWindow {
+    outcomes: VecDeque<(Instant, Option<Duration>)>,
+}
+
+impl OutcomeWindow {
+    pub fn record(&mut self, latency: Option<Duration>, window: Duration) {
+        let now = Instant::now();
+        self.outcomes.push_back((now, latency));
+        while self.outcomes.len() > MAX_OUTCOMES
+            || self
+                .outcomes
+                .front()
+                .map(|(t, _)| now.duration_since(*t) > window)
+                .unwrap_or(false)
+        {
+            self.outcomes.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.outcomes.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    /// Percentage of failed connections
+    pub fn error_rate(&self) -> f32 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        let errors = self.outcomes.iter().filter(|(_, l)| l.is_none()).count();
+        errors as f32 * 100.0 / self.outcomes.len() as f32
+    }
+
+    /// Average latency of successful connections
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let latencies: Vec<Duration> = self.outcomes.iter().filter_map(|(_, l)| *l).collect();
+        if latencies.is_empty() {
+            None
+        } else {
+            Some(latencies.iter().sum::<Duration>() / latencies.len() as u32)
+        }
+    }
+
+    /// Evaluates window against detection thresholds, `others_latency` are average latencies of other remotes
+    pub fn outlier(
+        &self,
+        detection: &OutlierDetection,
+        others_latency: &[Duration],
+    ) -> Option<OutlierReason> {
+        if self.outcomes.len() < detection.min_requests {
+            return None;
+        }
+        if self.error_rate() >= detection.error_rate {
+            return Some(OutlierReason::ErrorRate);
+        }
+        match (
+            detection.latency_factor,
+            self.mean_latency(),
+            median(others_latency),
+        ) {
+            (Some(factor), Some(latency), Some(median))
+                if latency.as_secs_f32() > median.as_secs_f32() * factor =>
+            {
+                Some(OutlierReason::Latency)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn median(values: &[Duration]) -> Option<Duration> {
+    let mut values = values.to_vec();
+    values.sort();
+    match values.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((values[n / 2 - 1] + values[n / 2]) / 2),
+        n => Some(values[n / 2]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_outlier_window() {
+        let detection = OutlierDetection {
+            min_requests: 5,
+            latency_factor: Some(3.0),
+            ..Default::default()
+        };
+        let window = Duration::from_secs(30);
+        let ms = Duration::from_millis;
+        let mut outcomes = OutcomeWindow::default();
+        for i in 0..4 {
+            outcomes.record(if i % 2 == 0 { None } else { Some(ms(10)) }, window);
+        }
+        // not enough connections yet
+        assert_eq!(None, outcomes.outlier(&detection, &[]));
+        outcomes.record(Some(ms(10)), window);
+        assert_eq!(40.0, outcomes.error_rate());
+        assert_eq!(None, outcomes.outlier(&detection, &[ms(5), ms(20)]));
+        assert_eq!(
+            Some(OutlierReason::Latency),
+            outcomes.outlier(&detection, &[ms(1), ms(2), ms(50)])
+        );
+        outcomes.record(None, window);
+        assert_eq!(
+            Some(OutlierReason::ErrorRate),
+            outcomes.outlier(&detection, &[])
+        );
+
+        assert_eq!(Duration::from_secs(30), detection.ejection_period(1));
+        assert_eq!(Duration::from_secs(90), detection.ejection_period(3));
+        assert_eq!(Duration::from_secs(300), detection.ejection_period(20));
+        assert!(detection.can_eject(0, 2));
+        assert!(!detection.can_eject(1, 2));
+        assert!(!detection.can_eject(0, 1));
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// Token bucket - tokens are refilled continuously with `rate` tokens per second,
+/// up to `burst` tokens
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Bucket starts full
+    pub fn new(rate: f64, burst: f64) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Bucket allowing burst of one second worth of tokens
+    pub fn per_second(rate: f32) -> Self {
+        let rate = rate as f64;
+        TokenBucket::new(rate, rate.max(1.0))
+    }
+
+    /// Changes rate, tokens already in bucket are kept
+    pub fn set_rate(&mut self, rate: f32, now: Instant) {
+        let rate = rate as f64;
+        if rate != self.rate {
+            self.refill(now);
+            self.rate = rate;
+            self.burst = rate.max(1.0);
+            self.tokens = self.tokens.min(self.burst);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Takes one token, if available
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes tokens, even if there is not enough of them,
+    /// returns time until this debt is paid back
+    pub fn take(&mut self, tokens: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= tokens;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Bucket is full, so it's same as new one
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+/// Bandwidth limits (bytes per second) in one direction of tunnel,
+/// shared by all tunnel connections, so they can be changed on live connections
+#[derive(Debug, Default)]
+pub struct BandwidthLimit {
+    /// Limit for each connection
+    connection_rate: Mutex<Option<f32>>,
+    /// Limit for all connections together
+    tunnel_bucket: Mutex<Option<TokenBucket>>,
+}
+
+/// Only positive rate is a limit
+fn positive(rate: Option<f32>) -> Option<f32> {
+    rate.filter(|r| *r > 0.0)
+}
+
+impl BandwidthLimit {
+    pub fn new(connection_rate: Option<f32>, tunnel_rate: Option<f32>) -> Self {
+        let (connection_rate, tunnel_rate) = (positive(connection_rate), positive(tunnel_rate));
+        BandwidthLimit {
+            connection_rate: Mutex::new(connection_rate),
+            tunnel_bucket: Mutex::new(tunnel_rate.map(TokenBucket::per_second)),
+        }
+    }
+
+    pub fn set(&self, connection_rate: Option<f32>, tunnel_rate: Option<f32>) {
+        let (connection_rate, tunnel_rate) = (positive(connection_rate), positive(tunnel_rate));
+        *self.connection_rate.lock() = connection_rate;
+        let mut tunnel_bucket = self.tunnel_bucket.lock();
+        match (tunnel_bucket.as_mut(), tunnel_rate) {
+            (Some(bucket), Some(rate)) => bucket.set_rate(rate, Instant::now()),
+            (_, rate) => *tunnel_bucket = rate.map(TokenBucket::per_second),
+        }
+    }
+
+    pub fn connection_rate(&self) -> Option<f32> {
+        *self.connection_rate.lock()
+    }
+
+    /// Charges bytes to tunnel limit, returns how long connection should wait before it can continue
+    pub fn take_tunnel(&self, bytes: usize, now: Instant) -> Duration {
+        self.tunnel_bucket
+            .lock()
+            .as_mut()
+            .map(|bucket| bucket.take(bytes as f64, now))
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Bandwidth limits of tunnel, up is from client to remote, down from remote to client
+#[derive(Debug, Default)]
+pub struct TunnelBandwidth {
+    pub up: BandwidthLimit,
+    pub down: BandwidthLimit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::per_second(2.0);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(!bucket.try_take(start + Duration::from_millis(400)));
+        assert!(bucket.try_take(start + Duration::from_millis(500)));
+        assert!(!bucket.is_full(start + Duration::from_millis(500)));
+        assert!(bucket.is_full(start + Duration::from_secs(10)));
+        assert!(bucket.try_take(start + Duration::from_secs(10)));
+        assert!(bucket.try_take(start + Duration::from_secs(10)));
+        assert!(!bucket.try_take(start + Duration::from_secs(10)));
+
+        let mut slow = TokenBucket::per_second(0.5);
+        assert!(slow.try_take(start));
+        assert!(!slow.try_take(start + Duration::from_secs(1)));
+        assert!(slow.try_take(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_bandwidth_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::per_second(1000.0);
+        assert_eq!(Duration::ZERO, bucket.take(1000.0, start));
+        assert_eq!(Duration::from_millis(500), bucket.take(500.0, start));
+        // debt is paid after 0.5s
+        assert_eq!(
+            Duration::ZERO,
+            bucket.take(0.0, start + Duration::from_millis(500))
+        );
+        bucket.set_rate(100.0, start + Duration::from_millis(500));
+        assert_eq!(
+            Duration::from_secs(1),
+            bucket.take(100.0, start + Duration::from_millis(500))
+        );
+
+        let limit = BandwidthLimit::new(Some(10.0), None);
+        assert_eq!(Some(10.0), limit.connection_rate());
+        assert_eq!(Duration::ZERO, limit.take_tunnel(1_000_000, start));
+        limit.set(None, Some(1000.0));
+        assert_eq!(None, limit.connection_rate());
+        assert!(limit.take_tunnel(2000, Instant::now()) > Duration::from_millis(900));
+
+        // zero rate is no limit
+        let limit = BandwidthLimit::new(Some(0.0), Some(0.0));
+        assert_eq!(None, limit.connection_rate());
+        assert_eq!(Duration::ZERO, limit.take_tunnel(1_000_000, start));
+        limit.set(Some(-1.0), Some(0.0));
+        assert_eq!(Duration::ZERO, limit.take_tunnel(1_000_000, start));
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::SystemTime;
+use std::{collections::BTreeMap, fmt::Display, time::SystemTime};
 
 use opentelemetry::metrics::{self, Meter};
 use serde::{Serialize, Serializer};
@@ -10,6 +10,15 @@ pub struct TunnelStats {
     pub bytes_received: u64,
     pub total_connections: u64,
     pub errors: u64,
+    pub idle_timeouts: u64,
+    pub lifetime_timeouts: u64,
+    pub rejected: u64,
+    pub rate_limited: u64,
+    pub denied: u64,
+    /// Lowest priority tier with live remotes, connections go only to this tier
+    pub active_tier: Option<u8>,
+    /// Number of failovers to backup tier and back
+    pub tier_changes: u64,
 }
 
 #[cfg(feature = "metrics")]
@@ -20,6 +29,13 @@ pub struct TunnelMetrics {
     pub bytes_received: metrics::Counter<u64>,
     pub total_connections: metrics::Counter<u64>,
     pub errors: metrics::Counter<u64>,
+    pub idle_timeouts: metrics::Counter<u64>,
+    pub lifetime_timeouts: metrics::Counter<u64>,
+    pub rejected: metrics::Counter<u64>,
+    pub rate_limited: metrics::Counter<u64>,
+    pub denied: metrics::Counter<u64>,
+    pub active_tier: metrics::UpDownCounter<i64>,
+    pub tier_changes: metrics::Counter<u64>,
 }
 
 #[cfg(feature = "metrics")]
@@ -46,12 +62,69 @@ impl TunnelMetrics {
                 .u64_counter("tunnel_errors")
                 .with_description("total number of errors per whole tunnel life")
                 .init(),
+            idle_timeouts: meter
+                .u64_counter("tunnel_idle_timeouts")
+                .with_description("number of connections closed for inactivity")
+                .init(),
+            lifetime_timeouts: meter
+                .u64_counter("tunnel_lifetime_timeouts")
+                .with_description("number of connections closed after maximum lifetime")
+                .init(),
+            rejected: meter
+                .u64_counter("tunnel_rejected_connections")
+                .with_description("number of connections rejected due to connection limits")
+                .init(),
+            rate_limited: meter
+                .u64_counter("tunnel_rate_limited_connections")
+                .with_description(
+                    "number of connections dropped due to accept or client rate limit",
+                )
+                .init(),
+            denied: meter
+                .u64_counter("tunnel_denied_connections")
+                .with_description("number of connections refused by client access lists")
+                .init(),
+            active_tier: meter
+                .i64_up_down_counter("tunnel_active_tier")
+                .with_description("priority tier of remotes currently used, 0 is primary")
+                .init(),
+            tier_changes: meter
+                .u64_counter("tunnel_tier_changes")
+                .with_description("number of failovers between priority tiers of remotes")
+                .init(),
+        }
+    }
+}
+
+/// Administrative state of remote, only active remotes are selected for new connections
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteAdminState {
+    #[default]
+    Active,
+    /// Existing connections continue, remote becomes disabled (or is removed) when they finish
+    Draining,
+    /// Remote is out of rotation until enabled again
+    Disabled,
+}
+
+impl Display for RemoteAdminState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteAdminState::Active => write!(f, "active"),
+            RemoteAdminState::Draining => write!(f, "draining"),
+            RemoteAdminState::Disabled => write!(f, "disabled"),
         }
     }
 }
 
 #[derive(Debug, Default, Clone, Serialize)]
 pub struct RemoteStats {
+    pub admin_state: RemoteAdminState,
+    /// Load balancing weight
+    pub weight: u32,
+    /// Priority tier, 0 is primary
+    pub tier: u8,
     pub bytes_sent: u64,
     pub streams_open: usize,
     pub streams_pending: usize,
@@ -61,6 +134,57 @@ pub struct RemoteStats {
     pub last_error_time: Option<SystemTime>,
     pub num_errors: u64,
     pub total_errors: u64,
+    pub health_checks: u64,
+    pub health_check_failures: u64,
+    /// Remote is ejected by outlier detection until this time
+    #[serde(serialize_with = "to_epoch_millis")]
+    pub ejected_until: Option<SystemTime>,
+    pub total_ejections: u64,
+    /// Exponentially weighted moving average of connect time in milliseconds
+    pub connect_latency_ewma: Option<f64>,
+    /// User defined key value data, available to load balancing strategies
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl RemoteStats {
+    pub fn is_ejected(&self) -> bool {
+        self.ejected_until
+            .map(|t| t > SystemTime::now())
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadRemoteStats {
+    #[serde(flatten)]
+    pub stats: RemoteStats,
+    #[serde(serialize_with = "to_epoch_millis")]
+    pub next_check: Option<SystemTime>,
+    /// Seconds
+    pub retry_interval: f32,
+}
+
+/// Result of replacement of tunnel remotes
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RemotesChange {
+    /// New remotes
+    pub added: usize,
+    /// Remotes, which stayed in tunnel
+    pub kept: usize,
+    /// Dropped live remotes, removed after open connections finish
+    pub draining: usize,
+    /// Dropped remotes removed at once
+    pub removed: usize,
+}
+
+impl Display for RemotesChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "added {}, kept {}, draining {}, removed {}",
+            self.added, self.kept, self.draining, self.removed
+        )
+    }
 }
 
 #[cfg(feature = "metrics")]
@@ -73,6 +197,7 @@ pub struct RemoteMetrics {
     pub total_connections: opentelemetry::metrics::Counter<u64>,
     pub num_errors: opentelemetry::metrics::UpDownCounter<i64>,
     pub total_errors: opentelemetry::metrics::Counter<u64>,
+    pub ejections: opentelemetry::metrics::Counter<u64>,
 }
 
 #[cfg(feature = "metrics")]
@@ -107,6 +232,47 @@ impl RemoteMetrics {
                 .u64_counter("retote_total_error")
                 .with_description("total number of errors")
                 .init(),
+            ejections: meter
+                .u64_counter("remote_ejections")
+                .with_description("number of times remote was ejected by outlier detection")
+                .init(),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+pub struct HealthMetrics {
+    pub checks: metrics::Counter<u64>,
+    pub failures: metrics::Counter<u64>,
+    pub remotes_down: metrics::Counter<u64>,
+    pub remotes_up: metrics::Counter<u64>,
+}
+
+#[cfg(feature = "metrics")]
+impl HealthMetrics {
+    pub fn new(meter: &Meter) -> Self {
+        HealthMetrics {
+            checks: meter
+                .u64_counter("health_checks")
+                .with_description("total number of remote health checks")
+                .init(),
+            failures: meter
+                .u64_counter("health_check_failures")
+                .with_description("number of failed remote health checks")
+                .init(),
+            remotes_down: meter
+                .u64_counter("health_remotes_down")
+                .with_description(
+                    "number of times remote was moved to dead remotes by health checks",
+                )
+                .init(),
+            remotes_up: meter
+                .u64_counter("health_remotes_up")
+                .with_description(
+                    "number of times remote was moved back to live remotes by health checks",
+                )
+                .init(),
         }
     }
 }
@@ -1,35 +1,67 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
 
-use rand::Rng;
-use serde::{Deserialize, Serialize};
+use parking_lot::{Mutex, RwLock};
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::error::{Error, Result};
+use crate::{
+    error::{Error, Result},
+    tunnel::SocketSpec,
+};
 
-use super::TunnelInfo;
+use super::{stats::RemoteStats, TunnelInfo};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum TunnelLBStrategy {
+    #[default]
     Random,
     RoundRobin,
     MinimumOpenConnections,
-}
-
-impl Default for TunnelLBStrategy {
-    fn default() -> Self {
-        TunnelLBStrategy::Random
-    }
+    WeightedRoundRobin,
+    WeightedRandom,
+    SourceHash,
+    ConsistentHash,
+    PeakEwma,
+    PowerOfTwoChoices,
+    /// Strategy registered in `StrategyRegistry`
+    Custom(String),
 }
 
 impl TunnelLBStrategy {
-    pub fn create(&self) -> Box<dyn LBStrategy + Send + Sync + 'static> {
-        match self {
+    pub fn create(
+        &self,
+        registry: &StrategyRegistry,
+    ) -> Result<Box<dyn LBStrategy + Send + Sync + 'static>> {
+        Ok(match self {
             TunnelLBStrategy::Random => Box::new(Random),
             TunnelLBStrategy::RoundRobin => Box::new(RoundRobin),
             TunnelLBStrategy::MinimumOpenConnections => Box::new(MinimumOpenConnections),
-        }
+            TunnelLBStrategy::WeightedRoundRobin => Box::<WeightedRoundRobin>::default(),
+            TunnelLBStrategy::WeightedRandom => Box::new(WeightedRandom),
+            TunnelLBStrategy::SourceHash => Box::new(SourceHash),
+            TunnelLBStrategy::ConsistentHash => Box::<ConsistentHash>::default(),
+            TunnelLBStrategy::PeakEwma => Box::new(PeakEwma),
+            TunnelLBStrategy::PowerOfTwoChoices => Box::new(PowerOfTwoChoices),
+            TunnelLBStrategy::Custom(name) => {
+                registry.create(name).ok_or(Error::InvalidLBStrategy)?
+            }
+        })
     }
 }
 
+fn is_strategy_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 impl FromStr for TunnelLBStrategy {
     type Err = Error;
 
@@ -43,6 +75,21 @@ impl FromStr for TunnelLBStrategy {
             | "min-open-connections"
             | "min_open_connections"
             | "minopenconnections" => Ok(TunnelLBStrategy::MinimumOpenConnections),
+            "weighted-round-robin" | "weighted_round_robin" | "weightedroundrobin" => {
+                Ok(TunnelLBStrategy::WeightedRoundRobin)
+            }
+            "weighted-random" | "weighted_random" | "weightedrandom" => {
+                Ok(TunnelLBStrategy::WeightedRandom)
+            }
+            "source-hash" | "source_hash" | "sourcehash" => Ok(TunnelLBStrategy::SourceHash),
+            "consistent-hash" | "consistent_hash" | "consistenthash" => {
+                Ok(TunnelLBStrategy::ConsistentHash)
+            }
+            "peak-ewma" | "peak_ewma" | "peakewma" => Ok(TunnelLBStrategy::PeakEwma),
+            "p2c" | "power-of-two-choices" | "power_of_two_choices" | "poweroftwochoices" => {
+                Ok(TunnelLBStrategy::PowerOfTwoChoices)
+            }
+            _ if is_strategy_name(s) => Ok(TunnelLBStrategy::Custom(s.to_string())),
             _ => Err(Error::InvalidLBStrategy),
         }
     }
@@ -54,21 +101,215 @@ impl Display for TunnelLBStrategy {
             TunnelLBStrategy::Random => write!(f, "random"),
             TunnelLBStrategy::RoundRobin => write!(f, "round-robin"),
             TunnelLBStrategy::MinimumOpenConnections => write!(f, "minimum-open-connections"),
+            TunnelLBStrategy::WeightedRoundRobin => write!(f, "weighted-round-robin"),
+            TunnelLBStrategy::WeightedRandom => write!(f, "weighted-random"),
+            TunnelLBStrategy::SourceHash => write!(f, "source-hash"),
+            TunnelLBStrategy::ConsistentHash => write!(f, "consistent-hash"),
+            TunnelLBStrategy::PeakEwma => write!(f, "peak-ewma"),
+            TunnelLBStrategy::PowerOfTwoChoices => write!(f, "p2c"),
+            TunnelLBStrategy::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Serialize for TunnelLBStrategy {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let name = match self {
+            TunnelLBStrategy::Random => "Random",
+            TunnelLBStrategy::RoundRobin => "RoundRobin",
+            TunnelLBStrategy::MinimumOpenConnections => "MinimumOpenConnections",
+            TunnelLBStrategy::WeightedRoundRobin => "WeightedRoundRobin",
+            TunnelLBStrategy::WeightedRandom => "WeightedRandom",
+            TunnelLBStrategy::SourceHash => "SourceHash",
+            TunnelLBStrategy::ConsistentHash => "ConsistentHash",
+            TunnelLBStrategy::PeakEwma => "PeakEwma",
+            TunnelLBStrategy::PowerOfTwoChoices => "PowerOfTwoChoices",
+            TunnelLBStrategy::Custom(name) => name,
+        };
+        serializer.serialize_str(name)
+    }
+}
+
+/// Accepts same names as tunnel spec, so custom strategies can be used in RPC too
+impl<'de> Deserialize<'de> for TunnelLBStrategy {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Creates new instance of load balancing strategy for a tunnel
+pub type StrategyFactory = Arc<dyn Fn() -> Box<dyn LBStrategy + Send + Sync> + Send + Sync>;
+
+/// User supplied load balancing strategies, which can be used as `strategy=<name>`
+#[derive(Default)]
+pub struct StrategyRegistry {
+    factories: RwLock<fxhash::FxHashMap<String, StrategyFactory>>,
+}
+
+impl StrategyRegistry {
+    /// Registers strategy under given name, name must not be name of built-in strategy
+    pub fn register(&self, name: impl Into<String>, factory: StrategyFactory) -> Result<()> {
+        let name = name.into();
+        match name.parse::<TunnelLBStrategy>() {
+            Ok(TunnelLBStrategy::Custom(_)) => {
+                self.factories.write().insert(name, factory);
+                Ok(())
+            }
+            _ => Err(Error::InvalidLBStrategy),
         }
     }
+
+    pub fn create(&self, name: &str) -> Option<Box<dyn LBStrategy + Send + Sync>> {
+        self.factories.read().get(name).map(|factory| factory())
+    }
+
+    /// Names of registered strategies
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.factories.read().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Read-only view of tunnel remote for load balancing strategies
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteView<'a> {
+    /// Index of remote in tunnel, as used in candidates
+    pub index: usize,
+    pub remote: &'a SocketSpec,
+    /// Statistics and configuration of remote - weight, tier, connect latency, metadata ...
+    pub stats: &'a RemoteStats,
+    /// Weight factor during slow start, 1 otherwise
+    pub slow_start_factor: f64,
+    /// Connect latency in milliseconds, which jumps up to peaks and decays slowly
+    pub peak_latency: f64,
+}
+
+impl RemoteView<'_> {
+    /// Metadata value set for remote
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.stats.metadata.get(key).map(String::as_str)
+    }
+}
+
+/// What is known about connection, when remote is selected for it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionContext<'a> {
+    pub client: Option<SocketAddr>,
+    /// TLS SNI server name
+    pub server_name: Option<&'a str>,
+}
+
+impl ConnectionContext<'_> {
+    /// Hash of client IP address (or server name, if client is not known) for client affinity
+    fn affinity_hash(&self) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        match (self.client, self.server_name) {
+            (Some(client), _) => client.ip().hash(&mut hasher),
+            (None, Some(name)) => name.hash(&mut hasher),
+            (None, None) => return None,
+        }
+        Some(hasher.finish())
+    }
 }
 
 pub trait LBStrategy: std::fmt::Debug {
-    fn select_remote(&self, tunnel: &TunnelInfo) -> Result<usize>;
+    /// Selects one of candidates - indexes of remotes in tunnel, that can be used for connection.
+    /// Candidates are never empty and contain only active remotes (not draining or disabled).
+    /// Strategies should respect `TunnelInfo::effective_weight` of candidates (e.g. slow start).
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        ctx: &ConnectionContext,
+    ) -> Result<usize>;
 }
 
 #[derive(Debug)]
 pub struct Random;
 
+fn weighted_random(candidates: &[usize], weight: impl Fn(usize) -> f64) -> usize {
+    let weights = candidates.iter().map(|&idx| weight(idx));
+    let idx = match WeightedIndex::new(weights) {
+        Ok(dist) => dist.sample(&mut rand::thread_rng()),
+        Err(_) => rand::thread_rng().gen_range(0..candidates.len()),
+    };
+    candidates[idx]
+}
+
 impl LBStrategy for Random {
-    fn select_remote(&self, tunnel: &TunnelInfo) -> Result<usize> {
-        let size = tunnel.remotes.len();
-        let idx: usize = rand::thread_rng().gen_range(0..size);
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        Ok(weighted_random(candidates, |idx| {
+            tunnel.effective_weight(idx)
+        }))
+    }
+}
+
+/// Random selection with probability proportional to remote weight
+#[derive(Debug)]
+pub struct WeightedRandom;
+
+impl LBStrategy for WeightedRandom {
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        Ok(weighted_random(candidates, |idx| {
+            tunnel.weighted_effective_weight(idx)
+        }))
+    }
+}
+
+/// Smooth weighted round robin (as in nginx) - remotes are interleaved
+/// according to their weights, rather then selected in bursts
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    current_weights: Mutex<fxhash::FxHashMap<SocketSpec, f64>>,
+}
+
+impl LBStrategy for WeightedRoundRobin {
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let mut current_weights = self.current_weights.lock();
+        current_weights.retain(|remote, _| tunnel.remotes.contains_key(remote));
+        let mut total = 0.0;
+        let mut selected: Option<(usize, f64)> = None;
+        for &idx in candidates {
+            let Some((remote, _)) = tunnel.remotes.get_index(idx) else {
+                continue;
+            };
+            let weight = tunnel.weighted_effective_weight(idx);
+            let current = current_weights.entry(remote.clone()).or_default();
+            *current += weight;
+            total += weight;
+            if selected.map(|(_, max)| *current > max).unwrap_or(true) {
+                selected = Some((idx, *current));
+            }
+        }
+        let idx = selected.map(|(idx, _)| idx).unwrap_or(candidates[0]);
+        if let Some((remote, _)) = tunnel.remotes.get_index(idx) {
+            if let Some(current) = current_weights.get_mut(remote) {
+                *current -= total;
+            }
+        }
         Ok(idx)
     }
 }
@@ -77,12 +318,29 @@ impl LBStrategy for Random {
 pub struct RoundRobin;
 
 impl LBStrategy for RoundRobin {
-    fn select_remote(&self, tunnel: &TunnelInfo) -> Result<usize> {
-        let size = tunnel.remotes.len();
-        let last = tunnel
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let start = tunnel
             .last_selected_index
-            .unwrap_or_else(|| tunnel.remotes.len().saturating_sub(1));
-        Ok((last + 1) % size)
+            .and_then(|last| candidates.iter().position(|&idx| idx > last))
+            .unwrap_or(0);
+        // remotes with lower weight (in slow start) are skipped with corresponding probability
+        let mut rng = rand::thread_rng();
+        let next = candidates
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(candidates.len())
+            .find(|&&idx| {
+                let weight = tunnel.effective_weight(idx);
+                weight >= 1.0 || rng.gen_bool(weight.clamp(0.0, 1.0))
+            })
+            .unwrap_or(&candidates[start]);
+        Ok(*next)
     }
 }
 
@@ -90,23 +348,187 @@ impl LBStrategy for RoundRobin {
 pub struct MinimumOpenConnections;
 
 impl LBStrategy for MinimumOpenConnections {
-    fn select_remote(&self, tunnel: &TunnelInfo) -> Result<usize> {
-        let mut min_idx = 0usize;
-        let mut min_val = usize::MAX;
-        for (idx, open_conns) in tunnel
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let mut min_idx = candidates[0];
+        let mut min_val = f64::MAX;
+        // open connections are scaled by weight, so remote in slow start gets fewer connections
+        for (idx, score) in candidates.iter().filter_map(|&idx| {
+            tunnel.remotes.get_index(idx).map(|(_, r)| {
+                let open_conns = r.stats.streams_open + r.stats.streams_pending;
+                (idx, (open_conns + 1) as f64 / tunnel.effective_weight(idx))
+            })
+        }) {
+            if score < min_val {
+                min_idx = idx;
+                min_val = score;
+            }
+        }
+
+        Ok(min_idx)
+    }
+}
+
+/// Client IP address hash modulo number of candidates - same client goes to same remote,
+/// as long as candidates do not change
+#[derive(Debug)]
+pub struct SourceHash;
+
+impl LBStrategy for SourceHash {
+    fn select_remote(
+        &self,
+        _tunnel: &TunnelInfo,
+        candidates: &[usize],
+        ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let idx = match ctx.affinity_hash() {
+            Some(hash) => (hash % candidates.len() as u64) as usize,
+            None => rand::thread_rng().gen_range(0..candidates.len()),
+        };
+        Ok(candidates[idx])
+    }
+}
+
+/// Virtual nodes on hash ring per unit of remote weight
+const RING_POINTS_PER_WEIGHT: u32 = 100;
+/// Limit of virtual nodes for one remote
+const MAX_RING_POINTS: u32 = 10_000;
+
+#[derive(Debug, Default)]
+struct HashRing {
+    /// Remotes and weights, from which ring was built
+    remotes: Vec<(SocketSpec, u32)>,
+    /// Sorted points on ring
+    points: Vec<(u64, SocketSpec)>,
+}
+
+impl HashRing {
+    fn new(remotes: Vec<(SocketSpec, u32)>) -> Self {
+        let mut points = Vec::new();
+        for (remote, weight) in &remotes {
+            let num_points = weight
+                .saturating_mul(RING_POINTS_PER_WEIGHT)
+                .min(MAX_RING_POINTS);
+            for n in 0..num_points {
+                let mut hasher = DefaultHasher::new();
+                remote.hash(&mut hasher);
+                n.hash(&mut hasher);
+                points.push((hasher.finish(), remote.clone()));
+            }
+        }
+        points.sort_by_key(|(point, _)| *point);
+        HashRing { remotes, points }
+    }
+}
+
+/// Consistent hashing of client IP address on hash ring - when remote is added or removed,
+/// only about 1/N of clients move to other remote
+#[derive(Debug, Default)]
+pub struct ConsistentHash {
+    ring: Mutex<HashRing>,
+}
+
+impl LBStrategy for ConsistentHash {
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let Some(hash) = ctx.affinity_hash() else {
+            return Ok(candidates[rand::thread_rng().gen_range(0..candidates.len())]);
+        };
+        // ring contains all live remotes, so it's not changed, when remote is only temporarily not candidate
+        let remotes: Vec<(SocketSpec, u32)> = tunnel
             .remotes
             .iter()
-            .map(|(_, r)| r.stats.streams_open + r.stats.streams_pending)
-            .enumerate()
-        {
-            if open_conns == 0 {
-                return Ok(idx);
-            } else if open_conns < min_val {
+            .map(|(remote, info)| (remote.clone(), info.stats.weight))
+            .collect();
+        let mut ring = self.ring.lock();
+        if ring.remotes != remotes {
+            *ring = HashRing::new(remotes);
+        }
+        let start = ring.points.partition_point(|(point, _)| *point < hash);
+        let selected = ring
+            .points
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(ring.points.len())
+            .filter_map(|(_, remote)| tunnel.remotes.get_index_of(remote))
+            .find(|idx| candidates.contains(idx))
+            .unwrap_or(candidates[0]);
+        Ok(selected)
+    }
+}
+
+/// Latency floor (ms), so that load matters also for remotes without measured latency
+const MIN_LATENCY: f64 = 0.01;
+
+/// Expected cost of new connection to remote - peak connect latency multiplied by load,
+/// lower for remotes in slow start
+fn latency_load_cost(tunnel: &TunnelInfo, idx: usize) -> f64 {
+    match tunnel.remotes.get_index(idx) {
+        Some((_, r)) => {
+            let load = (r.stats.streams_open + r.stats.streams_pending + 1) as f64;
+            r.peak_latency.max(MIN_LATENCY) * load / tunnel.effective_weight(idx)
+        }
+        None => f64::MAX,
+    }
+}
+
+/// Remote with lowest peak EWMA connect latency multiplied by number of open connections,
+/// remotes without measured latency are tried first
+#[derive(Debug)]
+pub struct PeakEwma;
+
+impl LBStrategy for PeakEwma {
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let mut min_idx = candidates[0];
+        let mut min_cost = f64::MAX;
+        for &idx in candidates {
+            let cost = latency_load_cost(tunnel, idx);
+            if cost < min_cost {
                 min_idx = idx;
-                min_val = open_conns;
+                min_cost = cost;
             }
         }
+        Ok(min_idx)
+    }
+}
+
+/// Power of two random choices - two random candidates are compared by latency and load,
+/// better one is selected
+#[derive(Debug)]
+pub struct PowerOfTwoChoices;
 
-        return Ok(min_idx);
+impl LBStrategy for PowerOfTwoChoices {
+    fn select_remote(
+        &self,
+        tunnel: &TunnelInfo,
+        candidates: &[usize],
+        _ctx: &ConnectionContext,
+    ) -> Result<usize> {
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..candidates.len());
+        let mut second = rng.gen_range(0..candidates.len() - 1);
+        if second >= first {
+            second += 1
+        }
+        let (first, second) = (candidates[first], candidates[second]);
+        if latency_load_cost(tunnel, second) < latency_load_cost(tunnel, first) {
+            Ok(second)
+        } else {
+            Ok(first)
+        }
     }
 }
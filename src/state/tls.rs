@@ -1,6 +1,6 @@
-use crate::error::Result;
-use rustls::{ClientConfig, OwnedTrustAnchor};
-use std::{fs::File, io::BufReader};
+use crate::error::{Error, Result};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, ServerConfig};
+use std::{fs::File, io::BufReader, path::Path};
 
 use crate::config::Args;
 
@@ -41,3 +41,36 @@ pub fn create_client_config(args: &Args) -> Result<ClientConfig> {
         .with_no_client_auth();
     Ok(config)
 }
+
+pub fn create_server_config(cert_file: &Path, key_file: &Path) -> Result<ServerConfig> {
+    let mut pem = BufReader::new(File::open(cert_file)?);
+    let certs: Vec<_> = rustls_pemfile::certs(&mut pem)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(Error::TLSConfigError(format!(
+            "No certificate found in {}",
+            cert_file.display()
+        )));
+    }
+
+    let mut pem = BufReader::new(File::open(key_file)?);
+    let key = rustls_pemfile::read_all(&mut pem)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            Error::TLSConfigError(format!("No private key found in {}", key_file.display()))
+        })?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(config)
+}
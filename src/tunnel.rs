@@ -3,13 +3,25 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     error::{Error, Result},
-    state::strategy::TunnelLBStrategy,
+    proxy_protocol::ProxyProtocolVersion,
+    state::{health::HealthCheck, outlier::OutlierDetection, strategy::TunnelLBStrategy},
     State,
 };
-use std::{fmt::Display, str::FromStr, sync::Arc};
+use nom::combinator::all_consuming;
+use rand::Rng;
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use self::parser::{socket_spec, tunnel};
+pub use self::cidr::Cidr;
+use self::parser::{options_update, rate_limits, remote_spec, socket_spec, tunnel};
 
+pub mod cidr;
 mod parser;
 
 /// This is our equivalence to SocketAddr, but with host name
@@ -20,23 +32,113 @@ pub struct SocketSpec {
     inner: Arc<str>,
 }
 
+const UNIX_PREFIX: &str = "unix:";
+const UDP_PREFIX: &str = "udp:";
+/// Separates host name and its resolved address in socket spec (`name/address:port`)
+const RESOLVED_SEPARATOR: char = '/';
+
 impl SocketSpec {
-    pub fn as_tuple(&self) -> (&str, u16) {
+    /// Host and port of TCP or UDP socket, `None` for unix socket spec,
+    /// for resolved host name spec host is the resolved address
+    pub fn as_tuple(&self) -> Option<(&str, u16)> {
+        if self.unix_path().is_some() {
+            return None;
+        }
         // here we assume that inner str is in normalized form
-        let (mut host, port) = self.inner.rsplit_once(':').unwrap();
+        let addr = self.inner.strip_prefix(UDP_PREFIX).unwrap_or(&self.inner);
+        let addr = addr
+            .split_once(RESOLVED_SEPARATOR)
+            .map(|(_, addr)| addr)
+            .unwrap_or(addr);
+        let (mut host, port) = addr.rsplit_once(':')?;
         if host.starts_with('[') && host.ends_with(']') {
             host = &host[1..host.len() - 1];
         }
-        let port = port.parse().unwrap();
-        (host, port)
+        let port = port.parse().ok()?;
+        Some((host, port))
+    }
+
+    /// Host and port for socket operations, unix socket spec is invalid input
+    pub fn host_port(&self) -> std::io::Result<(&str, u16)> {
+        self.as_tuple().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} has no host and port", self),
+            )
+        })
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        self.as_tuple().map(|(_, port)| port)
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.as_tuple().map(|(host, _)| host)
+    }
+
+    /// Name of the host for TLS - host name for resolved spec, otherwise same as `host`
+    pub fn server_name(&self) -> Option<&str> {
+        self.resolved_name_part().or_else(|| self.host())
+    }
+
+    fn resolved_name_part(&self) -> Option<&str> {
+        if self.unix_path().is_some() {
+            return None;
+        }
+        let addr = self.inner.strip_prefix(UDP_PREFIX).unwrap_or(&self.inner);
+        addr.split_once(RESOLVED_SEPARATOR).map(|(name, _)| name)
     }
 
-    pub fn port(&self) -> u16 {
-        self.as_tuple().1
+    /// Is host part a host name (not IP address), which was not resolved yet
+    pub fn is_host_name(&self) -> bool {
+        self.resolved_name_part().is_none()
+            && matches!(self.host(), Some(host) if host.parse::<IpAddr>().is_err())
     }
 
-    pub fn host(&self) -> &str {
-        self.as_tuple().0
+    /// Spec with host name, from which this spec was resolved (`name:port`)
+    pub fn resolved_name(&self) -> Option<SocketSpec> {
+        let (name, port) = self.resolved_name_part().zip(self.port())?;
+        let prefix = if self.is_udp() { UDP_PREFIX } else { "" };
+        Some(SocketSpec {
+            inner: format!("{}{}:{}", prefix, name, port).into(),
+        })
+    }
+
+    /// Spec of host name resolved to given address (`name/address:port`), `None` for unix socket spec
+    pub fn with_address(&self, address: IpAddr) -> Option<SocketSpec> {
+        let (name, port) = self.server_name().zip(self.port())?;
+        let prefix = if self.is_udp() { UDP_PREFIX } else { "" };
+        Some(SocketSpec {
+            inner: format!(
+                "{}{}{}{}",
+                prefix,
+                name,
+                RESOLVED_SEPARATOR,
+                SocketAddr::new(address, port)
+            )
+            .into(),
+        })
+    }
+
+    /// Path of unix domain socket, if this is unix socket spec (`unix:/path/to.sock`)
+    pub fn unix_path(&self) -> Option<&str> {
+        self.inner.strip_prefix(UNIX_PREFIX)
+    }
+
+    /// Is this UDP socket spec (`udp:host:port`)
+    pub fn is_udp(&self) -> bool {
+        self.inner.starts_with(UDP_PREFIX)
+    }
+
+    /// Can be this remote used for tunnel with given local socket -
+    /// UDP tunnel can forward only to UDP (host:port) remotes, TCP and unix tunnels
+    /// can forward only to TCP or unix remotes
+    pub fn compatible_remote(&self, remote: &SocketSpec) -> bool {
+        if self.is_udp() {
+            remote.unix_path().is_none()
+        } else {
+            !remote.is_udp()
+        }
     }
 }
 
@@ -88,14 +190,45 @@ impl From<SocketSpec> for opentelemetry::Value {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TunnelRemoteOptions {
     pub errors_till_dead: u64,
     pub connect_timeout: f32,
+    /// Interval of dead remote checks
     pub dead_retry: f32,
+    /// If set, interval of dead remote checks doubles after each failed check up to this value
+    pub dead_retry_max: Option<f32>,
+    /// Dead remote check interval is randomly changed by up to this fraction
+    pub dead_retry_jitter: f32,
     pub tls: bool,
 }
 
+impl Default for TunnelRemoteOptions {
+    fn default() -> Self {
+        TunnelOptions::default().options
+    }
+}
+
 impl TunnelRemoteOptions {
+    /// Interval for next dead remote check, after failed check done after `last` interval
+    pub fn next_dead_retry(&self, last: Duration) -> Duration {
+        let base = Duration::from_secs_f32(self.dead_retry);
+        match self.dead_retry_max {
+            Some(max) => (last * 2).min(Duration::from_secs_f32(max)).max(base),
+            None => base,
+        }
+    }
+
+    /// Randomizes interval, so checks of many remotes are not done in same time
+    pub fn with_jitter(&self, interval: Duration) -> Duration {
+        let jitter = self.dead_retry_jitter.clamp(0.0, 1.0) as f64;
+        if jitter > 0.0 {
+            interval.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+        } else {
+            interval
+        }
+    }
+
     pub fn tls_config(&self, state: &State) -> Option<Arc<ClientConfig>> {
         if self.tls {
             Some(state.client_ssl_config())
@@ -111,6 +244,58 @@ pub struct TunnelOptions {
     pub lb_strategy: TunnelLBStrategy,
     pub remote_connect_retries: u16,
     pub options: TunnelRemoteOptions,
+    /// Terminate TLS on tunnel listener, requires `local_cert` and `local_key`
+    pub local_tls: bool,
+    pub local_cert: Option<PathBuf>,
+    pub local_key: Option<PathBuf>,
+    /// Read TLS ClientHello from client to route connection by SNI server name
+    pub sni_routing: bool,
+    /// Send PROXY protocol header with client address to remote
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Expect PROXY protocol header from clients (load balancer in front of plexy)
+    pub accept_proxy_protocol: bool,
+    /// Sources allowed to send PROXY protocol header, must be set with `accept_proxy_protocol`,
+    /// empty means header is never accepted
+    pub proxy_trusted: Vec<Cidr>,
+    /// UDP session is closed after this many seconds without traffic
+    pub session_timeout: f32,
+    /// Connection is closed after this many seconds without data in either direction
+    pub idle_timeout: Option<f32>,
+    /// Connection is closed after this many seconds, regardless of activity
+    pub max_lifetime: Option<f32>,
+    /// Maximum of concurrent connections in tunnel
+    pub max_conns: Option<usize>,
+    /// Maximum of concurrent connections (including pending) to one remote
+    pub max_conns_per_remote: Option<usize>,
+    /// Maximum of concurrent connections from one client IP address
+    pub max_conns_per_client: Option<usize>,
+    /// How long connection can wait for free slot when limit is reached, if not set connection is rejected immediately
+    pub queue_timeout: Option<f32>,
+    /// Maximum of new connections per second accepted by tunnel
+    pub accept_rate: Option<f32>,
+    /// Maximum of new connections per second from one client IP address
+    pub client_rate: Option<f32>,
+    /// Bandwidth limit (bytes per second) from client to remote for each connection
+    pub rate_up: Option<f32>,
+    /// Bandwidth limit (bytes per second) from remote to client for each connection
+    pub rate_down: Option<f32>,
+    /// Bandwidth limit (bytes per second) from clients to remotes for all tunnel connections together
+    pub tunnel_rate_up: Option<f32>,
+    /// Bandwidth limit (bytes per second) from remotes to clients for all tunnel connections together
+    pub tunnel_rate_down: Option<f32>,
+    /// Only clients from these networks are accepted, empty means all clients
+    pub allow: Vec<Cidr>,
+    /// Clients from these networks are refused, takes precedence over allow list
+    pub deny: Vec<Cidr>,
+    /// Active health checks of remotes
+    pub health_check: Option<HealthCheck>,
+    /// Seconds during which share of traffic of recovered or newly added remote ramps up to full
+    pub slow_start: Option<f32>,
+    /// Ejection of remotes with high error rate or connect latency
+    pub outlier: Option<OutlierDetection>,
+    /// Host name remotes are resolved to all their addresses, refreshed at most after these seconds
+    /// (or sooner, when DNS records expire)
+    pub dns_refresh: Option<f32>,
 }
 
 static mut DEFAULT_TUNNEL_OPTIONS: TunnelOptions = TunnelOptions {
@@ -120,8 +305,36 @@ static mut DEFAULT_TUNNEL_OPTIONS: TunnelOptions = TunnelOptions {
         errors_till_dead: 1,
         connect_timeout: 10.0,
         dead_retry: 10.0,
+        dead_retry_max: None,
+        dead_retry_jitter: 0.1,
         tls: false,
     },
+    local_tls: false,
+    local_cert: None,
+    local_key: None,
+    sni_routing: false,
+    proxy_protocol: None,
+    accept_proxy_protocol: false,
+    proxy_trusted: Vec::new(),
+    session_timeout: 30.0,
+    idle_timeout: None,
+    max_lifetime: None,
+    max_conns: None,
+    max_conns_per_remote: None,
+    max_conns_per_client: None,
+    queue_timeout: None,
+    accept_rate: None,
+    client_rate: None,
+    rate_up: None,
+    rate_down: None,
+    tunnel_rate_up: None,
+    tunnel_rate_down: None,
+    allow: Vec::new(),
+    deny: Vec::new(),
+    health_check: None,
+    slow_start: None,
+    outlier: None,
+    dns_refresh: None,
 };
 
 /// Must be used only at very of beginning program before anything else
@@ -135,7 +348,7 @@ pub fn set_default_tunnel_options(options: TunnelOptions) {
 
 impl Default for TunnelOptions {
     fn default() -> Self {
-        unsafe { DEFAULT_TUNNEL_OPTIONS.clone() }
+        unsafe { (*std::ptr::addr_of!(DEFAULT_TUNNEL_OPTIONS)).clone() }
     }
 }
 
@@ -143,19 +356,324 @@ impl Display for TunnelOptions {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "strategy={},retries={},timeout={}, errors={}",
+            "strategy={},retries={},timeout={}, errors={}, check-interval={}, check-jitter={}",
             self.lb_strategy,
             self.remote_connect_retries,
             self.options.connect_timeout,
-            self.options.errors_till_dead
+            self.options.errors_till_dead,
+            self.options.dead_retry,
+            self.options.dead_retry_jitter
+        )?;
+        if let Some(max) = self.options.dead_retry_max {
+            write!(f, ", check-interval-max={}", max)?;
+        }
+        if self.local_tls {
+            write!(f, ", local-tls=true")?;
+        }
+        if self.sni_routing {
+            write!(f, ", sni-routing=true")?;
+        }
+        if let Some(version) = self.proxy_protocol {
+            write!(f, ", proxy-protocol={}", version)?;
+        }
+        if self.accept_proxy_protocol {
+            write!(f, ", accept-proxy-protocol=true")?;
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            write!(f, ", idle-timeout={}", idle_timeout)?;
+        }
+        if let Some(max_lifetime) = self.max_lifetime {
+            write!(f, ", max-lifetime={}", max_lifetime)?;
+        }
+        if let Some(max_conns) = self.max_conns {
+            write!(f, ", max-conns={}", max_conns)?;
+        }
+        if let Some(max_conns) = self.max_conns_per_remote {
+            write!(f, ", max-conns-per-remote={}", max_conns)?;
+        }
+        if let Some(max_conns) = self.max_conns_per_client {
+            write!(f, ", max-conns-per-client={}", max_conns)?;
+        }
+        if let Some(queue_timeout) = self.queue_timeout {
+            write!(f, ", queue-timeout={}", queue_timeout)?;
+        }
+        if let Some(rate) = self.accept_rate {
+            write!(f, ", accept-rate={}", rate)?;
+        }
+        if let Some(rate) = self.client_rate {
+            write!(f, ", client-rate={}", rate)?;
+        }
+        if let Some(rate) = self.rate_up {
+            write!(f, ", rate-up={}", rate)?;
+        }
+        if let Some(rate) = self.rate_down {
+            write!(f, ", rate-down={}", rate)?;
+        }
+        if let Some(rate) = self.tunnel_rate_up {
+            write!(f, ", tunnel-rate-up={}", rate)?;
+        }
+        if let Some(rate) = self.tunnel_rate_down {
+            write!(f, ", tunnel-rate-down={}", rate)?;
+        }
+        if !self.allow.is_empty() {
+            write!(f, ", allow={}", cidr_list_to_string(&self.allow))?;
+        }
+        if !self.deny.is_empty() {
+            write!(f, ", deny={}", cidr_list_to_string(&self.deny))?;
+        }
+        if let Some(ref check) = self.health_check {
+            write!(f, ", {}", check)?;
+        }
+        if let Some(slow_start) = self.slow_start {
+            write!(f, ", slow-start={}", slow_start)?;
+        }
+        if let Some(ref outlier) = self.outlier {
+            write!(f, ", {}", outlier)?;
+        }
+        if let Some(refresh) = self.dns_refresh {
+            write!(f, ", dns-refresh={}", refresh)?;
+        }
+        Ok(())
+    }
+}
+
+/// Client IP access lists of tunnel
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLists {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Display for AccessLists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "allow={}, deny={}",
+            cidr_list_to_string(&self.allow),
+            cidr_list_to_string(&self.deny)
         )
     }
 }
 
+fn cidr_list_to_string(list: &[Cidr]) -> String {
+    list.iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Change of tunnel rate limits at runtime - only set limits are changed,
+/// zero means no limit
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub accept_rate: Option<f32>,
+    pub client_rate: Option<f32>,
+    pub rate_up: Option<f32>,
+    pub rate_down: Option<f32>,
+    pub tunnel_rate_up: Option<f32>,
+    pub tunnel_rate_down: Option<f32>,
+}
+
+/// Zero or negative rate means no limit
+fn rate_limit(rate: f32) -> Option<f32> {
+    if rate > 0.0 {
+        Some(rate)
+    } else {
+        None
+    }
+}
+
+/// Time in seconds must be finite and positive, so it can be converted to `Duration`
+pub(crate) fn valid_seconds(seconds: f32) -> bool {
+    seconds.is_finite() && seconds > 0.0
+}
+
+impl TunnelOptions {
+    /// Checks values of options - options can also come from RPC or library code,
+    /// not only from tunnel spec parser
+    pub fn validate(&self) -> Result<()> {
+        let optional_seconds = |s: Option<f32>| s.map(valid_seconds).unwrap_or(true);
+        let optional_rate = |r: Option<f32>| r.map(|r| r.is_finite() && r > 0.0).unwrap_or(true);
+        let remote = &self.options;
+        let mut checks = vec![
+            ("timeout", valid_seconds(remote.connect_timeout)),
+            ("check-interval", valid_seconds(remote.dead_retry)),
+            (
+                "check-interval-max",
+                remote
+                    .dead_retry_max
+                    .map(|max| valid_seconds(max) && max >= remote.dead_retry)
+                    .unwrap_or(true),
+            ),
+            (
+                "check-jitter",
+                (0.0..=1.0).contains(&remote.dead_retry_jitter),
+            ),
+            (
+                "proxy-trusted",
+                !self.accept_proxy_protocol || !self.proxy_trusted.is_empty(),
+            ),
+            ("session-timeout", valid_seconds(self.session_timeout)),
+            ("idle-timeout", optional_seconds(self.idle_timeout)),
+            ("max-lifetime", optional_seconds(self.max_lifetime)),
+            ("queue-timeout", optional_seconds(self.queue_timeout)),
+            ("slow-start", optional_seconds(self.slow_start)),
+            ("dns-refresh", optional_seconds(self.dns_refresh)),
+            ("accept-rate", optional_rate(self.accept_rate)),
+            ("client-rate", optional_rate(self.client_rate)),
+            ("rate-up", optional_rate(self.rate_up)),
+            ("rate-down", optional_rate(self.rate_down)),
+            ("tunnel-rate-up", optional_rate(self.tunnel_rate_up)),
+            ("tunnel-rate-down", optional_rate(self.tunnel_rate_down)),
+        ];
+        if let Some(ref check) = self.health_check {
+            checks.extend([
+                ("health-interval", valid_seconds(check.interval)),
+                ("health-timeout", valid_seconds(check.timeout)),
+                ("health-rise", check.rise > 0),
+                ("health-fall", check.fall > 0),
+            ]);
+        }
+        if let Some(ref outlier) = self.outlier {
+            checks.extend([
+                ("outlier-window", valid_seconds(outlier.window)),
+                (
+                    "outlier-error-rate",
+                    (0.0..=100.0).contains(&outlier.error_rate),
+                ),
+                ("outlier-min-requests", outlier.min_requests > 0),
+                (
+                    "outlier-latency",
+                    outlier.latency_factor.map(|f| f > 1.0).unwrap_or(true),
+                ),
+                (
+                    "outlier-ejection-time",
+                    valid_seconds(outlier.ejection_time),
+                ),
+                (
+                    "outlier-max-ejection-time",
+                    valid_seconds(outlier.max_ejection_time),
+                ),
+                (
+                    "outlier-max-ejected",
+                    (0.0..=100.0).contains(&outlier.max_ejected),
+                ),
+            ]);
+        }
+        match checks.into_iter().find(|(_, valid)| !valid) {
+            Some((name, _)) => Err(Error::InvalidTunnel(format!(
+                "invalid value of option {}",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn access_lists(&self) -> AccessLists {
+        AccessLists {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        }
+    }
+
+    pub fn update_rate_limits(&mut self, limits: &RateLimits) {
+        if let Some(rate) = limits.accept_rate {
+            self.accept_rate = rate_limit(rate);
+        }
+        if let Some(rate) = limits.client_rate {
+            self.client_rate = rate_limit(rate);
+        }
+        if let Some(rate) = limits.rate_up {
+            self.rate_up = rate_limit(rate);
+        }
+        if let Some(rate) = limits.rate_down {
+            self.rate_down = rate_limit(rate);
+        }
+        if let Some(rate) = limits.tunnel_rate_up {
+            self.tunnel_rate_up = rate_limit(rate);
+        }
+        if let Some(rate) = limits.tunnel_rate_down {
+            self.tunnel_rate_down = rate_limit(rate);
+        }
+    }
+
+    /// Applies options in tunnel spec format (`key=value,...`) on top of these options
+    pub fn updated(&self, changes: &str) -> Result<TunnelOptions> {
+        all_consuming(|i| options_update(i, self))(changes.trim())
+            .map_err(|e| match e {
+                nom::Err::Incomplete(_) => Error::TunnelParseError("Incomplete options".into()),
+                nom::Err::Error(e) | nom::Err::Failure(e) => {
+                    Error::TunnelParseError(format!("Parser: {:?}, Unparsed: {}", e.code, e.input))
+                }
+            })
+            .and_then(|(_, options)| options.validate().map(|_| options))
+    }
+
+    /// Current rate limits, zero for no limit
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            accept_rate: Some(self.accept_rate.unwrap_or(0.0)),
+            client_rate: Some(self.client_rate.unwrap_or(0.0)),
+            rate_up: Some(self.rate_up.unwrap_or(0.0)),
+            rate_down: Some(self.rate_down.unwrap_or(0.0)),
+            tunnel_rate_up: Some(self.tunnel_rate_up.unwrap_or(0.0)),
+            tunnel_rate_down: Some(self.tunnel_rate_down.unwrap_or(0.0)),
+        }
+    }
+}
+
+impl FromStr for RateLimits {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        rate_limits(s.trim())
+            .map_err(|e| match e {
+                nom::Err::Incomplete(_) => Error::TunnelParseError("Incomplete rate limits".into()),
+                nom::Err::Error(e) | nom::Err::Failure(e) => {
+                    Error::TunnelParseError(format!("Parser: {:?}, Unparsed: {}", e.code, e.input))
+                }
+            })
+            .map(|(_, limits)| limits)
+    }
+}
+
+impl Display for RateLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let limits = [
+            ("accept-rate", self.accept_rate),
+            ("client-rate", self.client_rate),
+            ("rate-up", self.rate_up),
+            ("rate-down", self.rate_down),
+            ("tunnel-rate-up", self.tunnel_rate_up),
+            ("tunnel-rate-down", self.tunnel_rate_down),
+        ];
+        let mut first = true;
+        for (name, value) in limits {
+            if let Some(value) = value {
+                if !first {
+                    write!(f, ",")?;
+                }
+                first = false;
+                write!(f, "{}={}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options of running tunnel before and after change
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelOptionsUpdate {
+    pub previous: TunnelOptions,
+    pub current: TunnelOptions,
+}
+
 #[derive(Debug, Clone)]
 pub struct Tunnel {
     pub local: SocketSpec,
-    pub remote: Vec<SocketSpec>,
+    pub remote: Vec<RemoteSpec>,
     pub options: Option<TunnelOptions>,
 }
 
@@ -184,7 +702,79 @@ impl FromStr for Tunnel {
                     Error::TunnelParseError(format!("Parser: {:?}, Unparsed: {}", e.code, e.input))
                 }
             })
-            .map(|(_, t)| t)
+            .and_then(|(_, t)| {
+                if let Some(ref options) = t.options {
+                    options.validate()?;
+                }
+                Ok(t)
+            })
+    }
+}
+
+/// Weight of remote, if not given in remote spec
+pub const DEFAULT_REMOTE_WEIGHT: u32 = 1;
+
+/// Tier of backup remotes (`!backup`), primary remotes are in tier 0
+pub const BACKUP_TIER: u8 = 1;
+
+/// Remote socket with its load balancing weight and priority tier, written as `socket[@weight][!tier]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteSpec {
+    pub socket: SocketSpec,
+    pub weight: u32,
+    /// Only remotes from lowest tier with live remotes are used
+    pub tier: u8,
+}
+
+impl From<SocketSpec> for RemoteSpec {
+    fn from(socket: SocketSpec) -> Self {
+        RemoteSpec {
+            socket,
+            weight: DEFAULT_REMOTE_WEIGHT,
+            tier: 0,
+        }
+    }
+}
+
+impl FromStr for RemoteSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        remote_spec(s)
+            .map_err(|e| match e {
+                nom::Err::Incomplete(_) => {
+                    Error::SocketSpecParseError("Incomplete remote spec".into())
+                }
+                nom::Err::Error(e) | nom::Err::Failure(e) => Error::SocketSpecParseError(format!(
+                    "Failed parser: {:?}, unparsed: {}",
+                    e.code, e.input
+                )),
+            })
+            .and_then(|(rest, spec)| {
+                if !rest.trim_end().is_empty() {
+                    Err(Error::SocketSpecParseError(format!(
+                        "Extra characters after remote spec: {}",
+                        rest
+                    )))
+                } else {
+                    Ok(spec)
+                }
+            })
+    }
+}
+
+impl Display for RemoteSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.socket)?;
+        if self.weight != DEFAULT_REMOTE_WEIGHT {
+            write!(f, "@{}", self.weight)?;
+        }
+        match self.tier {
+            0 => {}
+            BACKUP_TIER => write!(f, "!backup")?,
+            tier => write!(f, "!{}", tier)?,
+        }
+        Ok(())
     }
 }
 
@@ -192,22 +782,255 @@ impl FromStr for Tunnel {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_remote_weight() {
+        let t: Tunnel = "3333=3000@5,unix:/run/backend.sock@2,3001"
+            .parse()
+            .expect("valid tunnel");
+        let weights: Vec<_> = t.remote.iter().map(|r| r.weight).collect();
+        assert_eq!(vec![5, 2, 1], weights);
+        assert_eq!(Some("/run/backend.sock"), t.remote[1].socket.unix_path());
+        assert_eq!("127.0.0.1:3000@5", t.remote[0].to_string());
+        assert_eq!("127.0.0.1:3001", t.remote[2].to_string());
+        assert!("3000@0".parse::<RemoteSpec>().is_err());
+        assert!("3000@x".parse::<RemoteSpec>().is_err());
+    }
+
+    #[test]
+    fn test_remote_tier() {
+        let t: Tunnel = "5432=10.0.0.1:5432,10.0.0.2:5432!backup,unix:/run/db.sock@2!3"
+            .parse()
+            .expect("valid tunnel");
+        let tiers: Vec<_> = t.remote.iter().map(|r| r.tier).collect();
+        assert_eq!(vec![0, BACKUP_TIER, 3], tiers);
+        assert_eq!(2, t.remote[2].weight);
+        assert_eq!("10.0.0.2:5432!backup", t.remote[1].to_string());
+        assert_eq!("unix:/run/db.sock@2!3", t.remote[2].to_string());
+        assert!("3000!primary".parse::<RemoteSpec>().is_err());
+    }
+
+    #[test]
+    fn test_options_update() {
+        let t: Tunnel = "3333=127.0.0.1:3000[max-conns=10,strategy=round-robin]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.expect("has options");
+        let updated = options
+            .updated("strategy=min-open-connections,timeout=3")
+            .expect("valid options");
+        assert_eq!(Some(10), updated.max_conns);
+        assert_eq!(3.0, updated.options.connect_timeout);
+        assert!(matches!(
+            updated.lb_strategy,
+            TunnelLBStrategy::MinimumOpenConnections
+        ));
+        assert!(options.updated("max-conns=x").is_err());
+        assert!(options.updated("max-conns=5]").is_err());
+    }
+
+    #[test]
+    fn test_resolved_spec() {
+        let name: SocketSpec = "api.internal:443".parse().unwrap();
+        assert!(name.is_host_name());
+        let resolved = name.with_address("10.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!("api.internal/10.0.0.1:443", resolved.to_string());
+        assert_eq!(Some(("10.0.0.1", 443)), resolved.as_tuple());
+        assert_eq!(Some("api.internal"), resolved.server_name());
+        assert_eq!(Some(name.clone()), resolved.resolved_name());
+        assert!(!resolved.is_host_name());
+        let resolved6 = name.with_address("::1".parse().unwrap()).unwrap();
+        assert_eq!("api.internal/[::1]:443", resolved6.to_string());
+        assert_eq!(resolved6, resolved6.to_string().parse().unwrap());
+        assert_eq!(resolved, "api.internal/10.0.0.1:443".parse().unwrap());
+        let ip: SocketSpec = "10.0.0.1:443".parse().unwrap();
+        assert!(!ip.is_host_name());
+        assert_eq!(None, ip.resolved_name());
+        assert_eq!(Some("10.0.0.1"), ip.server_name());
+        let unix: SocketSpec = "unix:/tmp/x.sock".parse().unwrap();
+        assert_eq!(None, unix.as_tuple());
+        assert_eq!(None, unix.host());
+        assert_eq!(None, unix.port());
+        assert_eq!(None, unix.server_name());
+        assert!(!unix.is_host_name());
+        assert!(unix.host_port().is_err());
+        assert_eq!(None, unix.with_address("10.0.0.1".parse().unwrap()));
+    }
+
     #[test]
     fn test_full() {
         let t: Tunnel = "0.0.0.0:3333=127.0.0.1:3000".parse().expect("valid tunnel");
-        assert_eq!(3333, t.local.port());
-        assert_eq!("0.0.0.0", t.local.host());
-        assert_eq!(3000, t.remote[0].port());
-        assert_eq!("127.0.0.1", t.remote[0].host());
+        assert_eq!(Some(3333), t.local.port());
+        assert_eq!(Some("0.0.0.0"), t.local.host());
+        assert_eq!(Some(3000), t.remote[0].socket.port());
+        assert_eq!(Some("127.0.0.1"), t.remote[0].socket.host());
     }
 
     #[test]
     fn test_port_only() {
         let t: Tunnel = "3333=127.0.0.1:3000".parse().expect("valid tunnel");
-        assert_eq!(3333, t.local.port());
-        assert_eq!("127.0.0.1", t.local.host());
-        assert_eq!(3000, t.remote[0].port());
-        assert_eq!("127.0.0.1", t.remote[0].host());
+        assert_eq!(Some(3333), t.local.port());
+        assert_eq!(Some("127.0.0.1"), t.local.host());
+        assert_eq!(Some(3000), t.remote[0].socket.port());
+        assert_eq!(Some("127.0.0.1"), t.remote[0].socket.host());
+    }
+
+    #[test]
+    fn test_unix_tunnel() {
+        let t: Tunnel = "unix:/tmp/plexy.sock=3000,unix:/run/backend.sock[strategy=round-robin]"
+            .parse()
+            .expect("valid tunnel");
+        assert_eq!(Some("/tmp/plexy.sock"), t.local.unix_path());
+        assert_eq!(Some(3000), t.remote[0].socket.port());
+        assert_eq!(Some("/run/backend.sock"), t.remote[1].socket.unix_path());
+    }
+
+    #[test]
+    fn test_udp_tunnel() {
+        let t: Tunnel = "udp:0.0.0.0:5353=10.0.0.1:53,[::1]:53[session-timeout=5]"
+            .parse()
+            .expect("valid tunnel");
+        assert!(t.local.is_udp());
+        assert_eq!("udp:0.0.0.0:5353", t.local.to_string());
+        assert_eq!(Some(5353), t.local.port());
+        assert_eq!(Some("0.0.0.0"), t.local.host());
+        assert!(!t.remote[0].socket.is_udp());
+        assert_eq!(Some("::1"), t.remote[1].socket.host());
+        assert_eq!(5.0, t.options.unwrap().session_timeout);
+        assert!(t.local.compatible_remote(&t.remote[0].socket));
+        assert!(!t
+            .local
+            .compatible_remote(&"unix:/tmp/x.sock".parse().unwrap()));
+        let tcp: SocketSpec = "3000".parse().unwrap();
+        assert!(!tcp.compatible_remote(&t.local));
+        for invalid in ["-1", "0", "NaN"] {
+            assert!(
+                format!("udp:0.0.0.0:5353=10.0.0.1:53[session-timeout={}]", invalid)
+                    .parse::<Tunnel>()
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let t: Tunnel = "3000=3001[accept-rate=100,client-rate=2.5]"
+            .parse()
+            .expect("valid tunnel");
+        let mut options = t.options.unwrap();
+        assert_eq!(Some(100.0), options.accept_rate);
+        assert_eq!(Some(2.5), options.client_rate);
+
+        let limits: RateLimits = "accept-rate=0".parse().unwrap();
+        assert_eq!(None, limits.client_rate);
+        options.update_rate_limits(&limits);
+        assert_eq!(None, options.accept_rate);
+        assert_eq!(Some(2.5), options.client_rate);
+        assert_eq!(
+            "accept-rate=0,client-rate=2.5,rate-up=0,rate-down=0,tunnel-rate-up=0,tunnel-rate-down=0",
+            options.rate_limits().to_string()
+        );
+
+        let limits: RateLimits = "rate-down=1000,tunnel-rate-up=5000".parse().unwrap();
+        options.update_rate_limits(&limits);
+        assert_eq!(Some(1000.0), options.rate_down);
+        assert_eq!(Some(5000.0), options.tunnel_rate_up);
+        assert_eq!(None, options.rate_up);
+
+        assert!("accept-rate=x".parse::<RateLimits>().is_err());
+        assert!("max-conns=1".parse::<RateLimits>().is_err());
+
+        // zero means no limit, same as in LIMIT command
+        let t: Tunnel = "3000=3001[accept-rate=0,client-rate=-1]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.unwrap();
+        assert_eq!(None, options.accept_rate);
+        assert_eq!(None, options.client_rate);
+    }
+
+    #[test]
+    fn test_zero_bandwidth_rate() {
+        let t: Tunnel = "3000=3001[rate-up=0,rate-down=-5,tunnel-rate-up=0,tunnel-rate-down=100]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.unwrap();
+        assert_eq!(None, options.rate_up);
+        assert_eq!(None, options.rate_down);
+        assert_eq!(None, options.tunnel_rate_up);
+        assert_eq!(Some(100.0), options.tunnel_rate_down);
+    }
+
+    #[test]
+    fn test_dead_retry_backoff() {
+        let t: Tunnel = "3000=3001[check-interval=1,check-interval-max=5,check-jitter=0.2]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.unwrap().options;
+        let mut interval = Duration::from_secs(1);
+        let mut intervals = vec![];
+        for _ in 0..4 {
+            interval = options.next_dead_retry(interval);
+            intervals.push(interval.as_secs());
+        }
+        assert_eq!(vec![2, 4, 5, 5], intervals);
+        for _ in 0..100 {
+            let jittered = options.with_jitter(Duration::from_secs(10));
+            assert!(jittered >= Duration::from_secs(8) && jittered <= Duration::from_secs(12));
+        }
+
+        let t: Tunnel = "3000=3001[check-interval=3,check-jitter=0]"
+            .parse()
+            .unwrap();
+        let options = t.options.unwrap().options;
+        assert_eq!(
+            Duration::from_secs(3),
+            options.next_dead_retry(Duration::from_secs(3))
+        );
+        assert_eq!(
+            Duration::from_secs(3),
+            options.with_jitter(Duration::from_secs(3))
+        );
+        assert!("3000=3001[check-jitter=2]".parse::<Tunnel>().is_err());
+        assert!("3000=3001[check-interval=0]".parse::<Tunnel>().is_err());
+        assert!("3000=3001[check-interval=-1]".parse::<Tunnel>().is_err());
+        assert!("3000=3001[check-interval-max=inf]"
+            .parse::<Tunnel>()
+            .is_err());
+        assert!("3000=3001[check-interval=10,check-interval-max=5]"
+            .parse::<Tunnel>()
+            .is_err());
+        let t: Tunnel = "3000=3001[check-interval=2,check-jitter=0.3]"
+            .parse()
+            .unwrap();
+        let displayed = t.options.unwrap().to_string();
+        assert!(displayed.contains("check-interval=2, check-jitter=0.3"));
+    }
+
+    #[test]
+    fn test_accept_proxy_protocol() {
+        assert!("3000=3001[accept-proxy-protocol=true]"
+            .parse::<Tunnel>()
+            .is_err());
+        let t: Tunnel = "3000=3001[accept-proxy-protocol=true,proxy-trusted=10.0.0.0/8]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.unwrap();
+        assert!(options.accept_proxy_protocol);
+        assert_eq!(1, options.proxy_trusted.len());
+    }
+
+    #[test]
+    fn test_access_lists() {
+        let t: Tunnel = "3000=3001[allow=10.0.0.0/8;2001:db8::/32,deny=10.1.0.0/16]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.unwrap();
+        assert_eq!(2, options.allow.len());
+        assert_eq!(1, options.deny.len());
+        assert_eq!(
+            "allow=10.0.0.0/8;2001:db8::/32, deny=10.1.0.0/16",
+            options.access_lists().to_string()
+        );
     }
 
     #[test]
@@ -216,4 +1039,33 @@ mod tests {
         let t: Tunnel = t_str.parse().expect("Valid tunnel spec");
         assert_eq!(t.options.unwrap().remote_connect_retries, 5);
     }
+
+    #[test]
+    fn test_stream_timeouts_options() {
+        let t: Tunnel = "3000=3001[idle-timeout=300,max-lifetime=3600.5]"
+            .parse()
+            .expect("valid tunnel");
+        let options = t.options.unwrap();
+        assert_eq!(Some(300.0), options.idle_timeout);
+        assert_eq!(Some(3600.5), options.max_lifetime);
+        assert!(options
+            .to_string()
+            .ends_with(", idle-timeout=300, max-lifetime=3600.5"));
+
+        for invalid in [
+            "idle-timeout=-1",
+            "idle-timeout=0",
+            "max-lifetime=NaN",
+            "max-lifetime=inf",
+            "queue-timeout=-0.5",
+            "slow-start=-10",
+            "timeout=0",
+        ] {
+            assert!(
+                format!("3000=3001[{}]", invalid).parse::<Tunnel>().is_err(),
+                "{} should be invalid",
+                invalid
+            );
+        }
+    }
 }
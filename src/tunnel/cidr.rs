@@ -0,0 +1,157 @@
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::{Error, Result};
+
+/// IP network in CIDR notation, like 10.0.0.0/8 or 2001:db8::/32,
+/// plain address is taken as network with single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// IPv4 clients on dual stack socket appear as IPv4 mapped IPv6 addresses
+fn unmap(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip6) => ip6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+        _ => *ip,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, unmap(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses list of CIDRs separated by semicolon
+pub fn parse_cidr_list(s: &str) -> Result<Vec<Cidr>> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Is address in any of networks in list
+pub fn cidr_list_contains(list: &[Cidr], ip: &IpAddr) -> bool {
+    list.iter().any(|net| net.contains(ip))
+}
+
+/// Client is permitted, if it is not in deny list and allow list is either empty or contains it
+pub fn access_permitted(allow: &[Cidr], deny: &[Cidr], ip: &IpAddr) -> bool {
+    !cidr_list_contains(deny, ip) && (allow.is_empty() || cidr_list_contains(allow, ip))
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| Error::CidrParseError(format!("Invalid address in {}", s)))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| Error::CidrParseError(format!("Invalid prefix in {}", s)))?,
+            None => max_prefix,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(net.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db8::1".parse().unwrap()));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"192.168.1.1".parse().unwrap()));
+
+        let single: Cidr = "192.168.1.1".parse().unwrap();
+        assert_eq!("192.168.1.1/32", single.to_string());
+        assert!(single.contains(&"192.168.1.1".parse().unwrap()));
+        assert!(!single.contains(&"192.168.1.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+
+        let list = parse_cidr_list("10.0.0.0/8; 192.168.0.0/16").unwrap();
+        assert_eq!(2, list.len());
+        assert!(cidr_list_contains(&list, &"192.168.5.5".parse().unwrap()));
+
+        let deny = parse_cidr_list("192.168.5.0/24").unwrap();
+        assert!(!access_permitted(
+            &list,
+            &deny,
+            &"192.168.5.5".parse().unwrap()
+        ));
+        assert!(access_permitted(
+            &list,
+            &deny,
+            &"192.168.6.5".parse().unwrap()
+        ));
+        assert!(!access_permitted(
+            &list,
+            &deny,
+            &"172.16.0.1".parse().unwrap()
+        ));
+        assert!(access_permitted(&[], &deny, &"172.16.0.1".parse().unwrap()));
+        assert!(access_permitted(&[], &[], &"::1".parse().unwrap()));
+    }
+}
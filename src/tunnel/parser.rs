@@ -1,16 +1,25 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_till, take_while, take_while_m_n},
-    character::complete::{alpha1, char, u8},
+    bytes::complete::{tag, take_till, take_till1, take_while, take_while_m_n},
+    character::complete::{alpha1, char, u32, u8},
     combinator::{all_consuming, map, opt, recognize, verify},
     multi::separated_list1,
-    sequence::{delimited, pair, separated_pair, tuple},
+    sequence::{delimited, pair, preceded, separated_pair, tuple},
     IResult,
 };
 
-use crate::Tunnel;
+use crate::{
+    state::{
+        health::{unescape, HealthCheck},
+        outlier::OutlierDetection,
+    },
+    Tunnel,
+};
 
-use super::{SocketSpec, TunnelOptions};
+use super::{
+    cidr::parse_cidr_list, rate_limit, valid_seconds, RateLimits, RemoteSpec, SocketSpec,
+    TunnelOptions, BACKUP_TIER, DEFAULT_REMOTE_WEIGHT, UDP_PREFIX, UNIX_PREFIX,
+};
 
 fn port(i: &str) -> IResult<&str, u16> {
     nom::character::complete::u16(i)
@@ -72,17 +81,115 @@ fn socket_spec3(i: &str) -> IResult<&str, SocketSpec> {
     })(i)
 }
 
+fn socket_spec_resolved(i: &str) -> IResult<&str, SocketSpec> {
+    map(
+        tuple((host_name, char('/'), alt((ipv4, ipv6)), char(':'), port)),
+        |(host, _, address, _, port)| {
+            let address = if address.contains(':') {
+                format!("[{}]", address)
+            } else {
+                address.to_string()
+            };
+            SocketSpec {
+                inner: format!("{}/{}:{}", host, address, port).into(),
+            }
+        },
+    )(i)
+}
+
+fn is_unix_path_terminator(c: char) -> bool {
+    c.is_whitespace() || ",=[]@!".contains(c)
+}
+
+fn socket_spec_unix(i: &str) -> IResult<&str, SocketSpec> {
+    map(
+        recognize(pair(tag(UNIX_PREFIX), take_till1(is_unix_path_terminator))),
+        |spec: &str| SocketSpec { inner: spec.into() },
+    )(i)
+}
+
+fn socket_spec_udp(i: &str) -> IResult<&str, SocketSpec> {
+    map(
+        preceded(
+            tag(UDP_PREFIX),
+            alt((socket_spec3, socket_spec2, socket_spec1)),
+        ),
+        |spec| SocketSpec {
+            inner: format!("{}{}", UDP_PREFIX, spec).into(),
+        },
+    )(i)
+}
+
 pub(super) fn socket_spec(i: &str) -> IResult<&str, SocketSpec> {
-    alt((socket_spec3, socket_spec2, socket_spec1))(i)
+    alt((
+        socket_spec_udp,
+        socket_spec_unix,
+        socket_spec_resolved,
+        socket_spec3,
+        socket_spec2,
+        socket_spec1,
+    ))(i)
 }
 
-fn options(i: &str) -> IResult<&str, TunnelOptions> {
-    fn err(input: &str) -> nom::Err<nom::error::Error<&str>> {
-        nom::Err::Failure(nom::error::Error {
-            input,
-            code: nom::error::ErrorKind::Verify,
-        })
+fn err(input: &str) -> nom::Err<nom::error::Error<&str>> {
+    nom::Err::Failure(nom::error::Error {
+        input,
+        code: nom::error::ErrorKind::Verify,
+    })
+}
+
+/// Time in seconds, must be finite and positive, so it can be converted to `Duration`
+fn seconds(v: &str) -> Result<f32, nom::Err<nom::error::Error<&str>>> {
+    v.parse()
+        .ok()
+        .filter(|s: &f32| valid_seconds(*s))
+        .ok_or_else(|| err(v))
+}
+
+/// Health check options, any health check option enables health checks
+fn health_check(options: &mut TunnelOptions) -> &mut HealthCheck {
+    options.health_check.get_or_insert_with(Default::default)
+}
+
+/// Outlier detection options, any outlier option enables outlier detection
+fn outlier(options: &mut TunnelOptions) -> &mut OutlierDetection {
+    options.outlier.get_or_insert_with(Default::default)
+}
+
+/// Unsets optional option, `None` if option cannot be unset
+fn unset_option(options: &mut TunnelOptions, key: &str) -> Option<()> {
+    match key.to_lowercase().as_str() {
+        "check-interval-max" => options.options.dead_retry_max = None,
+        "proxy-protocol" => options.proxy_protocol = None,
+        "health-check" => options.health_check = None,
+        "outlier" => options.outlier = None,
+        "outlier-latency" => {
+            if let Some(outlier) = options.outlier.as_mut() {
+                outlier.latency_factor = None
+            }
+        }
+        "slow-start" => options.slow_start = None,
+        "dns-refresh" => options.dns_refresh = None,
+        "idle-timeout" => options.idle_timeout = None,
+        "max-lifetime" => options.max_lifetime = None,
+        "max-conns" => options.max_conns = None,
+        "max-conns-per-remote" => options.max_conns_per_remote = None,
+        "max-conns-per-client" => options.max_conns_per_client = None,
+        "queue-timeout" => options.queue_timeout = None,
+        _ => return None,
     }
+    Some(())
+}
+
+fn options(i: &str) -> IResult<&str, TunnelOptions> {
+    options_update(i, &TunnelOptions::default())
+}
+
+/// Options given in input are applied on top of `base` options
+pub(super) fn options_update<'a>(
+    i: &'a str,
+    base: &TunnelOptions,
+) -> IResult<&'a str, TunnelOptions> {
     separated_list1(
         char(','),
         separated_pair(
@@ -92,15 +199,120 @@ fn options(i: &str) -> IResult<&str, TunnelOptions> {
         ),
     )(i)
     .and_then(|(rest, items)| {
-        let mut options = TunnelOptions::default();
+        let mut options = base.clone();
         for (k, v) in items {
+            if v.eq_ignore_ascii_case("none") {
+                unset_option(&mut options, k).ok_or_else(|| err(v))?;
+                continue;
+            }
             match k.to_lowercase().as_str() {
                 "strategy" => options.lb_strategy = v.parse().map_err(|_| err(v))?,
                 "retries" => options.remote_connect_retries = v.parse().map_err(|_| err(v))?,
-                "timeout" => options.options.connect_timeout = v.parse().map_err(|_| err(v))?,
+                "timeout" => options.options.connect_timeout = seconds(v)?,
                 "errors" => options.options.errors_till_dead = v.parse().map_err(|_| err(v))?,
-                "check-interval" => options.options.dead_retry = v.parse().map_err(|_| err(v))?,
+                "check-interval" => options.options.dead_retry = seconds(v)?,
+                "check-interval-max" => options.options.dead_retry_max = Some(seconds(v)?),
+                "check-jitter" => {
+                    options.options.dead_retry_jitter = v
+                        .parse()
+                        .ok()
+                        .filter(|j| (0.0..=1.0).contains(j))
+                        .ok_or_else(|| err(v))?
+                }
                 "remote-tls" => options.options.tls = v.parse().map_err(|_| err(v))?,
+                "local-tls" => options.local_tls = v.parse().map_err(|_| err(v))?,
+                "cert" => options.local_cert = Some(v.into()),
+                "key" => options.local_key = Some(v.into()),
+                "sni-routing" => options.sni_routing = v.parse().map_err(|_| err(v))?,
+                "proxy-protocol" => options.proxy_protocol = Some(v.parse().map_err(|_| err(v))?),
+                "accept-proxy-protocol" => {
+                    options.accept_proxy_protocol = v.parse().map_err(|_| err(v))?
+                }
+                "proxy-trusted" => {
+                    options.proxy_trusted = parse_cidr_list(v).map_err(|_| err(v))?
+                }
+                "health-check" => {
+                    health_check(&mut options).kind = v.parse().map_err(|_| err(v))?
+                }
+                "health-interval" => health_check(&mut options).interval = seconds(v)?,
+                "health-timeout" => health_check(&mut options).timeout = seconds(v)?,
+                "health-rise" => {
+                    health_check(&mut options).rise =
+                        v.parse().ok().filter(|n| *n > 0).ok_or_else(|| err(v))?
+                }
+                "health-fall" => {
+                    health_check(&mut options).fall =
+                        v.parse().ok().filter(|n| *n > 0).ok_or_else(|| err(v))?
+                }
+                "health-send" => {
+                    unescape(v).map_err(|_| err(v))?;
+                    health_check(&mut options).send = Some(v.into())
+                }
+                "health-expect" => {
+                    unescape(v).map_err(|_| err(v))?;
+                    health_check(&mut options).expect = Some(v.into())
+                }
+                "health-path" => {
+                    health_check(&mut options).path = Some(v)
+                        .filter(|p| p.starts_with('/'))
+                        .ok_or_else(|| err(v))?
+                        .into()
+                }
+                "health-status" => {
+                    health_check(&mut options).status = v.parse().map_err(|_| err(v))?
+                }
+                "outlier-window" => outlier(&mut options).window = seconds(v)?,
+                "outlier-error-rate" => {
+                    outlier(&mut options).error_rate = v
+                        .parse()
+                        .ok()
+                        .filter(|r| (0.0..=100.0).contains(r))
+                        .ok_or_else(|| err(v))?
+                }
+                "outlier-min-requests" => {
+                    outlier(&mut options).min_requests =
+                        v.parse().ok().filter(|n| *n > 0).ok_or_else(|| err(v))?
+                }
+                "outlier-latency" => {
+                    outlier(&mut options).latency_factor =
+                        Some(v.parse().ok().filter(|f| *f > 1.0).ok_or_else(|| err(v))?)
+                }
+                "outlier-ejection-time" => outlier(&mut options).ejection_time = seconds(v)?,
+                "outlier-max-ejection-time" => {
+                    outlier(&mut options).max_ejection_time = seconds(v)?
+                }
+                "outlier-max-ejected" => {
+                    outlier(&mut options).max_ejected = v
+                        .parse()
+                        .ok()
+                        .filter(|p| (0.0..=100.0).contains(p))
+                        .ok_or_else(|| err(v))?
+                }
+                "slow-start" => options.slow_start = Some(seconds(v)?),
+                "dns-refresh" => options.dns_refresh = Some(seconds(v)?),
+                "session-timeout" => options.session_timeout = seconds(v)?,
+                "idle-timeout" => options.idle_timeout = Some(seconds(v)?),
+                "max-lifetime" => options.max_lifetime = Some(seconds(v)?),
+                "max-conns" => options.max_conns = Some(v.parse().map_err(|_| err(v))?),
+                "max-conns-per-remote" => {
+                    options.max_conns_per_remote = Some(v.parse().map_err(|_| err(v))?)
+                }
+                "max-conns-per-client" => {
+                    options.max_conns_per_client = Some(v.parse().map_err(|_| err(v))?)
+                }
+                "queue-timeout" => options.queue_timeout = Some(seconds(v)?),
+                "allow" => options.allow = parse_cidr_list(v).map_err(|_| err(v))?,
+                "deny" => options.deny = parse_cidr_list(v).map_err(|_| err(v))?,
+                "accept-rate" => options.accept_rate = rate_limit(v.parse().map_err(|_| err(v))?),
+                "client-rate" => options.client_rate = rate_limit(v.parse().map_err(|_| err(v))?),
+                "rate-up" => options.rate_up = rate_limit(v.parse().map_err(|_| err(v))?),
+                "rate-down" => options.rate_down = rate_limit(v.parse().map_err(|_| err(v))?),
+                "tunnel-rate-up" => {
+                    options.tunnel_rate_up = rate_limit(v.parse().map_err(|_| err(v))?)
+                }
+                "tunnel-rate-down" => {
+                    options.tunnel_rate_down = rate_limit(v.parse().map_err(|_| err(v))?)
+                }
                 _ => return Err(err(k)),
             }
         }
@@ -108,13 +320,61 @@ fn options(i: &str) -> IResult<&str, TunnelOptions> {
     })
 }
 
+pub(super) fn rate_limits(i: &str) -> IResult<&str, RateLimits> {
+    all_consuming(separated_list1(
+        char(','),
+        separated_pair(
+            take_while(is_option_name_char),
+            char('='),
+            take_till(|c| c == ','),
+        ),
+    ))(i)
+    .and_then(|(rest, items)| {
+        let mut limits = RateLimits::default();
+        for (k, v) in items {
+            let rate = Some(v.trim().parse().map_err(|_| err(v))?);
+            match k.to_lowercase().as_str() {
+                "accept-rate" => limits.accept_rate = rate,
+                "client-rate" => limits.client_rate = rate,
+                "rate-up" => limits.rate_up = rate,
+                "rate-down" => limits.rate_down = rate,
+                "tunnel-rate-up" => limits.tunnel_rate_up = rate,
+                "tunnel-rate-down" => limits.tunnel_rate_down = rate,
+                _ => return Err(err(k)),
+            }
+        }
+        Ok((rest, limits))
+    })
+}
+
+/// Priority tier of remote - `!backup` or `!n`
+fn tier(i: &str) -> IResult<&str, u8> {
+    preceded(char('!'), alt((map(tag("backup"), |_| BACKUP_TIER), u8)))(i)
+}
+
+/// Remote socket with optional weight and tier like `host:port@5!backup`
+pub(super) fn remote_spec(i: &str) -> IResult<&str, RemoteSpec> {
+    map(
+        tuple((
+            socket_spec,
+            opt(preceded(char('@'), verify(u32, |w: &u32| *w > 0))),
+            opt(tier),
+        )),
+        |(socket, weight, tier)| RemoteSpec {
+            socket,
+            weight: weight.unwrap_or(DEFAULT_REMOTE_WEIGHT),
+            tier: tier.unwrap_or_default(),
+        },
+    )(i)
+}
+
 pub(super) fn tunnel(i: &str) -> IResult<&str, Tunnel> {
     all_consuming(map(
         separated_pair(
             socket_spec,
             char('='),
             tuple((
-                separated_list1(char(','), socket_spec),
+                separated_list1(char(','), remote_spec),
                 opt(delimited(char('['), options, char(']'))),
             )),
         ),
@@ -128,7 +388,7 @@ pub(super) fn tunnel(i: &str) -> IResult<&str, Tunnel> {
 
 #[cfg(test)]
 mod tests {
-    use crate::state::strategy::TunnelLBStrategy;
+    use crate::{proxy_protocol::ProxyProtocolVersion, state::strategy::TunnelLBStrategy};
 
     use super::*;
 
@@ -198,20 +458,33 @@ mod tests {
     fn test_socket_spec() {
         let x = "localhost:3333";
         let (_rest, s) = socket_spec(x).expect("valid socket address");
-        assert_eq!("localhost", s.host());
-        assert_eq!(3333, s.port());
+        assert_eq!(Some("localhost"), s.host());
+        assert_eq!(Some(3333), s.port());
 
         let y = "127.0.0.1:3000";
         let (_rest, s) = socket_spec(y).expect("valid socket address");
-        assert_eq!("127.0.0.1", s.host());
-        assert_eq!(3000, s.port());
+        assert_eq!(Some("127.0.0.1"), s.host());
+        assert_eq!(Some(3000), s.port());
 
         let z = "[2001:db8::1234:5678]:8080";
         let (rest, s) = socket_spec(z).expect("valid ipv6 socket spec");
         assert_eq!(0, rest.len());
-        assert_eq!(8080, s.port());
-        assert_eq!("2001:db8::1234:5678", s.host());
-        assert_eq!(("2001:db8::1234:5678", 8080), s.as_tuple());
+        assert_eq!(Some(8080), s.port());
+        assert_eq!(Some("2001:db8::1234:5678"), s.host());
+        assert_eq!(Some(("2001:db8::1234:5678", 8080)), s.as_tuple());
+    }
+
+    #[test]
+    fn test_unix_socket_spec() {
+        let (rest, s) = socket_spec("unix:/run/app/app.sock,3000").expect("valid unix socket");
+        assert_eq!(",3000", rest);
+        assert_eq!(Some("/run/app/app.sock"), s.unix_path());
+        assert_eq!("unix:/run/app/app.sock", s.to_string());
+
+        let (_rest, s) = socket_spec("127.0.0.1:3000").expect("valid socket address");
+        assert_eq!(None, s.unix_path());
+
+        assert!(socket_spec_unix("unix:").is_err());
     }
 
     #[test]
@@ -227,4 +500,36 @@ mod tests {
         assert!(matches!(res.lb_strategy, TunnelLBStrategy::Random));
         assert!(res.options.tls);
     }
+
+    #[test]
+    fn test_proxy_protocol_option() {
+        let (_, res) = options("proxy-protocol=v2,timeout=3").unwrap();
+        assert_eq!(Some(ProxyProtocolVersion::V2), res.proxy_protocol);
+        let (_, res) = options("proxy-protocol=V1").unwrap();
+        assert_eq!(Some(ProxyProtocolVersion::V1), res.proxy_protocol);
+        assert!(options("proxy-protocol=v3").is_err());
+
+        let (_, res) =
+            options("accept-proxy-protocol=true,proxy-trusted=10.0.0.0/8;fd00::/8").unwrap();
+        assert!(res.accept_proxy_protocol);
+        assert_eq!(2, res.proxy_trusted.len());
+        assert!(options("proxy-trusted=10.0.0.0/8;fd00::/200").is_err());
+    }
+
+    #[test]
+    fn test_local_tls_options() {
+        let options_str = "local-tls=true,cert=data/localhost.crt,key=/etc/plexy/localhost.key";
+        let (rest, res) = options(options_str).unwrap();
+        assert_eq!(0, rest.len());
+        assert!(res.local_tls);
+        assert_eq!(
+            Some(std::path::Path::new("data/localhost.crt")),
+            res.local_cert.as_deref()
+        );
+        assert_eq!(
+            Some(std::path::Path::new("/etc/plexy/localhost.key")),
+            res.local_key.as_deref()
+        );
+        assert!(!res.options.tls);
+    }
 }
@@ -0,0 +1,423 @@
+//! UDP tunnels - UDP has no connections, so each client address gets its own session
+//! with socket connected to remote selected by tunnel strategy.
+//! Session ends when there is no traffic in either direction for tunnel's `session_timeout`.
+//! Draining tunnel does not create new sessions, but existing sessions continue until they end.
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{sleep_until, timeout, Instant},
+};
+use tracing::{debug, error, info, instrument};
+
+use crate::{
+    error::{Error, Result},
+    state::{health::HealthCheckKind, strategy::ConnectionContext},
+    tunnel::{SocketSpec, TunnelOptions},
+    State, Tunnel,
+};
+
+/// Maximum size of UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Maximum number of UDP sessions (including ones being created) of one tunnel
+const MAX_SESSIONS: usize = 16384;
+/// Maximum number of datagrams queued for client, while its session is being created
+const MAX_PENDING_DATAGRAMS: usize = 16;
+/// Minimal interval between info logs of datagrams denied by access list
+const DENIED_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) struct UdpTunnelHandler {
+    state: State,
+    tunnel_key: SocketSpec,
+    socket: Arc<UdpSocket>,
+    close_channel: watch::Receiver<bool>,
+    drain_channel: watch::Receiver<bool>,
+}
+
+struct Session {
+    id: u64,
+    remote: SocketSpec,
+    socket: Arc<UdpSocket>,
+    last_activity: Arc<Mutex<Instant>>,
+    task: JoinHandle<()>,
+}
+
+/// Creates UDP socket connected to remote, so only datagrams from remote are received on it
+pub(crate) async fn connect_remote(remote: &SocketSpec) -> std::io::Result<UdpSocket> {
+    if remote.unix_path().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Unix socket remote is not supported for UDP tunnel",
+        ));
+    }
+    let addr = lookup_host(remote.host_port()?)
+        .await?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Cannot resolve remote {}", remote),
+            )
+        })?;
+    let bind_addr: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Checks that options are supported for UDP tunnel
+pub(crate) fn check_options(options: &TunnelOptions) -> Result<()> {
+    if options.local_tls
+        || options.sni_routing
+        || options.proxy_protocol.is_some()
+        || options.accept_proxy_protocol
+    {
+        return Err(Error::InvalidTunnel(
+            "TLS, SNI routing and PROXY protocol are not supported for UDP tunnel".into(),
+        ));
+    }
+    if matches!(options.health_check, Some(ref check) if check.kind != HealthCheckKind::Send) {
+        return Err(Error::InvalidTunnel(
+            "only send health check is supported for UDP tunnel".into(),
+        ));
+    }
+    // without expected response any sent datagram would pass the check
+    if matches!(options.health_check, Some(ref check) if check.expect.is_none()) {
+        return Err(Error::InvalidTunnel(
+            "health-expect is required for UDP health check".into(),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) async fn create_tunnel(tunnel: Tunnel, state: State) -> Result<UdpTunnelHandler> {
+    if state.tunnel_exists(&tunnel.local) {
+        return Err(Error::TunnelExists);
+    }
+    if let Some(ref options) = tunnel.options {
+        check_options(options)?;
+    }
+    let socket = UdpSocket::bind(tunnel.local.host_port()?).await?;
+    let (sender, receiver) = watch::channel(false);
+    let tunnel_key = tunnel.local.clone();
+    state.add_tunnel(tunnel, sender)?;
+    let drain_channel = state.drain_receiver(&tunnel_key)?;
+    Ok(UdpTunnelHandler {
+        state,
+        tunnel_key,
+        socket: Arc::new(socket),
+        close_channel: receiver,
+        drain_channel,
+    })
+}
+
+/// Logs datagrams denied by access list - each datagram is denied separately,
+/// so info is logged at most once per `DENIED_LOG_INTERVAL`, other denials only at debug level
+#[derive(Default)]
+struct DeniedLog {
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl DeniedLog {
+    fn record(&mut self, client: SocketAddr) {
+        let now = Instant::now();
+        match self.last_logged {
+            Some(last) if now.duration_since(last) < DENIED_LOG_INTERVAL => {
+                self.suppressed += 1;
+                debug!(client=%client, "UDP datagram denied by access list");
+            }
+            _ => {
+                info!(client=%client, suppressed=self.suppressed, "UDP session denied by access list");
+                self.last_logged = Some(now);
+                self.suppressed = 0;
+            }
+        }
+    }
+}
+
+/// Result of session creation - client and session, if it was created
+type SessionCreated = (SocketAddr, Option<Session>);
+
+#[instrument(skip_all, fields(tunnel=%handler.tunnel_key))]
+pub(crate) async fn run_tunnel(mut handler: UdpTunnelHandler) {
+    debug!("Started UDP tunnel");
+    let mut sessions: HashMap<SocketAddr, Session> = HashMap::new();
+    // datagrams from clients waiting for their sessions to be created
+    let mut pending: HashMap<SocketAddr, Vec<Vec<u8>>> = HashMap::new();
+    let (ended_sender, mut ended_receiver) = mpsc::unbounded_channel();
+    let (created_sender, mut created_receiver) = mpsc::unbounded_channel::<SessionCreated>();
+    let mut denied = DeniedLog::default();
+    let mut next_id = 0u64;
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            received = handler.socket.recv_from(&mut buf) => {
+                let (n, client) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        error!(error=%e, "Cannot receive datagram");
+                        continue;
+                    }
+                };
+                if let Some(waiting) = pending.get_mut(&client) {
+                    if waiting.len() < MAX_PENDING_DATAGRAMS {
+                        waiting.push(buf[..n].to_vec());
+                    } else {
+                        debug!(client=%client, "Datagram dropped, too many datagrams waiting for session");
+                    }
+                    continue;
+                }
+                let alive = sessions
+                    .get(&client)
+                    .map(|s| !s.task.is_finished())
+                    .unwrap_or(false);
+                if !alive {
+                    if *handler.drain_channel.borrow() {
+                        debug!(client=%client, "Datagram dropped, tunnel is draining");
+                        continue;
+                    }
+                    if handler.state.check_access(&handler.tunnel_key, &client).is_err() {
+                        denied.record(client);
+                        continue;
+                    }
+                    if sessions.len() + pending.len() >= MAX_SESSIONS {
+                        handler.state.client_rejected(&handler.tunnel_key);
+                        debug!(client=%client, "Datagram dropped, too many UDP sessions");
+                        continue;
+                    }
+                    next_id += 1;
+                    pending.insert(client, vec![buf[..n].to_vec()]);
+                    let ctx = SessionSetup {
+                        state: handler.state.clone(),
+                        tunnel_key: handler.tunnel_key.clone(),
+                        local_socket: handler.socket.clone(),
+                        close_channel: handler.close_channel.clone(),
+                    };
+                    let ended_sender = ended_sender.clone();
+                    let created_sender = created_sender.clone();
+                    let id = next_id;
+                    tokio::spawn(async move {
+                        let session = create_session(ctx, client, id, ended_sender).await;
+                        let _ = created_sender.send((client, session));
+                    });
+                    continue;
+                }
+                let session = sessions.get(&client).expect("session exists");
+                send_to_remote(&handler, session, client, &buf[..n]).await;
+            }
+
+            Some((client, session)) = created_receiver.recv() => {
+                let waiting = pending.remove(&client).unwrap_or_default();
+                if let Some(session) = session {
+                    if let Some(old) = sessions.insert(client, session) {
+                        handler.state.client_disconnected(&handler.tunnel_key, Some(&old.remote), &client);
+                    }
+                    let session = sessions.get(&client).expect("session exists");
+                    for datagram in waiting {
+                        send_to_remote(&handler, session, client, &datagram).await;
+                    }
+                }
+            }
+
+            Some((client, id)) = ended_receiver.recv() => {
+                if sessions.get(&client).map(|s| s.id == id).unwrap_or(false) {
+                    let session = sessions.remove(&client).expect("session exists");
+                    handler.state.client_disconnected(&handler.tunnel_key, Some(&session.remote), &client);
+                    debug!(client=%client, "UDP session ended");
+                }
+            }
+
+            _ = handler.close_channel.changed() => {
+                debug!("Finished UDP tunnel");
+                break
+            }
+        }
+    }
+}
+
+async fn send_to_remote(
+    handler: &UdpTunnelHandler,
+    session: &Session,
+    client: SocketAddr,
+    datagram: &[u8],
+) {
+    *session.last_activity.lock() = Instant::now();
+    match session.socket.send(datagram).await {
+        Ok(sent) => handler.state.update_transferred(
+            &handler.tunnel_key,
+            &session.remote,
+            true,
+            sent as u64,
+            client,
+        ),
+        Err(e) => {
+            error!(error=%e, client=%client, remote=%session.remote, "Cannot send datagram to remote")
+        }
+    }
+}
+
+/// Tunnel parts needed to create session in separate task
+struct SessionSetup {
+    state: State,
+    tunnel_key: SocketSpec,
+    local_socket: Arc<UdpSocket>,
+    close_channel: watch::Receiver<bool>,
+}
+
+async fn create_session(
+    setup: SessionSetup,
+    client: SocketAddr,
+    id: u64,
+    ended_sender: mpsc::UnboundedSender<(SocketAddr, u64)>,
+) -> Option<Session> {
+    let state = &setup.state;
+    let tunnel_key = &setup.tunnel_key;
+    let tunnel_options = state.tunnel_options(tunnel_key).ok()?;
+    if let Err(e) = state.check_rate(tunnel_key, &client) {
+        debug!(client=%client, error=%e, "Datagram dropped");
+        return None;
+    }
+    if let Err(e) = state.try_client_connected(tunnel_key, &client) {
+        state.client_rejected(tunnel_key);
+        error!(error=%e, client=%client, "UDP session rejected");
+        return None;
+    }
+    let mut retries = tunnel_options.remote_connect_retries;
+    while retries > 0 {
+        let ctx = ConnectionContext {
+            client: Some(client),
+            server_name: None,
+        };
+        match state.select_remote(tunnel_key, &ctx) {
+            Ok((remote, options)) => {
+                debug!(remote=%remote, client=%client, "Selected remote for UDP session");
+                let connect_start = Instant::now();
+                match timeout(
+                    Duration::from_secs_f32(options.connect_timeout),
+                    connect_remote(&remote),
+                )
+                .await
+                {
+                    Ok(Ok(socket)) => {
+                        state.remote_connected(
+                            tunnel_key,
+                            &remote,
+                            &client,
+                            connect_start.elapsed(),
+                        );
+                        let socket = Arc::new(socket);
+                        let last_activity = Arc::new(Mutex::new(Instant::now()));
+                        let task = tokio::spawn(run_session(
+                            SessionContext {
+                                id,
+                                client,
+                                remote: remote.clone(),
+                                tunnel_key: tunnel_key.clone(),
+                                state: state.clone(),
+                                idle_timeout: Duration::from_secs_f32(
+                                    tunnel_options.session_timeout,
+                                ),
+                            },
+                            socket.clone(),
+                            setup.local_socket.clone(),
+                            last_activity.clone(),
+                            setup.close_channel.clone(),
+                            ended_sender,
+                        ));
+                        return Some(Session {
+                            id,
+                            remote,
+                            socket,
+                            last_activity,
+                            task,
+                        });
+                    }
+                    Ok(Err(e)) => {
+                        state.remote_error(tunnel_key, &remote, &client, &options);
+                        error!(error=%e, remote=%remote, "Error while connecting to remote");
+                    }
+                    Err(_) => {
+                        state.remote_error(tunnel_key, &remote, &client, &options);
+                        error!(remote=%remote, "Timeout while connecting to remote");
+                    }
+                }
+            }
+            Err(e) => {
+                if let Error::ConnectionLimit(_) = e {
+                    state.client_rejected(tunnel_key);
+                }
+                error!(error=%e, "Cannot get available remote");
+                break;
+            }
+        }
+        retries -= 1;
+    }
+    state.client_disconnected(tunnel_key, None, &client);
+    None
+}
+
+struct SessionContext {
+    id: u64,
+    client: SocketAddr,
+    remote: SocketSpec,
+    tunnel_key: SocketSpec,
+    state: State,
+    idle_timeout: Duration,
+}
+
+/// Forwards datagrams from remote back to client, until session is idle or tunnel is closed
+async fn run_session(
+    ctx: SessionContext,
+    remote_socket: Arc<UdpSocket>,
+    local_socket: Arc<UdpSocket>,
+    last_activity: Arc<Mutex<Instant>>,
+    mut finish_receiver: watch::Receiver<bool>,
+    ended_sender: mpsc::UnboundedSender<(SocketAddr, u64)>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let idle_deadline = *last_activity.lock() + ctx.idle_timeout;
+        tokio::select! {
+            received = remote_socket.recv(&mut buf) => match received {
+                Ok(n) => {
+                    *last_activity.lock() = Instant::now();
+                    match local_socket.send_to(&buf[..n], ctx.client).await {
+                        Ok(sent) => ctx.state.update_transferred(
+                            &ctx.tunnel_key,
+                            &ctx.remote,
+                            false,
+                            sent as u64,
+                            ctx.client,
+                        ),
+                        Err(e) => error!(error=%e, client=%ctx.client, "Cannot send datagram to client"),
+                    }
+                }
+                Err(e) => {
+                    debug!(error=%e, remote=%ctx.remote, "Cannot receive datagram from remote");
+                    break;
+                }
+            },
+            _ = sleep_until(idle_deadline) => {
+                if last_activity.lock().elapsed() >= ctx.idle_timeout {
+                    break;
+                }
+            }
+            _ = finish_receiver.changed() => break,
+        }
+    }
+    let _ = ended_sender.send((ctx.client, ctx.id));
+}
@@ -2,7 +2,7 @@
 use plexy::config::Args;
 #[cfg(feature = "metrics")]
 use plexy::metrics::init_meter;
-use plexy::{error::Result, start_tunnel, stop_tunnel, State, Tunnel};
+use plexy::{drain_tunnel, error::Result, start_tunnel, stop_tunnel, State, Tunnel};
 
 #[tokio::test(flavor = "current_thread")]
 async fn start_stop_tunnel() -> Result<()> {
@@ -17,3 +17,199 @@ async fn start_stop_tunnel() -> Result<()> {
     assert_eq!(0, state.number_of_tunnels());
     Ok(())
 }
+
+#[tokio::test(flavor = "current_thread")]
+async fn start_stop_unix_tunnel() -> Result<()> {
+    #[cfg(feature = "metrics")]
+    let state = State::new(Args::default(), init_meter()).unwrap();
+    #[cfg(not(feature = "metrics"))]
+    let state = State::new(Args::default()).unwrap();
+    let path = std::env::temp_dir().join(format!("plexy-iteg-{}.sock", std::process::id()));
+    let tunnel: Tunnel = format!("unix:{}=127.0.0.1:3927", path.display()).parse()?;
+    let join = start_tunnel(tunnel.clone(), state.clone()).await?;
+    assert!(path.exists());
+    stop_tunnel(&tunnel.local, state.clone())?;
+    join.await.unwrap();
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn udp_tunnel_session() -> Result<()> {
+    use tokio::net::UdpSocket;
+    #[cfg(feature = "metrics")]
+    let state = State::new(Args::default(), init_meter()).unwrap();
+    #[cfg(not(feature = "metrics"))]
+    let state = State::new(Args::default()).unwrap();
+    let backend = UdpSocket::bind("127.0.0.1:3937").await?;
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1024];
+        while let Ok((n, peer)) = backend.recv_from(&mut buf).await {
+            backend.send_to(&buf[..n], peer).await.unwrap();
+        }
+    });
+    let tunnel: Tunnel = "udp:127.0.0.1:3938=127.0.0.1:3937".parse()?;
+    let join = start_tunnel(tunnel.clone(), state.clone()).await?;
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    client.connect("127.0.0.1:3938").await?;
+    let mut buf = [0u8; 1024];
+    for msg in [&b"ping"[..], &b"pong"[..]] {
+        client.send(msg).await?;
+        let n = tokio::time::timeout(std::time::Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .expect("reply in time")?;
+        assert_eq!(msg, &buf[..n]);
+    }
+    stop_tunnel(&tunnel.local, state.clone())?;
+    join.await.unwrap();
+    assert_eq!(0, state.number_of_tunnels());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn tunnel_connection_limit() -> Result<()> {
+    use std::time::Duration;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+    #[cfg(feature = "metrics")]
+    let state = State::new(Args::default(), init_meter()).unwrap();
+    #[cfg(not(feature = "metrics"))]
+    let state = State::new(Args::default()).unwrap();
+    let backend = TcpListener::bind("127.0.0.1:3947").await?;
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = backend.accept().await {
+            // keep connection open until client closes it
+            tokio::spawn(async move {
+                let mut buf = [0u8; 64];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let tunnel: Tunnel = "3948=127.0.0.1:3947[max-conns=1]".parse()?;
+    let join = start_tunnel(tunnel.clone(), state.clone()).await?;
+    let _first = TcpStream::connect("127.0.0.1:3948").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut second = TcpStream::connect("127.0.0.1:3948").await?;
+    let mut buf = [0u8; 1];
+    let read = timeout(Duration::from_secs(5), second.read(&mut buf))
+        .await
+        .expect("rejected connection is closed");
+    assert!(matches!(read, Ok(0) | Err(_)));
+    assert_eq!(1, state.stats()[0].1.rejected);
+    stop_tunnel(&tunnel.local, state.clone())?;
+    join.await.unwrap();
+
+    let tunnel: Tunnel = "3949=127.0.0.1:3947[max-conns=1,queue-timeout=5]".parse()?;
+    let join = start_tunnel(tunnel.clone(), state.clone()).await?;
+    let first = TcpStream::connect("127.0.0.1:3949").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let _second = TcpStream::connect("127.0.0.1:3949").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(1, state.stats()[0].1.streams_open);
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let stats = state.stats()[0].1.clone();
+    assert_eq!(0, stats.rejected);
+    assert_eq!(2, stats.total_connections);
+    stop_tunnel(&tunnel.local, state.clone())?;
+    join.await.unwrap();
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn tunnel_drain() -> Result<()> {
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    #[cfg(feature = "metrics")]
+    let state = State::new(Args::default(), init_meter()).unwrap();
+    #[cfg(not(feature = "metrics"))]
+    let state = State::new(Args::default()).unwrap();
+    let backend = TcpListener::bind("127.0.0.1:3957").await?;
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = backend.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = conn.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let tunnel: Tunnel = "3958=127.0.0.1:3957".parse()?;
+    let join = start_tunnel(tunnel.clone(), state.clone()).await?;
+    let mut finishing = TcpStream::connect("127.0.0.1:3958").await?;
+    let mut staying = TcpStream::connect("127.0.0.1:3958").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let drain = {
+        let (local, state) = (tunnel.local.clone(), state.clone());
+        tokio::spawn(async move { drain_tunnel(&local, Duration::from_millis(500), state).await })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    join.await.unwrap();
+    assert!(TcpStream::connect("127.0.0.1:3958").await.is_err());
+    assert!(state.is_draining(&tunnel.local));
+
+    // open connections still work while draining
+    let mut buf = [0u8; 4];
+    for conn in [&mut finishing, &mut staying] {
+        conn.write_all(b"ping").await?;
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(b"ping", &buf);
+    }
+    drop(finishing);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(1, state.open_connections(&tunnel.local)?);
+
+    let closed = drain.await.unwrap()?;
+    assert_eq!(1, closed);
+    assert_eq!(0, state.number_of_tunnels());
+    let read = staying.read(&mut buf).await;
+    assert!(matches!(read, Ok(0) | Err(_)));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn tunnel_health_checks() -> Result<()> {
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    #[cfg(feature = "metrics")]
+    let state = State::new(Args::default(), init_meter()).unwrap();
+    #[cfg(not(feature = "metrics"))]
+    let state = State::new(Args::default()).unwrap();
+    let _healthy = TcpListener::bind("127.0.0.1:3967").await?;
+    let tunnel: Tunnel = "3968=127.0.0.1:3967,127.0.0.1:3966[health-check=tcp,health-interval=0.1,health-rise=2,health-fall=2]"
+        .parse()?;
+    let join = start_tunnel(tunnel.clone(), state.clone()).await?;
+    let healthy: plexy::tunnel::SocketSpec = "127.0.0.1:3967".parse()?;
+    let failing: plexy::tunnel::SocketSpec = "127.0.0.1:3966".parse()?;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (remotes, dead) = state.remotes(&tunnel.local)?;
+    assert_eq!(1, dead);
+    assert_eq!(healthy, remotes[0].0);
+    assert!(remotes[0].1.health_checks >= 2);
+    let dead_remotes = state.dead_remotes(&tunnel.local)?;
+    assert_eq!(failing, dead_remotes[0].0);
+    assert!(dead_remotes[0].1.stats.health_check_failures >= 2);
+
+    // remote is back after rise successful checks
+    let _revived = TcpListener::bind("127.0.0.1:3966").await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let (remotes, dead) = state.remotes(&tunnel.local)?;
+    assert_eq!(0, dead);
+    assert_eq!(2, remotes.len());
+
+    stop_tunnel(&tunnel.local, state.clone())?;
+    join.await.unwrap();
+    Ok(())
+}